@@ -0,0 +1,156 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{task::JoinHandle, time};
+use tracing::{debug, info};
+
+use crate::State;
+
+/// Counts accepted connections, so that under churn they can be logged as
+/// periodic summaries rather than one line each.
+#[derive(Debug, Default)]
+pub struct AcceptLog {
+    /// Accepted since the last summary.
+    accepted: AtomicU64,
+    open: AtomicUsize,
+}
+
+/// Counts a connection as open until dropped.
+pub struct OpenConnection<'a>(&'a AtomicUsize);
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AcceptLog {
+    /// Summarizes the connections accepted since the last call, or returns
+    /// `None` if there were none.
+    pub fn summary(&self, period: Duration) -> Option<String> {
+        let accepted = self.accepted.swap(0, Ordering::Relaxed);
+        (accepted > 0).then(|| {
+            format!(
+                "Accepted {} connections in the last {}s, {} currently open",
+                accepted,
+                period.as_secs(),
+                self.open.load(Ordering::Relaxed)
+            )
+        })
+    }
+}
+
+impl State {
+    /// Logs a newly accepted connection, which stays counted as open as long
+    /// as the returned guard lives.
+    pub(crate) fn log_accept(&self, addr: SocketAddr) -> OpenConnection<'_> {
+        if self.server.accept_log_summary_secs > 0 {
+            debug!("Accepted connection from: {}", addr);
+            self.accepts.accepted.fetch_add(1, Ordering::Relaxed);
+        } else {
+            info!("Accepted connection from: {}", addr);
+        }
+        self.accepts.open.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(&self.accepts.open)
+    }
+}
+
+/// Logs a summary of accepted connections every `accept_log_summary_secs`.
+/// Returns `None` when connections are logged one by one instead.
+pub fn spawn_accept_summary(state: Arc<State>) -> Option<JoinHandle<()>> {
+    let period = Duration::from_secs(state.server.accept_log_summary_secs);
+    if period.is_zero() {
+        return None;
+    }
+
+    Some(tokio::spawn(async move {
+        let mut ticks = time::interval_at(time::Instant::now() + period, period);
+        loop {
+            ticks.tick().await;
+            if let Some(summary) = state.accepts.summary(period) {
+                info!("{}", summary);
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use anyhow::Result;
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::test_support::spawn_state;
+
+    /// Collects formatted log output.
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Logs {
+        fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    // the subscriber is only installed on this thread, which a
+    // current-thread runtime runs every task on
+    #[tokio::test]
+    async fn test_rapid_connects_are_summarized() -> Result<()> {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::INFO)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let state = Arc::new(State::new(ServerConfig {
+            accept_log_summary_secs: 1,
+            ..Default::default()
+        })?);
+        let addr = spawn_state(state.clone()).await?;
+        let mut clients = Vec::new();
+        for _ in 0..20 {
+            clients.push(TcpStream::connect(addr).await?);
+        }
+        while state.accepts.open.load(Ordering::Relaxed) < 20 {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        spawn_accept_summary(state.clone());
+        time::sleep(Duration::from_millis(1500)).await;
+        let logs = logs.contents();
+        assert!(
+            logs.contains("Accepted 20 connections in the last 1s, 20 currently open"),
+            "{}",
+            logs
+        );
+        assert!(!logs.contains("Accepted connection from"), "{}", logs);
+
+        drop(clients);
+        Ok(())
+    }
+}
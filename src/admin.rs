@@ -0,0 +1,74 @@
+use std::net::SocketAddr;
+
+use tokio::time;
+
+use crate::{Message, State};
+
+impl State {
+    /// Grants admin rights to a peer that knows the configured password.
+    pub(crate) async fn admin_login(&self, addr: SocketAddr, password: &str) {
+        let granted = self
+            .server
+            .admin_password
+            .as_deref()
+            .is_some_and(|expected| expected == password);
+
+        if granted {
+            if let Some(mut peer) = self.peers.get_mut(&addr) {
+                peer.admin = true;
+            }
+        }
+        let reply = if granted {
+            "You are now an admin."
+        } else {
+            "Wrong admin password."
+        };
+        self.notify(addr, Message::server(reply)).await;
+    }
+
+    pub(crate) fn is_admin(&self, addr: SocketAddr) -> bool {
+        self.peers.get(&addr).is_some_and(|peer| peer.admin)
+    }
+
+    /// Returns whether the peer is an admin, telling them off otherwise.
+    pub(crate) async fn require_admin(&self, addr: SocketAddr) -> bool {
+        if self.is_admin(addr) {
+            return true;
+        }
+        self.notify(addr, Message::server("Permission denied: admins only."))
+            .await;
+        false
+    }
+
+    /// Lists every peer with its address, oldest connection first.
+    pub(crate) async fn connections(&self, addr: SocketAddr) {
+        if !self.require_admin(addr).await {
+            return;
+        }
+        let now = time::Instant::now();
+        let mut peers: Vec<_> = self
+            .peers
+            .iter()
+            .map(|peer| {
+                let line = format!(
+                    "{} {} in {}, connected {}s, {} queued",
+                    peer.username,
+                    peer.key(),
+                    peer.current
+                        .as_ref()
+                        .map_or_else(|| "no channel".to_string(), |c| format!("#{}", c)),
+                    now.duration_since(peer.connected_at).as_secs(),
+                    peer.sender.len()
+                );
+                (peer.connected_at, line)
+            })
+            .collect();
+        peers.sort_by_key(|(connected_at, _)| *connected_at);
+
+        let header = format!("Connections ({}):", peers.len());
+        self.notify(addr, Message::server(header)).await;
+        for (_, line) in peers {
+            self.notify(addr, Message::server(line)).await;
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::{fmt, net::SocketAddr};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ServerConfig;
+use crate::{Message, State};
+
+const MAX_NAME_LEN: usize = 255;
+const MAX_URL_LEN: usize = 2048;
+
+/// A file sent out of band, referenced from a chat message. The server only
+/// relays the metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Attachment {
+    pub name: String,
+    pub url: String,
+    /// Size of the file in bytes, as the sender reports it.
+    pub size: u64,
+}
+
+impl fmt::Display for Attachment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[attachment: {} ({})]", self.name, self.url)
+    }
+}
+
+/// Checks attachments against the configured limits.
+pub fn validate(attachments: &[Attachment], config: &ServerConfig) -> Result<()> {
+    if attachments.len() > config.max_attachments {
+        bail!(
+            "Too many attachments; at most {} per message.",
+            config.max_attachments
+        );
+    }
+    for attachment in attachments {
+        let name = &attachment.name;
+        if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains(char::is_control) {
+            bail!("Invalid attachment name.");
+        }
+        let url = &attachment.url;
+        let web = url.starts_with("https://") || url.starts_with("http://");
+        if !web || url.len() > MAX_URL_LEN || url.contains(char::is_whitespace) {
+            bail!("Invalid attachment URL for {}.", name);
+        }
+        if config.max_attachment_bytes > 0 && attachment.size > config.max_attachment_bytes {
+            bail!(
+                "{} is too large; attachments can be up to {} bytes.",
+                name,
+                config.max_attachment_bytes
+            );
+        }
+    }
+    Ok(())
+}
+
+impl State {
+    /// Says something in the current channel with files attached.
+    pub(crate) async fn say_with_attachments(
+        &self,
+        addr: SocketAddr,
+        content: &str,
+        attachments: Vec<Attachment>,
+    ) {
+        if let Err(e) = validate(&attachments, &self.server) {
+            self.notify(addr, Message::server(e.to_string())).await;
+            return;
+        }
+        let content = self.chat_content(content);
+        self.say(addr, content, None, attachments).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::config;
+    use crate::test_support::{spawn_server, TestClient};
+
+    fn attachment(size: u64) -> Attachment {
+        Attachment {
+            name: "plan.pdf".to_string(),
+            url: "https://files.example.com/plan.pdf".to_string(),
+            size,
+        }
+    }
+
+    #[test]
+    fn test_validate_limits() {
+        let config = ServerConfig {
+            max_attachments: 2,
+            max_attachment_bytes: 1000,
+            ..Default::default()
+        };
+        assert!(validate(&[attachment(1000)], &config).is_ok());
+        assert!(validate(&[attachment(1001)], &config).is_err());
+        assert!(validate(&vec![attachment(1); 3], &config).is_err());
+
+        let local = Attachment {
+            url: "file:///etc/passwd".to_string(),
+            ..attachment(1)
+        };
+        assert!(validate(&[local], &config).is_err());
+        let unnamed = Attachment {
+            name: String::new(),
+            ..attachment(1)
+        };
+        assert!(validate(&[unnamed], &config).is_err());
+    }
+
+    #[test]
+    fn test_text_clients_see_attachments() {
+        let message = Message {
+            attachments: vec![attachment(42)],
+            ..Message::new("alice", "here's the plan")
+        };
+        assert_eq!(
+            message.to_string(),
+            "alice: here's the plan [attachment: plan.pdf (https://files.example.com/plan.pdf)]"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_attachments_are_relayed() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            protocol: config::Protocol::Json,
+            ..Default::default()
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        let files = serde_json::json!([
+            {"name": "plan.pdf", "url": "https://files.example.com/plan.pdf", "size": 42}
+        ]);
+        let frame = serde_json::json!({
+            "type": "message",
+            "content": "here's the plan",
+            "attachments": files,
+        });
+        alice.send_line(frame.to_string()).await?;
+        let message: serde_json::Value = serde_json::from_str(&bob.expect_line().await?)?;
+        assert_eq!(message["sender"], "alice");
+        assert_eq!(message["content"], "here's the plan");
+        assert_eq!(message["attachments"], files);
+
+        let frame = serde_json::json!({
+            "type": "message",
+            "content": "oops",
+            "attachments": [{"name": "x", "url": "ftp://example.com/x", "size": 1}],
+        });
+        alice.send_line(frame.to_string()).await?;
+        let reply: serde_json::Value = serde_json::from_str(&alice.expect_line().await?)?;
+        assert_eq!(reply["content"], "Invalid attachment URL for x.");
+        assert_eq!(bob.try_recv().await?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::{self, Instant},
+};
+use tokio_util::codec::Framed;
+
+use crate::codec::ChatCodec;
+use crate::State;
+
+/// The window connection rates are measured over.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Makes new connections answer a sum before they get the username prompt,
+/// to slow down bots during raids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ChallengeConfig {
+    /// How long the client has to answer.
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Only challenge while more than this many connections arrived in the
+    /// last minute; 0 challenges every connection.
+    #[serde(default)]
+    pub raid_connects_per_min: usize,
+}
+
+fn default_timeout_secs() -> u64 {
+    30
+}
+
+/// When recent connections arrived, to tell a raid from normal traffic.
+#[derive(Debug)]
+pub struct RecentConnects {
+    window: Duration,
+    arrivals: Mutex<VecDeque<Instant>>,
+}
+
+impl Default for RecentConnects {
+    fn default() -> Self {
+        Self::new(RATE_WINDOW)
+    }
+}
+
+impl RecentConnects {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            arrivals: Mutex::default(),
+        }
+    }
+
+    /// Counts a connection, returning how many arrived within the window.
+    pub fn record(&self, now: Instant) -> usize {
+        let mut arrivals = self.prune(now);
+        arrivals.push_back(now);
+        arrivals.len()
+    }
+
+    /// How many connections arrived within the window.
+    pub fn count(&self, now: Instant) -> usize {
+        self.prune(now).len()
+    }
+
+    fn prune(&self, now: Instant) -> MutexGuard<'_, VecDeque<Instant>> {
+        let mut arrivals = self.arrivals.lock().unwrap();
+        while arrivals
+            .front()
+            .is_some_and(|arrival| now.duration_since(*arrival) >= self.window)
+        {
+            arrivals.pop_front();
+        }
+        arrivals
+    }
+}
+
+/// A question with a single right answer, which only the connection's own
+/// task needs to know.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Challenge {
+    a: u32,
+    b: u32,
+}
+
+impl Challenge {
+    fn random(rng: &mut impl Rng) -> Self {
+        Self {
+            a: rng.gen_range(1..=20),
+            b: rng.gen_range(1..=20),
+        }
+    }
+
+    fn question(self) -> String {
+        format!("Anti-bot check: what is {} + {}?", self.a, self.b)
+    }
+
+    fn accepts(self, answer: &str) -> bool {
+        answer.trim().parse() == Ok(self.a + self.b)
+    }
+}
+
+impl Default for ChallengeConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: default_timeout_secs(),
+            raid_connects_per_min: 0,
+        }
+    }
+}
+
+impl State {
+    /// The challenge a new connection has to answer first, if any: the
+    /// configured one, and during raid mode always.
+    pub(crate) fn challenge_for_connection(&self) -> Option<ChallengeConfig> {
+        let configured = self.server.challenge.filter(|config| {
+            config.raid_connects_per_min == 0
+                || self.recent_connects.record(Instant::now()) > config.raid_connects_per_min
+        });
+        configured.or_else(|| {
+            self.raid_active()
+                .then(|| self.server.challenge.unwrap_or_default())
+        })
+    }
+}
+
+/// Asks the challenge and waits for the answer. Returns whether the client
+/// answered correctly in time.
+pub async fn pass_challenge<S>(
+    framed: &mut Framed<S, ChatCodec>,
+    config: ChallengeConfig,
+) -> Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let challenge = Challenge::random(&mut rand::thread_rng());
+    framed.send(challenge.question()).await?;
+    let timeout = Duration::from_secs(config.timeout_secs);
+    Ok(match time::timeout(timeout, framed.next()).await {
+        Ok(Some(Ok(answer))) => challenge.accepts(&answer),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::test_support::{spawn_server, TestClient};
+
+    /// Works out the answer to a challenge line.
+    fn answer(question: &str) -> String {
+        let sum = question
+            .strip_prefix("Anti-bot check: what is ")
+            .and_then(|sum| sum.strip_suffix('?'))
+            .unwrap_or_else(|| panic!("not a challenge: {}", question));
+        let (a, b) = sum.split_once(" + ").unwrap();
+        (a.parse::<u32>().unwrap() + b.parse::<u32>().unwrap()).to_string()
+    }
+
+    fn challenge_config(raid_connects_per_min: usize) -> ServerConfig {
+        ServerConfig {
+            challenge: Some(ChallengeConfig {
+                timeout_secs: 1,
+                raid_connects_per_min,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_answers() {
+        let challenge = Challenge { a: 3, b: 4 };
+        assert!(challenge.accepts("7"));
+        assert!(challenge.accepts(" 7 "));
+        assert!(!challenge.accepts("8"));
+        assert!(!challenge.accepts("seven"));
+        assert_eq!(answer(&challenge.question()), "7");
+    }
+
+    #[tokio::test]
+    async fn test_right_answer_gets_the_prompt() -> Result<()> {
+        let addr = spawn_server(challenge_config(0)).await?;
+        let mut client = TestClient::connect_raw(addr).await?;
+        let question = client.expect_line().await?;
+        client.send_line(answer(&question)).await?;
+        assert_eq!(client.expect_line().await?, "Enter your username:");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wrong_or_no_answer_is_refused() -> Result<()> {
+        let addr = spawn_server(challenge_config(0)).await?;
+        let refused =
+            "Connection refused (challenge_failed): Wrong or no answer to the anti-bot check.";
+
+        let mut wrong = TestClient::connect_raw(addr).await?;
+        let question = wrong.expect_line().await?;
+        wrong.send_line(format!("{}0", answer(&question))).await?;
+        assert_eq!(wrong.expect_line().await?, refused);
+        wrong.expect_closed().await?;
+
+        let mut silent = TestClient::connect_raw(addr).await?;
+        silent.expect_line().await?;
+        assert_eq!(silent.expect_line().await?, refused);
+        silent.expect_closed().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raid_mode_only_challenges_bursts() -> Result<()> {
+        let addr = spawn_server(challenge_config(2)).await?;
+        for _ in 0..2 {
+            let mut client = TestClient::connect_raw(addr).await?;
+            assert_eq!(client.expect_line().await?, "Enter your username:");
+        }
+        let mut client = TestClient::connect_raw(addr).await?;
+        let question = client.expect_line().await?;
+        client.send_line(answer(&question)).await?;
+        assert_eq!(client.expect_line().await?, "Enter your username:");
+        Ok(())
+    }
+}
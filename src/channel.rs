@@ -0,0 +1,515 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use dashmap::mapref::{entry::Entry, one::RefMut};
+use tracing::warn;
+
+use crate::config::ClearChannelAction;
+use crate::outbox::Outbox;
+use crate::{Message, State};
+
+/// Channel peers join on connect unless `default_channel` is configured.
+pub const DEFAULT_CHANNEL: &str = "general";
+
+const MAX_CHANNEL_NAME_LEN: usize = 32;
+
+#[derive(Debug, Default)]
+pub struct Channel {
+    pub members: HashSet<SocketAddr>,
+    /// Only invited users can join an invite-only channel.
+    pub invite_only: bool,
+    /// Pending invites by username, with their optional expiry.
+    pub invites: HashMap<String, Option<Instant>>,
+    /// Minimum interval between two messages from the same member.
+    pub slowmode: Option<Duration>,
+    /// When each member last spoke, tracked while slow mode is on.
+    pub last_spoke: HashMap<SocketAddr, Instant>,
+    /// The channel operators, starting with its creator. Operator status
+    /// ends when the member leaves.
+    pub ops: HashSet<SocketAddr>,
+    /// Set by an operator with `/topic`.
+    pub topic: Option<String>,
+    /// Set by an operator with `/pin` and shown to everyone who joins.
+    pub pinned: Option<String>,
+    /// Rules or guidelines set by an operator with `/setwelcome`, shown to
+    /// everyone who joins ahead of what is pinned.
+    pub welcome: Option<String>,
+    /// Template chat in this channel is rendered with for text protocol
+    /// peers, instead of the server's renderer.
+    pub format: Option<String>,
+}
+
+impl Channel {
+    /// Records a message from `addr`, or returns how long they still have to
+    /// wait under slow mode. `floor` is slow mode imposed server-wide, which
+    /// applies when it is stricter than the channel's own.
+    pub fn check_slowmode(
+        &mut self,
+        addr: SocketAddr,
+        floor: Option<Duration>,
+    ) -> Result<(), Duration> {
+        let Some(interval) = self.slowmode.max(floor) else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if let Some(last) = self.last_spoke.get(&addr) {
+            let elapsed = now.duration_since(*last);
+            if elapsed < interval {
+                return Err(interval - elapsed);
+            }
+        }
+        self.last_spoke.insert(addr, now);
+        Ok(())
+    }
+
+    /// Consumes the user's invite, if they have one that hasn't expired.
+    fn take_invite(&mut self, username: &str) -> bool {
+        match self.invites.remove(username) {
+            Some(Some(expires)) => expires > Instant::now(),
+            Some(None) => true,
+            None => false,
+        }
+    }
+}
+
+/// Normalizes a user supplied channel name, accepting an optional leading `#`.
+pub fn channel_name(name: &str) -> Result<String> {
+    let name = name.strip_prefix('#').unwrap_or(name);
+    if name.is_empty()
+        || name.len() > MAX_CHANNEL_NAME_LEN
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("Invalid channel name: {}", name);
+    }
+    Ok(name.to_ascii_lowercase())
+}
+
+impl State {
+    /// Whether a channel is kept while empty: the default channel and the
+    /// auto-join ones.
+    pub(crate) fn is_permanent(&self, channel: &str) -> bool {
+        channel == self.server.default_channel || self.server.auto_join.iter().any(|c| c == channel)
+    }
+
+    /// Adds a new peer to the default and auto-join channels, leaving the
+    /// default one current.
+    pub(crate) fn auto_join(&self, addr: SocketAddr) {
+        let default = &self.server.default_channel;
+        for channel in std::iter::once(default).chain(&self.server.auto_join) {
+            if let Err(e) = self.try_join(addr, channel) {
+                warn!("Failed to auto-join #{}: {:?}", channel, e);
+            }
+        }
+        if let Some(mut peer) = self.peers.get_mut(&addr) {
+            peer.current = Some(default.clone());
+        }
+    }
+
+    /// Tells the other members of each auto-join channel that the peer
+    /// arrived; the default channel hears about it from the server wide
+    /// join notice. The peer is shown the welcome and what is pinned in
+    /// each.
+    pub(crate) async fn announce_auto_join(&self, addr: SocketAddr) {
+        let default = &self.server.default_channel;
+        for channel in std::iter::once(default).chain(&self.server.auto_join) {
+            self.show_welcome(addr, channel).await;
+            self.show_pinned(addr, channel, false).await;
+        }
+        for channel in &self.server.auto_join {
+            let joined = self
+                .peers
+                .get(&addr)
+                .is_some_and(|peer| peer.channels.contains(channel));
+            if !joined || *channel == self.server.default_channel {
+                continue;
+            }
+            self.broadcast_channel(
+                channel,
+                addr,
+                Arc::new(Message::server(format!(
+                    "{} has joined #{}.",
+                    self.display_name(addr),
+                    channel
+                ))),
+            )
+            .await;
+        }
+    }
+
+    pub(crate) async fn join(&self, addr: SocketAddr, channel: &str) {
+        let reply = match self.try_join(addr, channel) {
+            Ok(true) => {
+                self.broadcast_channel(
+                    channel,
+                    addr,
+                    Arc::new(Message::server(format!(
+                        "{} has joined #{}.",
+                        self.display_name(addr),
+                        channel
+                    ))),
+                )
+                .await;
+                self.notify(addr, Message::server(format!("Joined #{}.", channel)))
+                    .await;
+                self.show_welcome(addr, channel).await;
+                self.show_pinned(addr, channel, false).await;
+                return;
+            }
+            Ok(false) => format!("Now talking in #{}.", channel),
+            Err(e) => e.to_string(),
+        };
+        self.notify(addr, Message::server(reply)).await;
+    }
+
+    /// Adds the peer to the channel and makes it their current one. Returns
+    /// whether the peer wasn't a member already.
+    pub(crate) fn try_join(&self, addr: SocketAddr, channel: &str) -> Result<bool> {
+        let username = {
+            let Some(mut peer) = self.peers.get_mut(&addr) else {
+                bail!("Not connected");
+            };
+            if peer.channels.contains(channel) {
+                peer.current = Some(channel.to_string());
+                return Ok(false);
+            }
+            if peer.channels.len() >= self.server.max_channels_per_user {
+                bail!(
+                    "You can't be in more than {} channels.",
+                    self.server.max_channels_per_user
+                );
+            }
+            peer.username.clone()
+        };
+
+        let joined = match self.channels.entry(channel.to_string()) {
+            Entry::Occupied(existing) => {
+                Self::join_existing(existing.into_ref(), addr, &username)?;
+                true
+            }
+            Entry::Vacant(_) => false,
+        };
+        if !joined {
+            // counting channels can't happen under an entry, so creators take
+            // turns instead
+            let _creating = self.creating_channel.lock().unwrap();
+            if self.at_channel_limit() {
+                bail!("Channel limit reached.");
+            }
+            match self.channels.entry(channel.to_string()) {
+                Entry::Occupied(existing) => {
+                    Self::join_existing(existing.into_ref(), addr, &username)?;
+                }
+                Entry::Vacant(vacant) => {
+                    vacant.insert(Channel {
+                        members: HashSet::from([addr]),
+                        ops: HashSet::from([addr]),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+
+        if let Some(mut peer) = self.peers.get_mut(&addr) {
+            peer.channels.insert(channel.to_string());
+            peer.current = Some(channel.to_string());
+        }
+        Ok(true)
+    }
+
+    fn join_existing(
+        mut existing: RefMut<'_, String, Channel>,
+        addr: SocketAddr,
+        username: &str,
+    ) -> Result<()> {
+        if existing.invite_only && !existing.take_invite(username) {
+            bail!("#{} is invite-only.", existing.key());
+        }
+        existing.members.insert(addr);
+        Ok(())
+    }
+
+    pub(crate) async fn part(&self, addr: SocketAddr, channel: Option<String>) {
+        let channel =
+            channel.or_else(|| self.peers.get(&addr).and_then(|peer| peer.current.clone()));
+        let Some(channel) = channel.filter(|channel| self.leave(addr, channel)) else {
+            self.notify(addr, Message::server("You are not in that channel."))
+                .await;
+            return;
+        };
+        self.broadcast_channel(
+            &channel,
+            addr,
+            Arc::new(Message::server(format!(
+                "{} has left #{}.",
+                self.display_name(addr),
+                channel
+            ))),
+        )
+        .await;
+        self.notify(addr, Message::server(format!("Left #{}.", channel)))
+            .await;
+    }
+
+    pub(crate) async fn invite(&self, addr: SocketAddr, to: &str, channel: &str) {
+        let reply = match self.try_invite(addr, to, channel) {
+            Ok(target) => {
+                self.notify(
+                    target,
+                    Message::server(format!(
+                        "{} invited you to #{}. Type /join {} to accept.",
+                        self.display_name(addr),
+                        channel,
+                        channel
+                    )),
+                )
+                .await;
+                format!("Invited {} to #{}.", to, channel)
+            }
+            Err(e) => e.to_string(),
+        };
+        self.notify(addr, Message::server(reply)).await;
+    }
+
+    fn try_invite(&self, addr: SocketAddr, to: &str, channel: &str) -> Result<SocketAddr> {
+        let Some(target) = self.find_peer(to) else {
+            bail!("No such user: {}", to);
+        };
+        let Some(mut existing) = self.channels.get_mut(channel) else {
+            bail!("No such channel: #{}", channel);
+        };
+        if !existing.members.contains(&addr) {
+            bail!("You must be in #{} to invite others.", channel);
+        }
+
+        let ttl = self.server.invite_ttl_secs;
+        let expires = (ttl > 0).then(|| Instant::now() + Duration::from_secs(ttl));
+        existing.invites.insert(to.to_string(), expires);
+        Ok(target)
+    }
+
+    /// Lists the members of a channel, the peer's current one by default.
+    pub(crate) async fn names(&self, addr: SocketAddr, channel: Option<String>) {
+        let channel =
+            channel.or_else(|| self.peers.get(&addr).and_then(|peer| peer.current.clone()));
+        let Some(channel) = channel else {
+            self.notify(addr, Message::server("You are not in any channel."))
+                .await;
+            return;
+        };
+        let Some((members, ops)) = self
+            .channels
+            .get(&channel)
+            .map(|existing| (existing.members.clone(), existing.ops.clone()))
+        else {
+            self.notify(
+                addr,
+                Message::server(format!("No such channel: #{}", channel)),
+            )
+            .await;
+            return;
+        };
+
+        let mut names: Vec<(String, bool)> = members
+            .iter()
+            .filter_map(|member| {
+                let peer = self.peers.get(member)?;
+                Some((peer.nick.clone(), ops.contains(member)))
+            })
+            .collect();
+        names.sort();
+        let names: Vec<String> = names
+            .into_iter()
+            .map(|(nick, op)| if op { format!("@{}", nick) } else { nick })
+            .collect();
+        let reply = format!(
+            "Members of #{} ({}): {}",
+            channel,
+            names.len(),
+            names.join(", ")
+        );
+        self.notify(addr, Message::server(reply)).await;
+    }
+
+    /// Toggles invite-only on the peer's current channel.
+    pub(crate) async fn set_invite_only(&self, addr: SocketAddr, on: bool) {
+        let Some(channel) = self.moderated_channel(addr).await else {
+            return;
+        };
+        let reply = if channel == self.server.default_channel {
+            format!("#{} can't be invite-only.", channel)
+        } else {
+            if let Some(mut existing) = self.channels.get_mut(&channel) {
+                existing.invite_only = on;
+            }
+            let status = if on { "now" } else { "no longer" };
+            format!("#{} is {} invite-only.", channel, status)
+        };
+        self.notify(addr, Message::server(reply)).await;
+    }
+
+    /// Sets the slow mode interval of the peer's current channel; 0 turns
+    /// it off.
+    pub(crate) async fn set_slowmode(&self, addr: SocketAddr, secs: u64) {
+        let Some(channel) = self.moderated_channel(addr).await else {
+            return;
+        };
+
+        if let Some(mut existing) = self.channels.get_mut(&channel) {
+            existing.slowmode = (secs > 0).then(|| Duration::from_secs(secs));
+            existing.last_spoke.clear();
+        }
+        let notice = if secs > 0 {
+            format!("Slow mode in #{} is now {} seconds.", channel, secs)
+        } else {
+            format!("Slow mode in #{} is off.", channel)
+        };
+        self.broadcast_channel(&channel, addr, Arc::new(Message::server(notice.clone())))
+            .await;
+        self.notify(addr, Message::server(notice)).await;
+    }
+
+    /// Moves every member out of a channel, or disconnects them, and deletes
+    /// the channel. Permanent channels can't be cleared.
+    pub(crate) async fn clear_channel(&self, addr: SocketAddr, channel: &str) {
+        if !self.require_admin(addr).await {
+            return;
+        }
+        if self.is_permanent(channel) {
+            self.notify(
+                addr,
+                Message::server(format!("#{} can't be cleared.", channel)),
+            )
+            .await;
+            return;
+        }
+        let Some(members) = self
+            .channels
+            .get(channel)
+            .map(|existing| existing.members.iter().copied().collect::<Vec<_>>())
+        else {
+            self.notify(
+                addr,
+                Message::server(format!("No such channel: #{}", channel)),
+            )
+            .await;
+            return;
+        };
+
+        let notice = format!("#{} was closed by an admin.", channel);
+        for member in &members {
+            if let Some(mut peer) = self.peers.get_mut(member) {
+                peer.channels.remove(channel);
+            }
+            self.remove_member(channel, *member);
+            match self.server.clear_channel_action {
+                ClearChannelAction::Move => {
+                    let default = &self.server.default_channel;
+                    if let Err(e) = self.try_join(*member, default) {
+                        warn!(
+                            "Failed to move {:?} to the default channel: {:?}",
+                            member, e
+                        );
+                    }
+                    let moved = format!("{} You are now in #{}.", notice, default);
+                    self.notify(*member, Message::server(moved)).await;
+                }
+                ClearChannelAction::Disconnect => self.disconnect(*member, notice.clone()).await,
+            }
+        }
+        self.channels.remove(channel);
+        self.notify(
+            addr,
+            Message::server(format!("Cleared #{} ({} members).", channel, members.len())),
+        )
+        .await;
+    }
+
+    /// Takes the peer out of a channel they are in, moving them to another
+    /// of their channels if it was the current one.
+    pub(crate) fn leave(&self, addr: SocketAddr, channel: &str) -> bool {
+        let left = self.peers.get_mut(&addr).is_some_and(|mut peer| {
+            let left = peer.channels.remove(channel);
+            if left && peer.current.as_deref() == Some(channel) {
+                peer.current = peer.channels.iter().next().cloned();
+            }
+            left
+        });
+        if left {
+            self.remove_member(channel, addr);
+        }
+        left
+    }
+
+    /// Whether `max_channels` channels with members already exist. Empty
+    /// ones don't count; only permanent channels stay around empty.
+    fn at_channel_limit(&self) -> bool {
+        let max = self.server.max_channels;
+        max > 0
+            && self
+                .channels
+                .iter()
+                .filter(|channel| !channel.members.is_empty())
+                .count()
+                >= max
+    }
+
+    /// Removes the peer from the channel, dropping the channel itself once
+    /// it is empty.
+    pub(crate) fn remove_member(&self, channel: &str, addr: SocketAddr) {
+        self.channels.remove_if_mut(channel, |name, channel| {
+            channel.members.remove(&addr);
+            channel.last_spoke.remove(&addr);
+            channel.ops.remove(&addr);
+            channel.members.is_empty() && !self.is_permanent(name)
+        });
+    }
+
+    /// Sends a message to every member of a channel except `addr`.
+    pub(crate) async fn broadcast_channel(
+        &self,
+        channel: &str,
+        addr: SocketAddr,
+        message: Arc<Message>,
+    ) {
+        let recipients = self.channel_recipients(channel, Some(addr));
+        self.deliver(recipients, message).await;
+    }
+
+    /// Snapshots the queues of a channel's members, leaving out `except`.
+    pub(crate) fn channel_recipients(
+        &self,
+        channel: &str,
+        except: Option<SocketAddr>,
+    ) -> Vec<(SocketAddr, Outbox)> {
+        let members: Vec<SocketAddr> = match self.channels.get(channel) {
+            Some(channel) => channel.members.iter().copied().collect(),
+            None => return Vec::new(),
+        };
+        members
+            .into_iter()
+            .filter(|member| Some(*member) != except)
+            .filter_map(|member| self.peers.get(&member).map(|p| (member, p.sender.clone())))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_name() {
+        assert_eq!(channel_name("#Rust").unwrap(), "rust");
+        assert_eq!(channel_name("dev-ops_2").unwrap(), "dev-ops_2");
+        assert!(channel_name("#").is_err());
+        assert!(channel_name("no spaces").is_err());
+        assert!(channel_name(&"x".repeat(33)).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+
+/// Longest `/client` line accepted during the handshake.
+const MAX_LINE_LEN: usize = 512;
+const MAX_VALUE_LEN: usize = 64;
+const MIN_WIDTH: u16 = 20;
+const MAX_WIDTH: u16 = 1000;
+
+/// What a client says about itself before logging in, with
+/// `/client name=termchat version=1.2 locale=en_US width=120` or the same
+/// keys as a JSON object.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientInfo {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub locale: Option<String>,
+    /// Columns in the client's terminal.
+    pub width: Option<u16>,
+}
+
+impl ClientInfo {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn validate(self) -> Result<Self> {
+        for (key, value) in [
+            ("name", &self.name),
+            ("version", &self.version),
+            ("locale", &self.locale),
+        ] {
+            if let Some(value) = value {
+                let printable = value.chars().all(|c| c.is_ascii_graphic());
+                if value.is_empty() || value.len() > MAX_VALUE_LEN || !printable {
+                    bail!("invalid {}", key);
+                }
+            }
+        }
+        if let Some(width) = self.width {
+            if !(MIN_WIDTH..=MAX_WIDTH).contains(&width) {
+                bail!("width must be between {} and {}", MIN_WIDTH, MAX_WIDTH);
+            }
+        }
+        Ok(self)
+    }
+}
+
+impl FromStr for ClientInfo {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.len() > MAX_LINE_LEN {
+            bail!("too long");
+        }
+        if s.starts_with('{') {
+            let info: Self = serde_json::from_str(s).map_err(|_| anyhow!("invalid JSON"))?;
+            return info.validate();
+        }
+
+        let mut info = Self::default();
+        for pair in s.split_whitespace() {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected key=value, got {}", pair))?;
+            let value = value.to_string();
+            match key {
+                "name" => info.name = Some(value),
+                "version" => info.version = Some(value),
+                "locale" => info.locale = Some(value),
+                "width" => info.width = Some(value.parse().map_err(|_| anyhow!("invalid width"))?),
+                _ => bail!("unknown key {}", key),
+            }
+        }
+        info.validate()
+    }
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        match (&self.name, &self.version) {
+            (Some(name), Some(version)) => parts.push(format!("client {} {}", name, version)),
+            (Some(name), None) => parts.push(format!("client {}", name)),
+            (None, Some(version)) => parts.push(format!("client version {}", version)),
+            (None, None) => {}
+        }
+        if let Some(locale) = &self.locale {
+            parts.push(format!("locale {}", locale));
+        }
+        if let Some(width) = self.width {
+            parts.push(format!("width {}", width));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_values_and_json_agree() {
+        let pairs: ClientInfo = "name=termchat version=1.2 locale=en_US width=120"
+            .parse()
+            .unwrap();
+        let json: ClientInfo =
+            r#"{"name": "termchat", "version": "1.2", "locale": "en_US", "width": 120}"#
+                .parse()
+                .unwrap();
+        assert_eq!(pairs, json);
+        assert_eq!(
+            pairs.to_string(),
+            "client termchat 1.2, locale en_US, width 120"
+        );
+        assert_eq!(
+            "locale=de".parse::<ClientInfo>().unwrap().to_string(),
+            "locale de"
+        );
+    }
+
+    #[test]
+    fn test_bad_info_is_refused() {
+        for line in [
+            "name",
+            "colour=red",
+            "width=5",
+            "width=wide",
+            "name=",
+            r#"{"name": "a b"}"#,
+            r#"{"shell": "zsh"}"#,
+            &format!("name={}", "x".repeat(65)),
+            &format!("name=x {}", "locale=en ".repeat(60)),
+        ] {
+            assert!(line.parse::<ClientInfo>().is_err(), "{}", line);
+        }
+    }
+}
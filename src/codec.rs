@@ -0,0 +1,242 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bytes::{BufMut, BytesMut};
+use flate2::{Compress, Compression, FlushCompress, Status};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tokio_util::codec::{Decoder, Encoder, LinesCodec, LinesCodecError};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    Crlf,
+}
+
+impl LineEnding {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::Crlf => b"\r\n",
+        }
+    }
+}
+
+/// Bytes that went through a connection's codec in each direction, and
+/// when any last did.
+#[derive(Debug)]
+pub struct Traffic {
+    received: AtomicU64,
+    sent: AtomicU64,
+    last_io: Mutex<Instant>,
+}
+
+impl Default for Traffic {
+    fn default() -> Self {
+        Self {
+            received: AtomicU64::default(),
+            sent: AtomicU64::default(),
+            last_io: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl Traffic {
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// When bytes last went through in either direction.
+    pub fn last_io(&self) -> Instant {
+        *self.last_io.lock().unwrap()
+    }
+
+    fn touch(&self) {
+        *self.last_io.lock().unwrap() = Instant::now();
+    }
+}
+
+/// A `LinesCodec` that terminates outbound lines with a configurable line
+/// ending. Inbound lines accept either ending. Outbound lines can switch to
+/// a raw DEFLATE stream, sync-flushed after every line.
+#[derive(Debug)]
+pub struct ChatCodec {
+    lines: LinesCodec,
+    line_ending: LineEnding,
+    traffic: Arc<Traffic>,
+    /// Set from outside to compress every line encoded from then on.
+    deflate: Arc<AtomicBool>,
+    compressor: Option<Compress>,
+}
+
+impl ChatCodec {
+    pub fn new(line_ending: LineEnding) -> Self {
+        Self {
+            lines: LinesCodec::new(),
+            line_ending,
+            traffic: Arc::default(),
+            deflate: Arc::default(),
+            compressor: None,
+        }
+    }
+
+    /// Counters shared with everyone who asks, readable once the codec has
+    /// moved into a `Framed`.
+    pub fn traffic(&self) -> Arc<Traffic> {
+        self.traffic.clone()
+    }
+
+    /// A switch that, once set, compresses the lines encoded after it.
+    /// There is no going back within a connection.
+    pub fn deflate_switch(&self) -> Arc<AtomicBool> {
+        self.deflate.clone()
+    }
+
+    fn count_received(&self, before: usize, buf: &BytesMut) {
+        let consumed = before.saturating_sub(buf.len()) as u64;
+        if consumed > 0 {
+            self.traffic.received.fetch_add(consumed, Ordering::Relaxed);
+            self.traffic.touch();
+        }
+    }
+}
+
+impl Decoder for ChatCodec {
+    type Item = String;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        let before = buf.len();
+        let line = self.lines.decode(buf);
+        self.count_received(before, buf);
+        line
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        let before = buf.len();
+        let line = self.lines.decode_eof(buf);
+        self.count_received(before, buf);
+        line
+    }
+}
+
+impl<T: AsRef<str>> Encoder<T> for ChatCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, line: T, buf: &mut BytesMut) -> Result<(), LinesCodecError> {
+        let line = line.as_ref();
+        let ending = self.line_ending.as_bytes();
+        if self.compressor.is_none() && self.deflate.load(Ordering::Relaxed) {
+            self.compressor = Some(Compress::new(Compression::default(), false));
+        }
+        let before = buf.len();
+        match &mut self.compressor {
+            Some(compressor) => {
+                let mut plain = Vec::with_capacity(line.len() + ending.len());
+                plain.extend_from_slice(line.as_bytes());
+                plain.extend_from_slice(ending);
+                deflate(compressor, &plain, buf)?;
+            }
+            None => {
+                buf.reserve(line.len() + ending.len());
+                buf.put(line.as_bytes());
+                buf.put(ending);
+            }
+        }
+        self.traffic
+            .sent
+            .fetch_add((buf.len() - before) as u64, Ordering::Relaxed);
+        self.traffic.touch();
+        Ok(())
+    }
+}
+
+/// Compresses `input` onto `buf` and sync-flushes, so the peer can inflate
+/// everything written so far.
+fn deflate(compressor: &mut Compress, mut input: &[u8], buf: &mut BytesMut) -> io::Result<()> {
+    let mut out = Vec::with_capacity(input.len() + 64);
+    loop {
+        let consumed = compressor.total_in();
+        let status = compressor
+            .compress_vec(input, &mut out, FlushCompress::Sync)
+            .map_err(io::Error::other)?;
+        input = &input[(compressor.total_in() - consumed) as usize..];
+        // the flush is done once it stops short of filling the output
+        if status == Status::BufError || (input.is_empty() && out.len() < out.capacity()) {
+            break;
+        }
+        out.reserve(out.capacity());
+    }
+    buf.extend_from_slice(&out);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_line_endings() -> anyhow::Result<()> {
+        let mut buf = BytesMut::new();
+        ChatCodec::new(LineEnding::Lf).encode("hi", &mut buf)?;
+        assert_eq!(&buf[..], b"hi\n");
+
+        let mut buf = BytesMut::new();
+        ChatCodec::new(LineEnding::Crlf).encode("hi", &mut buf)?;
+        assert_eq!(&buf[..], b"hi\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_either_line_ending() -> anyhow::Result<()> {
+        let mut codec = ChatCodec::new(LineEnding::Lf);
+        let mut buf = BytesMut::from(&b"one\r\ntwo\n"[..]);
+        assert_eq!(codec.decode(&mut buf)?.as_deref(), Some("one"));
+        assert_eq!(codec.decode(&mut buf)?.as_deref(), Some("two"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_counts_traffic() -> anyhow::Result<()> {
+        let mut codec = ChatCodec::new(LineEnding::Crlf);
+        let traffic = codec.traffic();
+        let mut buf = BytesMut::from(&b"one\r\ntw"[..]);
+        codec.decode(&mut buf)?;
+        codec.decode(&mut buf)?;
+        assert_eq!(traffic.received(), 5);
+
+        codec.encode("hello", &mut BytesMut::new())?;
+        assert_eq!(traffic.sent(), 7);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deflate_switch() -> anyhow::Result<()> {
+        let mut codec = ChatCodec::new(LineEnding::Lf);
+        let mut buf = BytesMut::new();
+        codec.encode("plain", &mut buf)?;
+        codec.deflate_switch().store(true, Ordering::Relaxed);
+        codec.encode("squeezed", &mut buf)?;
+        codec.encode("squeezed again", &mut buf)?;
+        assert_eq!(&buf[..6], b"plain\n");
+        assert_eq!(codec.traffic().sent(), buf.len() as u64);
+
+        // each line is flushed, so the stream inflates without an end
+        let mut inflater = flate2::Decompress::new(false);
+        let mut out = Vec::with_capacity(1024);
+        inflater.decompress_vec(&buf[6..], &mut out, flate2::FlushDecompress::Sync)?;
+        assert_eq!(out, b"squeezed\nsqueezed again\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,491 @@
+use anyhow::{anyhow, bail, Result};
+
+use crate::channel::channel_name;
+use crate::config::CommandsConfig;
+use crate::dice::Dice;
+
+const MAX_NICK_LEN: usize = 32;
+
+/// Names of every built-in command, without the leading slash.
+pub const BUILTIN_COMMANDS: &[&str] = &[
+    "admin",
+    "clearchannel",
+    "compress",
+    "connections",
+    "deop",
+    "dnd",
+    "drain",
+    "export",
+    "format",
+    "invite",
+    "inviteonly",
+    "join",
+    "kick",
+    "motd",
+    "msg",
+    "names",
+    "nick",
+    "op",
+    "part",
+    "pin",
+    "pinned",
+    "quiet",
+    "reply-to",
+    "roll",
+    "search",
+    "setwelcome",
+    "slowmode",
+    "stats",
+    "timestamps",
+    "topic",
+    "unpin",
+    "version",
+    "who",
+    "whoami",
+    "whois",
+    "wrap",
+];
+
+/// A slash command sent by a peer instead of a chat message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `/msg <user> <text>` sends a private message.
+    Msg { to: String, text: String },
+    /// `/dnd on|off` toggles do-not-disturb, which refuses private messages.
+    Dnd(bool),
+    /// `/quiet on|off` toggles getting only server notices, without chat.
+    Quiet(bool),
+    /// `/timestamps on|off` toggles showing when chat messages were sent.
+    Timestamps(bool),
+    /// `/wrap on|off` toggles wrapping long lines to the terminal's width.
+    Wrap(bool),
+    /// `/compress on` compresses everything the server sends from then on
+    /// as a raw DEFLATE stream.
+    Compress(bool),
+    /// `/join <channel>` joins a channel, creating it on demand, and makes
+    /// it the current one.
+    Join(String),
+    /// `/part [channel]` leaves a channel, the current one by default.
+    Part(Option<String>),
+    /// `/invite <user> <channel>` lets a user join an invite-only channel.
+    Invite { user: String, channel: String },
+    /// `/inviteonly on|off` (op) restricts the current channel to invited
+    /// users.
+    InviteOnly(bool),
+    /// `/op <user>` (op) makes a member an operator of the current channel.
+    Op(String),
+    /// `/deop <user>` (op) takes a member's operator status away.
+    Deop(String),
+    /// `/topic [text]` shows the current channel's topic, or (op) sets it.
+    Topic(Option<String>),
+    /// `/pin <message>` (op) pins a message to the current channel, shown
+    /// to everyone who joins it.
+    Pin(String),
+    /// `/unpin` (op) clears the current channel's pinned message.
+    Unpin,
+    /// `/pinned` shows the current channel's pinned message.
+    Pinned,
+    /// `/setwelcome [text]` (op) sets the welcome shown to whoever joins
+    /// the current channel; no text clears it.
+    SetWelcome(Option<String>),
+    /// `/format [template]` (op) sets how chat in the current channel is
+    /// rendered, using `{sender}`, `{content}`, `{timestamp}` and `{channel}`;
+    /// no template goes back to the server's format.
+    Format(Option<String>),
+    /// `/kick <user>` (op) removes a member from the current channel.
+    Kick(String),
+    /// `/names [channel]` lists a channel's members, the current one by
+    /// default, marking operators with `@`.
+    Names(Option<String>),
+    /// `/nick <name>` changes the display name, keeping the login name.
+    Nick(String),
+    /// `/motd` shows the message of the day again.
+    Motd,
+    /// `/admin <password>` grants admin rights.
+    Admin(String),
+    /// `/slowmode <seconds>` (op) sets the minimum interval between
+    /// messages from one user in the current channel; 0 turns it off.
+    Slowmode(u64),
+    /// `/clearchannel <channel>` (admin) empties a channel and deletes it.
+    ClearChannel(String),
+    /// `/roll <count>d<sides>` rolls dice for everyone in the current
+    /// channel to see.
+    Roll(Dice),
+    /// `/export <channel>` (admin) exports the channel's recent history.
+    Export(String),
+    /// `/drain` (admin) stops accepting new peers ahead of maintenance.
+    Drain,
+    /// `/stats` shows peer and channel counts and whether the server is
+    /// draining.
+    Stats,
+    /// `/who [page]` lists the users online, a page at a time.
+    Who(usize),
+    /// `/whois <user>` shows a peer's current channel and traffic.
+    Whois(String),
+    /// `/whoami` shows the caller's own names, channel, roles and session.
+    Whoami,
+    /// `/connections` (admin) lists every peer with its address.
+    Connections,
+    /// `/version` shows the server version, build and capabilities.
+    Version,
+    /// `/search <query> [limit]` searches recent history.
+    Search { query: String, limit: Option<usize> },
+    /// `/reply-to <id> <text>` says something in the current channel,
+    /// quoting the message with that id.
+    ReplyTo { id: u64, text: String },
+}
+
+impl Command {
+    /// Like [`Command::parse`], but refuses commands the config disables.
+    pub fn parse_enabled(line: &str, config: &CommandsConfig) -> Option<Result<Self>> {
+        let name = line
+            .strip_prefix('/')?
+            .split(' ')
+            .next()
+            .unwrap_or_default();
+        if BUILTIN_COMMANDS.contains(&name) && !config.is_enabled(name) {
+            return Some(Err(anyhow!("Command disabled: /{}", name)));
+        }
+        Self::parse(line)
+    }
+
+    /// Returns `None` when the line is an ordinary chat message.
+    pub fn parse(line: &str) -> Option<Result<Self>> {
+        let line = line.strip_prefix('/')?;
+        let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+        let args = args.trim();
+
+        Some(match name {
+            "msg" => parse_msg(args),
+            "dnd" => parse_toggle(args).map(Command::Dnd),
+            "quiet" => parse_toggle(args).map(Command::Quiet),
+            "timestamps" => parse_toggle(args).map(Command::Timestamps),
+            "wrap" => parse_toggle(args).map(Command::Wrap),
+            "compress" => parse_toggle(args).map(Command::Compress),
+            "join" if args.is_empty() => Err(anyhow!("Usage: /join <channel>")),
+            "join" => channel_name(args).map(Command::Join),
+            "part" if args.is_empty() => Ok(Command::Part(None)),
+            "part" => channel_name(args).map(|name| Command::Part(Some(name))),
+            "names" if args.is_empty() => Ok(Command::Names(None)),
+            "names" => channel_name(args).map(|name| Command::Names(Some(name))),
+            "invite" => parse_invite(args),
+            "inviteonly" => parse_toggle(args).map(Command::InviteOnly),
+            "op" => parse_user(args, "/op <user>").map(Command::Op),
+            "deop" => parse_user(args, "/deop <user>").map(Command::Deop),
+            "kick" => parse_user(args, "/kick <user>").map(Command::Kick),
+            "pin" if args.is_empty() => Err(anyhow!("Usage: /pin <message>")),
+            "pin" => Ok(Command::Pin(args.to_string())),
+            "unpin" => Ok(Command::Unpin),
+            "pinned" => Ok(Command::Pinned),
+            "setwelcome" if args.is_empty() => Ok(Command::SetWelcome(None)),
+            "setwelcome" => Ok(Command::SetWelcome(Some(args.to_string()))),
+            "format" if args.is_empty() => Ok(Command::Format(None)),
+            "format" => Ok(Command::Format(Some(args.to_string()))),
+            "topic" if args.is_empty() => Ok(Command::Topic(None)),
+            "topic" => Ok(Command::Topic(Some(args.to_string()))),
+            "search" => parse_search(args),
+            "reply-to" => parse_reply_to(args),
+            "nick" => parse_nick(args),
+            "motd" => Ok(Command::Motd),
+            "admin" if args.is_empty() => Err(anyhow!("Usage: /admin <password>")),
+            "admin" => Ok(Command::Admin(args.to_string())),
+            "slowmode" => args
+                .parse()
+                .map(Command::Slowmode)
+                .map_err(|_| anyhow!("Usage: /slowmode <seconds>")),
+            "clearchannel" if args.is_empty() => Err(anyhow!("Usage: /clearchannel <channel>")),
+            "clearchannel" => channel_name(args).map(Command::ClearChannel),
+            "roll" => args.parse().map(Command::Roll),
+            "drain" => Ok(Command::Drain),
+            "stats" => Ok(Command::Stats),
+            "version" => Ok(Command::Version),
+            "connections" => Ok(Command::Connections),
+            "who" if args.is_empty() => Ok(Command::Who(1)),
+            "who" => match args.parse() {
+                Ok(page) if page > 0 => Ok(Command::Who(page)),
+                _ => Err(anyhow!("Usage: /who [page]")),
+            },
+            "whoami" => Ok(Command::Whoami),
+            "whois" if args.is_empty() => Err(anyhow!("Usage: /whois <user>")),
+            "whois" => Ok(Command::Whois(args.to_string())),
+            "export" if args.is_empty() => Err(anyhow!("Usage: /export <channel>")),
+            "export" => channel_name(args).map(Command::Export),
+            _ => Err(anyhow!("Unknown command: /{}", name)),
+        })
+    }
+}
+
+fn parse_msg(args: &str) -> Result<Command> {
+    match args.split_once(' ') {
+        Some((to, text)) if !text.trim().is_empty() => Ok(Command::Msg {
+            to: to.to_string(),
+            text: text.trim().to_string(),
+        }),
+        _ => bail!("Usage: /msg <user> <text>"),
+    }
+}
+
+fn parse_invite(args: &str) -> Result<Command> {
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [user, channel] => Ok(Command::Invite {
+            user: user.to_string(),
+            channel: channel_name(channel)?,
+        }),
+        _ => bail!("Usage: /invite <user> <channel>"),
+    }
+}
+
+fn parse_search(args: &str) -> Result<Command> {
+    let (query, limit) = match args.rsplit_once(' ') {
+        Some((query, limit)) => match limit.parse() {
+            Ok(limit) => (query.trim(), Some(limit)),
+            Err(_) => (args, None),
+        },
+        None => (args, None),
+    };
+    if query.is_empty() {
+        bail!("Usage: /search <query> [limit]");
+    }
+    Ok(Command::Search {
+        query: query.to_string(),
+        limit,
+    })
+}
+
+fn parse_reply_to(args: &str) -> Result<Command> {
+    match args.split_once(' ') {
+        Some((id, text)) if !text.trim().is_empty() => Ok(Command::ReplyTo {
+            id: id
+                .parse()
+                .map_err(|_| anyhow!("Usage: /reply-to <id> <text>"))?,
+            text: text.trim().to_string(),
+        }),
+        _ => bail!("Usage: /reply-to <id> <text>"),
+    }
+}
+
+fn parse_user(args: &str, usage: &str) -> Result<String> {
+    if !valid_name(args) {
+        bail!("Usage: {}", usage);
+    }
+    Ok(args.to_string())
+}
+
+/// Whether a login or display name is acceptable.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NICK_LEN && !name.contains(char::is_whitespace)
+}
+
+fn parse_nick(args: &str) -> Result<Command> {
+    if !valid_name(args) {
+        bail!(
+            "Usage: /nick <name> (up to {} characters, no spaces)",
+            MAX_NICK_LEN
+        );
+    }
+    Ok(Command::Nick(args.to_string()))
+}
+
+fn parse_toggle(args: &str) -> Result<bool> {
+    match args {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => bail!("Expected `on` or `off`"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_plain_message() {
+        assert!(Command::parse("hello /msg").is_none());
+    }
+
+    #[test]
+    fn test_parse_msg() {
+        let cmd = Command::parse("/msg bob hi there").unwrap().unwrap();
+        assert_eq!(
+            cmd,
+            Command::Msg {
+                to: "bob".to_string(),
+                text: "hi there".to_string()
+            }
+        );
+        assert!(Command::parse("/msg bob").unwrap().is_err());
+    }
+
+    #[test]
+    fn test_parse_dnd() {
+        assert_eq!(
+            Command::parse("/dnd on").unwrap().unwrap(),
+            Command::Dnd(true)
+        );
+        assert_eq!(
+            Command::parse("/dnd off").unwrap().unwrap(),
+            Command::Dnd(false)
+        );
+        assert!(Command::parse("/dnd maybe").unwrap().is_err());
+        assert!(Command::parse("/nope").unwrap().is_err());
+    }
+
+    #[test]
+    fn test_parse_join_part() {
+        assert_eq!(
+            Command::parse("/join #Rust").unwrap().unwrap(),
+            Command::Join("rust".to_string())
+        );
+        assert!(Command::parse("/join").unwrap().is_err());
+        assert_eq!(
+            Command::parse("/part").unwrap().unwrap(),
+            Command::Part(None)
+        );
+        assert_eq!(
+            Command::parse("/part rust").unwrap().unwrap(),
+            Command::Part(Some("rust".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_invite() {
+        assert_eq!(
+            Command::parse("/invite bob #secret").unwrap().unwrap(),
+            Command::Invite {
+                user: "bob".to_string(),
+                channel: "secret".to_string()
+            }
+        );
+        assert!(Command::parse("/invite bob").unwrap().is_err());
+        assert_eq!(
+            Command::parse("/inviteonly on").unwrap().unwrap(),
+            Command::InviteOnly(true)
+        );
+    }
+
+    #[test]
+    fn test_parse_nick() {
+        assert_eq!(
+            Command::parse("/nick ally").unwrap().unwrap(),
+            Command::Nick("ally".to_string())
+        );
+        assert!(Command::parse("/nick").unwrap().is_err());
+        assert!(Command::parse("/nick two words").unwrap().is_err());
+    }
+
+    #[test]
+    fn test_parse_slowmode() {
+        assert_eq!(
+            Command::parse("/slowmode 30").unwrap().unwrap(),
+            Command::Slowmode(30)
+        );
+        assert!(Command::parse("/slowmode soon").unwrap().is_err());
+    }
+
+    #[test]
+    fn test_parse_enabled() {
+        let config = CommandsConfig {
+            enabled: None,
+            disabled: vec!["nick".to_string()],
+        };
+        let err = Command::parse_enabled("/nick bob", &config)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Command disabled: /nick");
+        assert!(Command::parse_enabled("/motd", &config).unwrap().is_ok());
+        let err = Command::parse_enabled("/nope", &config)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Unknown command: /nope");
+
+        let config = CommandsConfig {
+            enabled: Some(vec!["msg".to_string()]),
+            disabled: Vec::new(),
+        };
+        assert!(Command::parse_enabled("/msg bob hi", &config)
+            .unwrap()
+            .is_ok());
+        assert!(Command::parse_enabled("/motd", &config).unwrap().is_err());
+        assert!(Command::parse_enabled("hello", &config).is_none());
+    }
+
+    #[test]
+    fn test_parse_op_commands() {
+        assert_eq!(
+            Command::parse("/op bob").unwrap().unwrap(),
+            Command::Op("bob".to_string())
+        );
+        assert!(Command::parse("/deop").unwrap().is_err());
+        assert!(Command::parse("/kick bob carol").unwrap().is_err());
+        assert_eq!(
+            Command::parse("/topic").unwrap().unwrap(),
+            Command::Topic(None)
+        );
+        assert_eq!(
+            Command::parse("/topic Rust 2024 news").unwrap().unwrap(),
+            Command::Topic(Some("Rust 2024 news".to_string()))
+        );
+        assert_eq!(
+            Command::parse("/pin Read the rules").unwrap().unwrap(),
+            Command::Pin("Read the rules".to_string())
+        );
+        assert!(Command::parse("/pin").unwrap().is_err());
+        assert_eq!(
+            Command::parse("/setwelcome Be kind").unwrap().unwrap(),
+            Command::SetWelcome(Some("Be kind".to_string()))
+        );
+        assert_eq!(
+            Command::parse("/setwelcome").unwrap().unwrap(),
+            Command::SetWelcome(None)
+        );
+    }
+
+    #[test]
+    fn test_parse_who() {
+        assert_eq!(Command::parse("/who").unwrap().unwrap(), Command::Who(1));
+        assert_eq!(Command::parse("/who 3").unwrap().unwrap(), Command::Who(3));
+        assert!(Command::parse("/who 0").unwrap().is_err());
+        assert!(Command::parse("/who next").unwrap().is_err());
+    }
+
+    #[test]
+    fn test_parse_clearchannel() {
+        assert_eq!(
+            Command::parse("/clearchannel #Room").unwrap().unwrap(),
+            Command::ClearChannel("room".to_string())
+        );
+        assert!(Command::parse("/clearchannel").unwrap().is_err());
+    }
+
+    #[test]
+    fn test_parse_search() {
+        assert_eq!(
+            Command::parse("/search hello world 5").unwrap().unwrap(),
+            Command::Search {
+                query: "hello world".to_string(),
+                limit: Some(5)
+            }
+        );
+        assert_eq!(
+            Command::parse("/search hello").unwrap().unwrap(),
+            Command::Search {
+                query: "hello".to_string(),
+                limit: None
+            }
+        );
+        assert!(Command::parse("/search").unwrap().is_err());
+    }
+
+    #[test]
+    fn test_parse_reply_to() {
+        assert_eq!(
+            Command::parse("/reply-to 7 good point").unwrap().unwrap(),
+            Command::ReplyTo {
+                id: 7,
+                text: "good point".to_string()
+            }
+        );
+        assert!(Command::parse("/reply-to 7").unwrap().is_err());
+        assert!(Command::parse("/reply-to seven hi").unwrap().is_err());
+    }
+}
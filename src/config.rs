@@ -0,0 +1,724 @@
+use std::{
+    collections::BTreeMap,
+    env, fmt,
+    fs::File,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::challenge::ChallengeConfig;
+use crate::channel::DEFAULT_CHANNEL;
+use crate::codec::LineEnding;
+use crate::federation::FederationConfig;
+use crate::health::HealthConfig;
+use crate::onboarding::OnboardingConfig;
+use crate::persistence::PersistenceConfig;
+use crate::raid::RaidConfig;
+use crate::resume::ResumeConfig;
+use crate::runtime::RuntimeConfig;
+use crate::store::HistoryStoreConfig;
+use crate::tls::TlsConfig;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// Listen on `host:port`. Turn off to only serve the Unix socket.
+    #[serde(default = "default_true")]
+    pub listen_tcp: bool,
+    /// Serve TLS instead of plain TCP on `host:port`.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Also listen on a Unix domain socket at this path.
+    #[serde(default)]
+    pub unix_socket_path: Option<PathBuf>,
+    /// Answer liveness and readiness probes over HTTP.
+    #[serde(default)]
+    pub health: Option<HealthConfig>,
+    /// Disconnect every peer this many seconds after they log in, however
+    /// active they are; 0 disables the limit.
+    #[serde(default)]
+    pub max_session_secs: u64,
+    /// Disconnect peers that send nothing for this many seconds; 0 disables
+    /// the timeout.
+    #[serde(default)]
+    pub idle_timeout_secs: u64,
+    /// Warn idle peers this many seconds before `idle_timeout_secs` runs
+    /// out; 0 disconnects them without a warning.
+    #[serde(default)]
+    pub idle_nudge_secs: u64,
+    /// Close connections that neither read nor write anything for this many
+    /// seconds, checked by a periodic sweep; 0 disables the watchdog.
+    #[serde(default)]
+    pub io_timeout_secs: u64,
+    /// Log accepted connections as a summary every this many seconds, and
+    /// each one only at debug level; 0 logs each one at info level.
+    #[serde(default)]
+    pub accept_log_summary_secs: u64,
+    /// Hold back leave notices this many seconds, and drop both the leave
+    /// and the join if the user is back by then; 0 announces every one.
+    #[serde(default)]
+    pub presence_cooldown_secs: u64,
+    /// Maximum number of connected clients; 0 means no limit.
+    #[serde(default)]
+    pub max_connections: usize,
+    /// Connections over `max_connections` wait in a queue of this length
+    /// for a free slot; anyone beyond it is refused.
+    #[serde(default)]
+    pub max_queue: usize,
+    /// Maximum number of broadcasts fanning out at once; further senders
+    /// wait for a turn. 0 means no limit.
+    #[serde(default)]
+    pub max_concurrent_broadcasts: usize,
+    /// While draining, disconnect peers that have been quiet for this many
+    /// seconds; 0 leaves idle peers alone.
+    #[serde(default)]
+    pub drain_idle_secs: u64,
+    /// Disconnect everyone this many seconds after draining starts; 0 waits
+    /// for peers to leave on their own.
+    #[serde(default)]
+    pub drain_timeout_secs: u64,
+    /// Scheduler and threads the server runs on.
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+    /// Maximum number of pending connections queued by the kernel.
+    #[serde(default = "default_backlog")]
+    pub backlog: u32,
+    /// Bind with `SO_REUSEPORT`, so that during an upgrade the new server
+    /// can start accepting on the same port while this one drains.
+    #[serde(default)]
+    pub reuse_port: bool,
+    /// Wire format used for outbound messages.
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// When the writer flushes outbound messages to the socket.
+    #[serde(default)]
+    pub flush_policy: FlushPolicy,
+    /// Drop a peer whose connection takes longer than this many seconds
+    /// to accept a write; 0 waits forever.
+    #[serde(default)]
+    pub send_timeout_secs: u64,
+    /// Per-peer cap on outbound bytes. Messages queue up, and are dropped
+    /// once the queue is full, while a peer is over it; 0 disables the cap.
+    #[serde(default)]
+    pub max_bytes_per_sec: u64,
+    /// Tell a peer how many messages were dropped for it, because it fell
+    /// behind, once it has caught up. Either way `/whois` shows the count.
+    #[serde(default)]
+    pub report_dropped_messages: bool,
+    /// Initial size of each connection's read buffer. Smaller buffers use
+    /// less memory per peer; larger ones take fewer reads for long lines.
+    /// The buffer still grows to fit a whole line.
+    #[serde(default = "default_read_buffer_bytes")]
+    pub read_buffer_bytes: usize,
+    /// Line ending appended to outbound lines; telnet clients want `crlf`.
+    #[serde(default)]
+    pub line_ending: LineEnding,
+    /// Message of the day shown to peers when they connect. Reloadable.
+    #[serde(default)]
+    pub motd: Option<String>,
+    /// Private messages sent to each new user from a bot.
+    #[serde(default)]
+    pub onboarding: Option<OnboardingConfig>,
+    /// Per-peer limit on inbound lines; unlimited when unset. Reloadable.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Addresses refused at connect time. Reloadable.
+    #[serde(default)]
+    pub banned_ips: Vec<IpAddr>,
+    /// Rules shown after login that peers must `/accept` before chatting.
+    #[serde(default)]
+    pub terms: Option<String>,
+    /// How long a peer has to accept the terms before being disconnected.
+    #[serde(default = "default_terms_timeout_secs")]
+    pub terms_timeout_secs: u64,
+    /// Make new connections answer a simple sum before the username prompt.
+    #[serde(default)]
+    pub challenge: Option<ChallengeConfig>,
+    /// Tighten up automatically while connections arrive unusually fast.
+    #[serde(default)]
+    pub raid: Option<RaidConfig>,
+    /// How many times a peer may pick an invalid or taken username before
+    /// being disconnected.
+    #[serde(default = "default_max_username_attempts")]
+    pub max_username_attempts: u32,
+    /// How closely the first line a client sends is checked for binary
+    /// data, which gets the connection refused.
+    #[serde(default)]
+    pub binary_check: BinaryCheck,
+    /// Which slash commands peers may use.
+    #[serde(default)]
+    pub commands: CommandsConfig,
+    /// Line sent to refused connections; `{code}`, `{reason}` and
+    /// `{contact}` are filled in.
+    #[serde(default = "default_rejection_template")]
+    pub rejection_template: String,
+    /// Who refused clients can get in touch with, such as an email or URL.
+    #[serde(default)]
+    pub operator_contact: Option<String>,
+    /// Channel every peer joins on connect and talks in until they switch.
+    #[serde(default = "default_channel")]
+    pub default_channel: String,
+    /// Further channels peers join on connect. They are created at startup
+    /// and, like the default channel, kept while empty.
+    #[serde(default)]
+    pub auto_join: Vec<String>,
+    /// Maximum number of channels a single peer can be a member of,
+    /// including the default channel.
+    #[serde(default = "default_max_channels_per_user")]
+    pub max_channels_per_user: usize,
+    /// Maximum number of channels with members, server-wide; joining would
+    /// not create one past it. 0 means no limit.
+    #[serde(default)]
+    pub max_channels: usize,
+    /// Number of users listed on each page of `/who`.
+    #[serde(default = "default_who_page_size")]
+    pub who_page_size: usize,
+    /// What `/clearchannel` does with the members of the channel.
+    #[serde(default)]
+    pub clear_channel_action: ClearChannelAction,
+    /// How long an invite to an invite-only channel stays valid; 0 means
+    /// invites never expire.
+    #[serde(default = "default_invite_ttl_secs")]
+    pub invite_ttl_secs: u64,
+    /// Number of recent chat messages kept in memory for `/search`.
+    #[serde(default = "default_history_size")]
+    pub history_size: usize,
+    /// Where chat history is kept; in memory, `history_size` messages of it.
+    #[serde(default)]
+    pub history_store: HistoryStoreConfig,
+    /// Whether text protocol peers see when chat messages were sent until
+    /// they change it with `/timestamps`.
+    #[serde(default)]
+    pub show_timestamps: bool,
+    /// Whether text protocol peers get long lines wrapped to their
+    /// terminal's width until they change it with `/wrap`.
+    #[serde(default)]
+    pub wrap_lines: bool,
+    /// Columns to wrap to for clients that didn't send their width.
+    #[serde(default = "default_wrap_width")]
+    pub wrap_width: usize,
+    /// Drop chat lines that are empty once trailing whitespace is trimmed.
+    #[serde(default = "default_true")]
+    pub suppress_empty_messages: bool,
+    /// Most files a JSON mode message can have attached; 0 allows none.
+    #[serde(default = "default_max_attachments")]
+    pub max_attachments: usize,
+    /// Largest attachment size a message may declare, in bytes; 0 means
+    /// no limit.
+    #[serde(default)]
+    pub max_attachment_bytes: u64,
+    /// Let peers compress what the server sends them with `/compress on`.
+    #[serde(default)]
+    pub compression: bool,
+    /// Chat macros by name: a message that is `!name`, or starts with
+    /// `!name `, has that token replaced with the macro's text.
+    #[serde(default)]
+    pub macros: BTreeMap<String, String>,
+    /// Expand `:shortcode:` sequences in chat messages into emoji.
+    #[serde(default)]
+    pub emoji_shortcodes: bool,
+    /// Let peers that lose their connection resume their session and get
+    /// the messages they missed.
+    #[serde(default)]
+    pub resume: Option<ResumeConfig>,
+    /// Append chat messages to a rotating log file when set.
+    #[serde(default)]
+    pub persistence: Option<PersistenceConfig>,
+    /// Directory `/export` writes channel archives to. Archives are sent to
+    /// the admin directly when unset.
+    #[serde(default)]
+    pub export_dir: Option<PathBuf>,
+    /// Serve runtime diagnostics to tokio-console; needs the `console`
+    /// feature.
+    #[serde(default)]
+    pub tokio_console: bool,
+    /// Password for `/admin`; nobody can become an admin when unset.
+    #[serde(default)]
+    pub admin_password: Option<String>,
+    /// URL that peer joins and leaves are POSTed to as JSON.
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    /// Shared secret used to HMAC-sign messages in JSON mode. Signing is
+    /// disabled when unset.
+    #[serde(default)]
+    pub hmac_secret: Option<String>,
+    /// Relay that channel messages are shared with other nodes through;
+    /// needs `hmac_secret`, which every node checks the others' messages
+    /// with.
+    #[serde(default)]
+    pub federation: Option<FederationConfig>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlushPolicy {
+    /// Flush after every message, for the lowest latency.
+    #[default]
+    Immediate,
+    /// Write every queued message, then flush once.
+    Coalesced,
+}
+
+/// Restricts the commands peers can use; every built-in is enabled by
+/// default.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CommandsConfig {
+    /// Only these commands are available when set.
+    #[serde(default)]
+    pub enabled: Option<Vec<String>>,
+    /// These commands are never available.
+    #[serde(default)]
+    pub disabled: Vec<String>,
+}
+
+impl CommandsConfig {
+    /// Whether a command, named without its leading slash, may be used.
+    pub fn is_enabled(&self, name: &str) -> bool {
+        let allowed = self
+            .enabled
+            .as_ref()
+            .is_none_or(|enabled| enabled.iter().any(|e| e == name));
+        allowed && !self.disabled.iter().any(|d| d == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClearChannelAction {
+    /// Move members to the default channel.
+    #[default]
+    Move,
+    /// Disconnect members from the server.
+    Disconnect,
+}
+
+/// How a client's first line is screened for binary data.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BinaryCheck {
+    /// Accept anything the line codec decodes.
+    Off,
+    /// Refuse lines that aren't UTF-8 or are mostly control characters.
+    #[default]
+    Lenient,
+    /// Refuse lines that aren't UTF-8 or have any control characters.
+    Strict,
+}
+
+impl BinaryCheck {
+    /// Share of control characters above which a lenient check refuses a
+    /// line.
+    const LENIENT_CONTROL_RATIO: f64 = 0.3;
+
+    /// Whether a decoded first line looks like text.
+    pub fn accepts(self, line: &str) -> bool {
+        let total = line.chars().count();
+        let control = line
+            .chars()
+            .filter(|c| c.is_control() && *c != '\t')
+            .count();
+        match self {
+            BinaryCheck::Off => true,
+            BinaryCheck::Lenient => {
+                total == 0 || (control as f64 / total as f64) <= Self::LENIENT_CONTROL_RATIO
+            }
+            BinaryCheck::Strict => control == 0,
+        }
+    }
+}
+
+/// A token bucket allowing `burst` lines at once, refilled at
+/// `messages_per_sec`. A line has to fit the byte budget too, when there
+/// is one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub struct RateLimit {
+    pub messages_per_sec: f64,
+    pub burst: u32,
+    /// Bytes of lines allowed per second; 0 doesn't count bytes.
+    #[serde(default)]
+    pub bytes_per_sec: f64,
+    /// Bytes allowed at once; 0 allows one second's worth.
+    #[serde(default)]
+    pub burst_bytes: u32,
+}
+
+/// The part of the config that can be swapped in while the server runs.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub motd: Option<String>,
+    pub rate_limit: Option<RateLimit>,
+    pub banned_ips: Vec<IpAddr>,
+}
+
+/// Config keys picked up by a reload; changing anything else needs a
+/// restart.
+pub const RELOADABLE_KEYS: &[&str] = &["motd", "rate_limit", "banned_ips"];
+
+impl From<&ServerConfig> for Settings {
+    fn from(config: &ServerConfig) -> Self {
+        Self {
+            motd: config.motd.clone(),
+            rate_limit: config.rate_limit,
+            banned_ips: config.banned_ips.clone(),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_backlog() -> u32 {
+    1024
+}
+
+fn default_read_buffer_bytes() -> usize {
+    8 * 1024
+}
+
+fn default_terms_timeout_secs() -> u64 {
+    60
+}
+
+fn default_max_username_attempts() -> u32 {
+    3
+}
+
+fn default_rejection_template() -> String {
+    "Connection refused ({code}): {reason} {contact}".to_string()
+}
+
+fn default_channel() -> String {
+    DEFAULT_CHANNEL.to_string()
+}
+
+fn default_who_page_size() -> usize {
+    50
+}
+
+fn default_wrap_width() -> usize {
+    80
+}
+
+fn default_max_channels_per_user() -> usize {
+    10
+}
+
+fn default_invite_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_max_attachments() -> usize {
+    10
+}
+
+fn default_history_size() -> usize {
+    1000
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 9999,
+            listen_tcp: true,
+            tls: None,
+            unix_socket_path: None,
+            health: None,
+            max_session_secs: 0,
+            idle_timeout_secs: 0,
+            idle_nudge_secs: 0,
+            io_timeout_secs: 0,
+            accept_log_summary_secs: 0,
+            presence_cooldown_secs: 0,
+            max_connections: 0,
+            max_queue: 0,
+            max_concurrent_broadcasts: 0,
+            drain_idle_secs: 0,
+            drain_timeout_secs: 0,
+            runtime: RuntimeConfig::default(),
+            backlog: default_backlog(),
+            reuse_port: false,
+            protocol: Protocol::default(),
+            flush_policy: FlushPolicy::default(),
+            send_timeout_secs: 0,
+            max_bytes_per_sec: 0,
+            report_dropped_messages: false,
+            read_buffer_bytes: default_read_buffer_bytes(),
+            line_ending: LineEnding::default(),
+            motd: None,
+            onboarding: None,
+            rate_limit: None,
+            banned_ips: Vec::new(),
+            terms: None,
+            terms_timeout_secs: default_terms_timeout_secs(),
+            challenge: None,
+            raid: None,
+            max_username_attempts: default_max_username_attempts(),
+            binary_check: BinaryCheck::default(),
+            commands: CommandsConfig::default(),
+            rejection_template: default_rejection_template(),
+            operator_contact: None,
+            default_channel: default_channel(),
+            auto_join: Vec::new(),
+            max_channels_per_user: default_max_channels_per_user(),
+            max_channels: 0,
+            who_page_size: default_who_page_size(),
+            clear_channel_action: ClearChannelAction::default(),
+            invite_ttl_secs: default_invite_ttl_secs(),
+            history_size: default_history_size(),
+            history_store: HistoryStoreConfig::default(),
+            show_timestamps: false,
+            wrap_lines: false,
+            wrap_width: default_wrap_width(),
+            suppress_empty_messages: true,
+            max_attachments: default_max_attachments(),
+            max_attachment_bytes: 0,
+            compression: false,
+            macros: BTreeMap::new(),
+            emoji_shortcodes: false,
+            resume: None,
+            persistence: None,
+            export_dir: None,
+            tokio_console: false,
+            admin_password: None,
+            webhook_url: None,
+            hmac_secret: None,
+            federation: None,
+        }
+    }
+}
+
+/// A place the config file can be loaded from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// The file named by `APP_CONFIG_PATH`.
+    Env,
+    /// `config.yaml` in the working directory.
+    Local,
+    /// `/etc/config.yaml`.
+    System,
+}
+
+impl FromStr for ConfigSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "env" => Ok(Self::Env),
+            "local" => Ok(Self::Local),
+            "system" => Ok(Self::System),
+            other => bail!(
+                "unknown config source `{}`; expected env, local or system",
+                other
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Env => "env",
+            Self::Local => "local",
+            Self::System => "system",
+        })
+    }
+}
+
+/// The candidate config files and the order they are tried in; the first
+/// one that exists wins.
+#[derive(Debug, Clone)]
+pub struct ConfigSources {
+    pub order: Vec<ConfigSource>,
+    pub env: Option<PathBuf>,
+    pub local: PathBuf,
+    pub system: PathBuf,
+}
+
+impl ConfigSources {
+    /// The explicit `APP_CONFIG_PATH` wins by default, then `config.yaml`,
+    /// then `/etc/config.yaml`. `APP_CONFIG_PRECEDENCE` overrides the order
+    /// with a comma separated list such as `local,system,env`; sources left
+    /// out of it are not tried.
+    pub fn from_env() -> Result<Self> {
+        let order = match env::var("APP_CONFIG_PRECEDENCE") {
+            Ok(order) => order
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<_>>()
+                .context("invalid APP_CONFIG_PRECEDENCE")?,
+            Err(_) => vec![ConfigSource::Env, ConfigSource::Local, ConfigSource::System],
+        };
+        Ok(Self {
+            order,
+            env: env::var_os("APP_CONFIG_PATH").map(PathBuf::from),
+            local: PathBuf::from("config.yaml"),
+            system: PathBuf::from("/etc/config.yaml"),
+        })
+    }
+
+    /// Loads the first source whose file exists. A file that exists but
+    /// can't be read or parsed is an error rather than skipped, and so is an
+    /// `APP_CONFIG_PATH` naming a missing file.
+    pub fn load(&self) -> Result<(ConfigSource, ServerConfig)> {
+        let mut tried = Vec::new();
+        for &source in &self.order {
+            let path = match source {
+                ConfigSource::Env => match &self.env {
+                    Some(path) if !path.exists() => {
+                        bail!("APP_CONFIG_PATH names a missing file: {}", path.display())
+                    }
+                    Some(path) => path,
+                    None => {
+                        tried.push(format!("{} (APP_CONFIG_PATH unset)", source));
+                        continue;
+                    }
+                },
+                ConfigSource::Local => &self.local,
+                ConfigSource::System => &self.system,
+            };
+            if !path.exists() {
+                tried.push(format!("{} ({})", source, path.display()));
+                continue;
+            }
+            let config = ServerConfig::load(path)
+                .with_context(|| format!("failed to load config from {}", path.display()))?;
+            return Ok((source, config));
+        }
+        bail!("Config file not found; tried: {}", tried.join(", "))
+    }
+}
+
+impl ServerConfig {
+    pub fn try_load() -> Result<Self> {
+        let (source, config) = ConfigSources::from_env()?.load()?;
+        info!("Loaded config from the {} source", source);
+        Ok(config)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(serde_yaml::from_reader(File::open(path)?)?)
+    }
+
+    /// Names of the settings that differ from `other` and can't be applied
+    /// without a restart.
+    pub fn restart_required(&self, other: &ServerConfig) -> Result<Vec<String>> {
+        let (serde_yaml::Value::Mapping(current), serde_yaml::Value::Mapping(other)) =
+            (serde_yaml::to_value(self)?, serde_yaml::to_value(other)?)
+        else {
+            bail!("config is not a mapping");
+        };
+
+        Ok(current
+            .iter()
+            .filter_map(|(key, value)| key.as_str().map(|key| (key, value)))
+            .filter(|(key, _)| !RELOADABLE_KEYS.contains(key))
+            .filter(|(key, value)| other.get(*key) != Some(*value))
+            .map(|(key, _)| key.to_string())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restart_required() -> Result<()> {
+        let current = ServerConfig::default();
+        let reloaded = ServerConfig {
+            motd: Some("hi".to_string()),
+            port: 1234,
+            ..Default::default()
+        };
+        assert_eq!(current.restart_required(&reloaded)?, ["port"]);
+        assert!(current.restart_required(&current.clone())?.is_empty());
+        Ok(())
+    }
+
+    fn sources(dir: &Path, order: &[ConfigSource]) -> ConfigSources {
+        ConfigSources {
+            order: order.to_vec(),
+            env: Some(dir.join("env.yaml")),
+            local: dir.join("local.yaml"),
+            system: dir.join("system.yaml"),
+        }
+    }
+
+    #[test]
+    fn test_config_precedence() -> Result<()> {
+        use ConfigSource::*;
+
+        let dir = tempfile::tempdir()?;
+        for (name, port) in [("env", 1), ("local", 2), ("system", 3)] {
+            let config = format!("host: 127.0.0.1\nport: {}\n", port);
+            std::fs::write(dir.path().join(format!("{}.yaml", name)), config)?;
+        }
+
+        let (source, config) = sources(dir.path(), &[Env, Local, System]).load()?;
+        assert_eq!((source, config.port), (Env, 1));
+        let (source, config) = sources(dir.path(), &[Local, System, Env]).load()?;
+        assert_eq!((source, config.port), (Local, 2));
+        let (source, config) = sources(dir.path(), &[System, Env]).load()?;
+        assert_eq!((source, config.port), (System, 3));
+
+        // missing files fall through to the next source
+        std::fs::remove_file(dir.path().join("local.yaml"))?;
+        let (source, _) = sources(dir.path(), &[Local, System]).load()?;
+        assert_eq!(source, System);
+        let unset = ConfigSources {
+            env: None,
+            ..sources(dir.path(), &[Env, System])
+        };
+        assert_eq!(unset.load()?.0, System);
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_env_config_is_an_error() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("local.yaml"), "host: 127.0.0.1\nport: 2\n")?;
+
+        let err = sources(dir.path(), &[ConfigSource::Env, ConfigSource::Local])
+            .load()
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("APP_CONFIG_PATH names a missing file: "));
+        assert!(err.ends_with("env.yaml"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_not_found_lists_sources() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sources = ConfigSources {
+            env: None,
+            ..sources(dir.path(), &[ConfigSource::Env, ConfigSource::Local])
+        };
+        let err = sources.load().unwrap_err().to_string();
+        assert!(
+            err.starts_with("Config file not found; tried: env (APP_CONFIG_PATH unset), local (")
+        );
+        assert!(err.contains("local.yaml"));
+
+        assert_eq!("system".parse::<ConfigSource>()?, ConfigSource::System);
+        assert!("cwd".parse::<ConfigSource>().is_err());
+        Ok(())
+    }
+}
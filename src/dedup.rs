@@ -0,0 +1,92 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{Message, State};
+
+/// The most recent message ids seen by this node, oldest first.
+#[derive(Debug)]
+pub struct SeenSet {
+    inner: Mutex<(VecDeque<Uuid>, HashSet<Uuid>)>,
+    capacity: usize,
+}
+
+impl SeenSet {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new((VecDeque::with_capacity(capacity), HashSet::new())),
+            capacity,
+        }
+    }
+
+    /// Records an id, returning whether it hadn't been seen yet.
+    pub fn insert(&self, id: Uuid) -> bool {
+        let (order, seen) = &mut *self.inner.lock().unwrap();
+        if !seen.insert(id) {
+            return false;
+        }
+        if order.len() == self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                seen.remove(&oldest);
+            }
+        }
+        order.push_back(id);
+        true
+    }
+}
+
+impl State {
+    /// Delivers a channel message published by another node to the local
+    /// members of its channel, once its signature checks out. Messages this
+    /// node already delivered, such as its own coming back from pub/sub,
+    /// are dropped.
+    pub(crate) async fn relay_federated(&self, mut message: Message, signature: Option<&str>) {
+        let signed = match (&self.signer, signature) {
+            (Some(signer), Some(signature)) => signer.verify(&message, signature),
+            _ => false,
+        };
+        if !signed {
+            warn!(
+                "Dropping federated message from {} without a valid signature",
+                message.sender
+            );
+            return;
+        }
+        let (Some(id), Some(channel)) = (message.uuid, message.channel.clone()) else {
+            warn!("Dropping federated message without an id or channel");
+            return;
+        };
+        if !self.seen.insert(id) {
+            return;
+        }
+        // ids are numbered per node, so the sender's would clash with ours
+        message.id = None;
+        let message = Arc::new(message);
+        self.history.append(&message).await;
+        let recipients = self.channel_recipients(&channel, None);
+        self.keep_for_parked(&message);
+        self.deliver(recipients, message).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seen_set_is_bounded() {
+        let seen = SeenSet::new(2);
+        let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        assert!(seen.insert(ids[0]));
+        assert!(!seen.insert(ids[0]));
+        assert!(seen.insert(ids[1]));
+        assert!(seen.insert(ids[2]));
+        // the oldest id was forgotten to make room
+        assert!(seen.insert(ids[0]));
+        assert!(!seen.insert(ids[2]));
+    }
+}
@@ -0,0 +1,117 @@
+use std::{fmt, net::SocketAddr, str::FromStr, sync::Arc};
+
+use anyhow::{anyhow, bail, Error, Result};
+use rand::Rng;
+
+use crate::{Message, State};
+
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+
+/// A dice expression like `2d6`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dice {
+    count: u32,
+    sides: u32,
+}
+
+impl Dice {
+    pub fn roll(self, rng: &mut impl Rng) -> Vec<u32> {
+        (0..self.count)
+            .map(|_| rng.gen_range(1..=self.sides))
+            .collect()
+    }
+}
+
+impl FromStr for Dice {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let usage = || anyhow!("Usage: /roll <count>d<sides>, like /roll 2d6");
+        let (count, sides) = s.split_once(['d', 'D']).ok_or_else(usage)?;
+        let count = match count {
+            "" => 1,
+            count => count.parse().map_err(|_| usage())?,
+        };
+        let sides = sides.parse().map_err(|_| usage())?;
+        if count == 0 || sides == 0 {
+            return Err(usage());
+        }
+        if count > MAX_DICE || sides > MAX_SIDES {
+            bail!(
+                "You can roll at most {} dice with up to {} sides.",
+                MAX_DICE,
+                MAX_SIDES
+            );
+        }
+        Ok(Self { count, sides })
+    }
+}
+
+impl fmt::Display for Dice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}d{}", self.count, self.sides)
+    }
+}
+
+impl State {
+    /// Rolls the dice and shows the result to the peer's current channel.
+    pub(crate) async fn roll(&self, addr: SocketAddr, dice: Dice) {
+        let Some(channel) = self.peers.get(&addr).and_then(|peer| peer.current.clone()) else {
+            self.notify(addr, Message::server("You are not in any channel."))
+                .await;
+            return;
+        };
+        let rolls = dice.roll(&mut rand::thread_rng());
+        let total: u32 = rolls.iter().sum();
+        let rolls: Vec<String> = rolls.iter().map(u32::to_string).collect();
+        let result = Message::server(format!(
+            "* {} rolled {}: {} (total {})",
+            self.display_name(addr),
+            dice,
+            rolls.join(", "),
+            total
+        ))
+        .in_channel(&channel);
+
+        self.broadcast_channel(&channel, addr, Arc::new(result.clone()))
+            .await;
+        self.notify(addr, result).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn test_roll_is_deterministic_for_a_seed() -> Result<()> {
+        let dice: Dice = "2d6".parse()?;
+        let rolls = dice.roll(&mut StdRng::seed_from_u64(7));
+        assert_eq!(rolls, dice.roll(&mut StdRng::seed_from_u64(7)));
+        assert_eq!(rolls.len(), 2);
+        assert!(rolls.iter().all(|roll| (1..=6).contains(roll)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_expressions() {
+        for expr in ["", "d", "2d", "xd6", "2d0", "0d6", "2x6", "-1d6"] {
+            assert!(expr.parse::<Dice>().is_err(), "{:?} parsed", expr);
+        }
+        assert_eq!("d20".parse::<Dice>().unwrap().to_string(), "1d20");
+    }
+
+    #[test]
+    fn test_caps() {
+        assert!("100d1000".parse::<Dice>().is_ok());
+        let err = "101d6".parse::<Dice>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "You can roll at most 100 dice with up to 1000 sides."
+        );
+        assert!("1d1001".parse::<Dice>().is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use std::{
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tracing::info;
+
+use crate::{Message, State};
+
+impl State {
+    pub(crate) fn is_draining(&self) -> bool {
+        self.drain.is_cancelled()
+    }
+
+    /// Stops accepting new peers, tells everyone, and disconnects peers
+    /// that have been idle for `drain_idle_secs`.
+    pub(crate) async fn start_drain(&self) {
+        if self.drain_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.drain.cancel();
+        info!("Draining");
+
+        let notice = "The server is going down for maintenance. New connections are refused.";
+        self.broadcast_all(Arc::new(Message::server(notice))).await;
+
+        let idle_secs = self.server.drain_idle_secs;
+        if idle_secs == 0 {
+            return;
+        }
+        let idle_since = Instant::now() - Duration::from_secs(idle_secs);
+        let idle: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|peer| peer.last_active <= idle_since)
+            .map(|peer| *peer.key())
+            .collect();
+        for addr in idle {
+            self.disconnect(addr, "Disconnected for maintenance while idle.")
+                .await;
+        }
+    }
+
+    pub(crate) async fn drain_command(&self, addr: SocketAddr) {
+        if !self.require_admin(addr).await {
+            return;
+        }
+        if self.is_draining() {
+            self.notify(addr, Message::server("Already draining."))
+                .await;
+            return;
+        }
+        self.start_drain().await;
+    }
+}
+
+/// Drains the server on SIGUSR1 or `/drain`, then disconnects whoever is
+/// left once `drain_timeout_secs` have passed.
+pub fn spawn_drain_handler(state: Arc<State>) -> Result<JoinHandle<()>> {
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+
+    Ok(tokio::spawn(async move {
+        tokio::select! {
+            _ = usr1.recv() => state.start_drain().await,
+            _ = state.drain.cancelled() => {}
+        }
+
+        let timeout = state.server.drain_timeout_secs;
+        if timeout == 0 {
+            return;
+        }
+        time::sleep(Duration::from_secs(timeout)).await;
+        let remaining: Vec<SocketAddr> = state.peers.iter().map(|peer| *peer.key()).collect();
+        info!("Drain timed out, disconnecting {} peers", remaining.len());
+        for addr in remaining {
+            state
+                .disconnect(addr, "Disconnected for maintenance.")
+                .await;
+        }
+    }))
+}
@@ -0,0 +1,102 @@
+/// Expands `:shortcode:` sequences into emoji. Unknown shortcodes are left
+/// untouched.
+pub fn expand(content: &str) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(start) = rest.find(':') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let emoji = after
+            .find(':')
+            .and_then(|end| lookup(&after[..end]).map(|emoji| (emoji, end)));
+
+        match emoji {
+            Some((emoji, end)) => {
+                out.push_str(emoji);
+                rest = &after[end + 1..];
+            }
+            None => {
+                // the closing colon may still open the next shortcode
+                out.push(':');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn lookup(code: &str) -> Option<&'static str> {
+    let emoji = match code {
+        "smile" => "😄",
+        "grin" => "😁",
+        "joy" => "😂",
+        "wink" => "😉",
+        "blush" => "😊",
+        "heart_eyes" => "😍",
+        "thinking" => "🤔",
+        "neutral_face" => "😐",
+        "sweat_smile" => "😅",
+        "cry" => "😢",
+        "sob" => "😭",
+        "angry" => "😠",
+        "scream" => "😱",
+        "sunglasses" => "😎",
+        "sleeping" => "😴",
+        "heart" => "❤️",
+        "broken_heart" => "💔",
+        "thumbsup" | "+1" => "👍",
+        "thumbsdown" | "-1" => "👎",
+        "clap" => "👏",
+        "wave" => "👋",
+        "pray" => "🙏",
+        "ok_hand" => "👌",
+        "muscle" => "💪",
+        "eyes" => "👀",
+        "fire" => "🔥",
+        "star" => "⭐",
+        "sparkles" => "✨",
+        "tada" => "🎉",
+        "rocket" => "🚀",
+        "100" => "💯",
+        "check" => "✅",
+        "x" => "❌",
+        "warning" => "⚠️",
+        "coffee" => "☕",
+        "beer" => "🍺",
+        "pizza" => "🍕",
+        "cake" => "🍰",
+        "sun" => "☀️",
+        "moon" => "🌙",
+        "zap" => "⚡",
+        "bug" => "🐛",
+        "soccer" => "⚽",
+        _ => return None,
+    };
+    Some(emoji)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_known_shortcode() {
+        assert_eq!(expand("hello :smile:"), "hello 😄");
+        assert_eq!(expand(":+1::tada:"), "👍🎉");
+    }
+
+    #[test]
+    fn test_unknown_shortcode_untouched() {
+        assert_eq!(expand("hi :notanemoji: there"), "hi :notanemoji: there");
+        assert_eq!(expand("time is 10:30"), "time is 10:30");
+    }
+
+    #[test]
+    fn test_shortcode_next_to_punctuation() {
+        assert_eq!(expand("great job:clap:!"), "great job👏!");
+        assert_eq!(expand("(:fire:), ok"), "(🔥), ok");
+        assert_eq!(expand("ratio 1:smile:"), "ratio 1😄");
+    }
+}
@@ -0,0 +1,66 @@
+use std::{net::SocketAddr, path::PathBuf, time::SystemTime};
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use crate::store::unix_millis;
+use crate::{Message, State};
+
+/// A channel message as written by `/export`.
+#[derive(Debug, Serialize)]
+struct ExportedMessage<'a> {
+    sender: &'a str,
+    content: &'a str,
+    /// Milliseconds since the Unix epoch.
+    timestamp: u64,
+}
+
+impl State {
+    /// Sends a channel's last `history_size` messages to an admin as a JSON
+    /// array, or writes them to the export directory when one is
+    /// configured.
+    pub(crate) async fn export(&self, addr: SocketAddr, channel: &str) {
+        if !self.require_admin(addr).await {
+            return;
+        }
+        let reply = match self.try_export(channel).await {
+            Ok(reply) => reply,
+            Err(e) => e.to_string(),
+        };
+        self.notify(addr, Message::server(reply)).await;
+    }
+
+    async fn try_export(&self, channel: &str) -> Result<String> {
+        let messages = self.history.recent(channel, self.server.history_size).await;
+        if messages.is_empty() && !self.channels.contains_key(channel) {
+            bail!("No such channel: #{}", channel);
+        }
+        let exported: Vec<_> = messages
+            .iter()
+            .map(|(sent, message)| ExportedMessage {
+                sender: &message.sender,
+                content: &message.content,
+                timestamp: unix_millis(*sent),
+            })
+            .collect();
+        let json = serde_json::to_string(&exported)?;
+
+        let Some(dir) = &self.server.export_dir else {
+            return Ok(json);
+        };
+        let path: PathBuf = dir.join(format!(
+            "{}-{}.json",
+            channel,
+            unix_millis(SystemTime::now())
+        ));
+        if let Err(e) = tokio::fs::write(&path, json).await {
+            bail!("Failed to write {}: {}", path.display(), e);
+        }
+        Ok(format!(
+            "Exported {} messages from #{} to {}",
+            exported.len(),
+            channel,
+            path.display()
+        ))
+    }
+}
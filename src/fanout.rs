@@ -0,0 +1,51 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Caps how many broadcasts fan out at once, so a spike of senders can't
+/// each hold a copy of the recipient list at the same time. Senders over
+/// the cap wait, which stops their connection from reading further lines.
+#[derive(Debug)]
+pub struct FanoutLimit {
+    permits: Semaphore,
+    max: usize,
+    waiting: AtomicUsize,
+}
+
+impl FanoutLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            permits: Semaphore::new(max),
+            max,
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Waits for a turn to broadcast, which lasts until the permit is
+    /// dropped.
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let _waiting = Waiting(&self.waiting);
+        // the semaphore is never closed
+        self.permits.acquire().await.unwrap()
+    }
+
+    /// Senders currently waiting for a turn.
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    /// Broadcasts currently fanning out.
+    pub fn in_flight(&self) -> usize {
+        self.max - self.permits.available_permits()
+    }
+}
+
+/// Counts a sender as no longer waiting, even if it gives up.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
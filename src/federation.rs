@@ -0,0 +1,237 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::broadcast::error::RecvError,
+    task::JoinHandle,
+    time,
+};
+use tokio_util::codec::{Framed, LinesCodec};
+use tracing::{info, warn};
+
+use crate::signing::SignedMessage;
+use crate::{Message, State};
+
+/// Longest line taken from the relay.
+const MAX_FRAME_BYTES: usize = 64 * 1024;
+
+/// How long to wait before reconnecting to the relay, doubling from the
+/// first to the last while it stays unreachable.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Shares channel messages with other nodes through a relay, which passes
+/// every JSON line a node writes on to the others.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FederationConfig {
+    /// `host:port` of the relay.
+    pub relay: String,
+}
+
+/// A channel message as it travels between nodes.
+#[derive(Debug, Deserialize)]
+struct Frame {
+    #[serde(flatten)]
+    message: Message,
+    #[serde(default)]
+    signature: Option<String>,
+}
+
+impl State {
+    /// Hands a local channel message to the relay, if this node is linked
+    /// to one.
+    pub(crate) fn publish(&self, message: &Arc<Message>) {
+        if let Some(published) = &self.published {
+            // nobody is listening while the relay is down
+            let _ = published.send(message.clone());
+        }
+    }
+
+    /// Passes messages both ways over a relay connection until it closes.
+    async fn federate<S>(&self, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let Some(published) = &self.published else {
+            return Ok(());
+        };
+        let mut published = published.subscribe();
+        let mut relay = Framed::new(stream, LinesCodec::new_with_max_length(MAX_FRAME_BYTES));
+        loop {
+            tokio::select! {
+                line = relay.next() => {
+                    let Some(line) = line else {
+                        return Ok(());
+                    };
+                    match serde_json::from_str::<Frame>(&line?) {
+                        Ok(frame) => {
+                            self.relay_federated(frame.message, frame.signature.as_deref())
+                                .await
+                        }
+                        Err(e) => warn!("Dropping bad line from the relay: {}", e),
+                    }
+                }
+                message = published.recv() => match message {
+                    Ok(message) => {
+                        let frame = SignedMessage::new(&message, self.signer.as_ref());
+                        relay.send(serde_json::to_string(&frame)?).await?;
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Relay fell behind; {} messages were not published", missed)
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+            }
+        }
+    }
+}
+
+/// Keeps this node linked to the relay, reconnecting whenever the link
+/// drops. Returns `None` when federation is off.
+pub fn spawn_federation(state: Arc<State>) -> Option<JoinHandle<()>> {
+    let config = state.server.federation.clone()?;
+    Some(tokio::spawn(async move {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match TcpStream::connect(&config.relay).await {
+                Ok(stream) => {
+                    info!("Linked to relay {}", config.relay);
+                    backoff = MIN_BACKOFF;
+                    if let Err(e) = state.federate(stream).await {
+                        warn!("Relay link failed: {}", e);
+                    }
+                    info!("Lost relay {}", config.relay);
+                }
+                Err(e) => warn!("Failed to reach relay {}: {}", config.relay, e),
+            }
+            time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+        net::{
+            tcp::{OwnedReadHalf, OwnedWriteHalf},
+            TcpListener,
+        },
+    };
+
+    use super::*;
+    use crate::channel::DEFAULT_CHANNEL;
+    use crate::config::ServerConfig;
+    use crate::signing::MessageSigner;
+    use crate::test_support::{spawn_state, TestClient};
+
+    /// A node linked to a fake relay, with alice logged in.
+    struct Linked {
+        alice: TestClient,
+        to_node: OwnedWriteHalf,
+        from_node: Lines<BufReader<OwnedReadHalf>>,
+    }
+
+    async fn linked_node() -> Result<Linked> {
+        let relay = TcpListener::bind("127.0.0.1:0").await?;
+        let state = Arc::new(State::new(ServerConfig {
+            hmac_secret: Some("secret".to_string()),
+            federation: Some(FederationConfig {
+                relay: relay.local_addr()?.to_string(),
+            }),
+            ..Default::default()
+        })?);
+        let addr = spawn_state(state.clone()).await?;
+        spawn_federation(state.clone());
+        let (link, _) = relay.accept().await?;
+        let (link, to_node) = link.into_split();
+        Ok(Linked {
+            alice: TestClient::connect(addr, "alice").await?,
+            to_node,
+            from_node: BufReader::new(link).lines(),
+        })
+    }
+
+    async fn send_line(to_node: &mut OwnedWriteHalf, line: &str) -> Result<()> {
+        to_node.write_all(format!("{}\n", line).as_bytes()).await?;
+        Ok(())
+    }
+
+    fn remote(content: &str) -> Message {
+        Message {
+            uuid: Some(uuid::Uuid::new_v4()),
+            ..Message::new("carol", content).in_channel(DEFAULT_CHANNEL)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_pass_both_ways_through_the_relay() -> Result<()> {
+        let Linked {
+            mut alice,
+            mut to_node,
+            mut from_node,
+        } = linked_node().await?;
+
+        // only the remote message signed with the shared secret gets through
+        let signer = MessageSigner::new("secret");
+        for (content, signer) in [
+            ("unsigned", None),
+            ("forged", Some(MessageSigner::new("other"))),
+            ("remote hello", Some(signer.clone())),
+        ] {
+            let message = remote(content);
+            let line = serde_json::to_string(&SignedMessage::new(&message, signer.as_ref()))?;
+            send_line(&mut to_node, &line).await?;
+        }
+        assert_eq!(alice.expect_line().await?, "carol: remote hello");
+        assert_eq!(alice.try_recv().await?, None);
+
+        alice.send_line("local hello").await?;
+        let line = from_node.next_line().await?.unwrap();
+        let frame: Frame = serde_json::from_str(&line)?;
+        assert_eq!(frame.message.content, "local hello");
+        assert!(signer.verify(&frame.message, &frame.signature.unwrap()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rewritten_frames_are_dropped() -> Result<()> {
+        let Linked {
+            mut alice,
+            mut to_node,
+            ..
+        } = linked_node().await?;
+
+        // signed for a channel alice isn't in, then moved into hers under a
+        // fresh id to get past the seen set
+        let signer = MessageSigner::new("secret");
+        let message = remote("for #ops only").in_channel("ops");
+        let mut frame = serde_json::to_value(SignedMessage::new(&message, Some(&signer)))?;
+        frame["channel"] = DEFAULT_CHANNEL.into();
+        frame["uuid"] = uuid::Uuid::new_v4().to_string().into();
+        send_line(&mut to_node, &frame.to_string()).await?;
+
+        let message = remote("remote hello");
+        let line = serde_json::to_string(&SignedMessage::new(&message, Some(&signer)))?;
+        send_line(&mut to_node, &line).await?;
+        assert_eq!(alice.expect_line().await?, "carol: remote hello");
+        assert_eq!(alice.try_recv().await?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_federation_needs_a_secret() {
+        let config = ServerConfig {
+            federation: Some(FederationConfig {
+                relay: "127.0.0.1:1".to_string(),
+            }),
+            ..Default::default()
+        };
+        assert!(State::new(config).is_err());
+    }
+}
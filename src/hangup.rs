@@ -0,0 +1,76 @@
+use std::{
+    fmt,
+    sync::{Arc, OnceLock},
+};
+
+use tokio_util::sync::CancellationToken;
+
+/// Why a peer's session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Departure {
+    /// The peer closed the connection, or reading from it failed.
+    ReadClosed,
+    /// Writing to the peer failed or timed out.
+    WriteFailed,
+    /// The server disconnected the peer.
+    Disconnected,
+    /// The session hit `max_session_secs`.
+    SessionExpired,
+    /// The peer was quiet for `idle_timeout_secs`.
+    IdleTimeout,
+    /// The connection saw no reads or writes for `io_timeout_secs`.
+    Stalled,
+}
+
+impl fmt::Display for Departure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Departure::ReadClosed => "connection closed",
+            Departure::WriteFailed => "write failed",
+            Departure::Disconnected => "disconnected by the server",
+            Departure::SessionExpired => "session expired",
+            Departure::IdleTimeout => "idle timeout",
+            Departure::Stalled => "connection stalled",
+        })
+    }
+}
+
+/// Shared by a peer's read loop, writer task and handle. Whichever notices
+/// first that the session is over hangs up, which stops the other side and
+/// records why; later hang-ups don't change the reason.
+#[derive(Debug, Clone, Default)]
+pub struct Hangup {
+    token: CancellationToken,
+    reason: Arc<OnceLock<Departure>>,
+}
+
+impl Hangup {
+    pub fn hang_up(&self, reason: Departure) {
+        let _ = self.reason.set(reason);
+        self.token.cancel();
+    }
+
+    /// Resolves once someone has hung up.
+    pub async fn wait(&self) {
+        self.token.cancelled().await
+    }
+
+    pub fn reason(&self) -> Option<Departure> {
+        self.reason.get().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_first_reason_wins() {
+        let hangup = Hangup::default();
+        assert_eq!(hangup.reason(), None);
+        hangup.clone().hang_up(Departure::WriteFailed);
+        hangup.hang_up(Departure::ReadClosed);
+        hangup.wait().await;
+        assert_eq!(hangup.reason(), Some(Departure::WriteFailed));
+    }
+}
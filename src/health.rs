@@ -0,0 +1,199 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+use tracing::{info, warn};
+
+use crate::State;
+
+/// How long a probe has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Requests are only read this far; probes send a few short lines.
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+/// An HTTP listener for orchestration probes: `GET /live` answers as long
+/// as the server runs, `GET /ready` only while it should get new peers.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HealthConfig {
+    /// Address the probes connect to, e.g. `127.0.0.1:9100`.
+    pub listen: SocketAddr,
+    /// Report not ready once the server's resident memory is over this
+    /// many bytes; 0 never does.
+    #[serde(default)]
+    pub max_rss_bytes: u64,
+}
+
+impl State {
+    /// Why the server shouldn't get new peers right now, if it shouldn't.
+    fn unready_reason(&self, config: &HealthConfig) -> Option<String> {
+        if self.is_draining() {
+            return Some("draining".to_string());
+        }
+        match resident_bytes() {
+            Some(rss) if config.max_rss_bytes > 0 && rss > config.max_rss_bytes => Some(format!(
+                "using {} bytes of memory, over {}",
+                rss, config.max_rss_bytes
+            )),
+            _ => None,
+        }
+    }
+
+    /// Answers one probe, with a status line and the basic numbers.
+    fn probe(&self, config: &HealthConfig, path: &str) -> (&'static str, String) {
+        let (status, verdict) = match path {
+            "/live" => ("200 OK", "OK".to_string()),
+            "/ready" => match self.unready_reason(config) {
+                None => ("200 OK", "READY".to_string()),
+                Some(reason) => ("503 Service Unavailable", format!("NOT READY: {}", reason)),
+            },
+            _ => return ("404 Not Found", "Try /live or /ready.\n".to_string()),
+        };
+        let body = format!(
+            "{}\nuptime_secs: {}\npeers: {}\ndraining: {}\n",
+            verdict,
+            self.started_at.elapsed().as_secs(),
+            self.peers.len(),
+            self.is_draining()
+        );
+        (status, body)
+    }
+}
+
+/// The server's resident set size, where the platform tells.
+fn resident_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: sysconf has no preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page_size).ok()?)
+}
+
+/// Answers probes until the listener fails.
+pub async fn serve_health(state: Arc<State>, config: HealthConfig) -> Result<()> {
+    let listener = TcpListener::bind(config.listen).await?;
+    info!("Health checks on {}", listener.local_addr()?);
+    serve_probes(state, config, listener).await
+}
+
+async fn serve_probes(
+    state: Arc<State>,
+    config: HealthConfig,
+    listener: TcpListener,
+) -> Result<()> {
+    let config = Arc::new(config);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let state = state.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer(&state, &config, stream).await {
+                warn!("Failed to answer health probe: {}", e);
+            }
+        });
+    }
+}
+
+async fn answer(state: &State, config: &HealthConfig, mut stream: TcpStream) -> Result<()> {
+    let mut request = Vec::new();
+    let mut chunk = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        let n = time::timeout(REQUEST_TIMEOUT, stream.read(&mut chunk)).await??;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&chunk[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(path)) => state.probe(config, path),
+        _ => ("400 Bad Request", "Expected a GET request.\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+
+    fn health_config(max_rss_bytes: u64) -> HealthConfig {
+        HealthConfig {
+            listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+            max_rss_bytes,
+        }
+    }
+
+    async fn spawn_probes(state: Arc<State>, config: HealthConfig) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(config.listen).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(serve_probes(state, config, listener));
+        Ok(addr)
+    }
+
+    async fn get(addr: SocketAddr, path: &str) -> Result<String> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        time::timeout(REQUEST_TIMEOUT, stream.read_to_string(&mut response)).await??;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn test_ready_until_draining() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig::default())?);
+        let addr = spawn_probes(state.clone(), health_config(0)).await?;
+
+        let ready = get(addr, "/ready").await?;
+        assert!(ready.starts_with("HTTP/1.1 200 OK\r\n"), "{}", ready);
+        assert!(
+            ready.ends_with("\r\n\r\nREADY\nuptime_secs: 0\npeers: 0\ndraining: false\n"),
+            "{}",
+            ready
+        );
+
+        state.start_drain().await;
+        let draining = get(addr, "/ready").await?;
+        assert!(
+            draining.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{}",
+            draining
+        );
+        assert!(draining.contains("NOT READY: draining\n"), "{}", draining);
+        assert!(draining.contains("draining: true\n"), "{}", draining);
+
+        // a draining server is still alive
+        let live = get(addr, "/live").await?;
+        assert!(live.starts_with("HTTP/1.1 200 OK\r\n"), "{}", live);
+        assert!(get(addr, "/nope")
+            .await?
+            .starts_with("HTTP/1.1 404 Not Found\r\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_over_memory_threshold() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig::default())?);
+        let addr = spawn_probes(state, health_config(1)).await?;
+        let response = get(addr, "/ready").await?;
+        if resident_bytes().is_some() {
+            assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+            assert!(response.contains("NOT READY: using "), "{}", response);
+        }
+        Ok(())
+    }
+}
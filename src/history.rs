@@ -0,0 +1,241 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+
+use crate::Message;
+
+/// Decides which messages a search may return.
+pub type Visible<'a> = &'a (dyn Fn(&Message) -> bool + Send + Sync);
+
+/// Where chat history is kept, backing `/search`, `/export` and
+/// `/reply-to`.
+#[async_trait]
+pub trait HistoryStore: fmt::Debug + Send + Sync {
+    /// Records a chat message sent just now.
+    async fn append(&self, message: &Arc<Message>);
+
+    /// Returns up to `limit` of the most recent messages in a channel with
+    /// the time they were sent, oldest first.
+    async fn recent(&self, channel: &str, limit: usize) -> Vec<(SystemTime, Arc<Message>)>;
+
+    /// Returns up to `limit` of the most recent messages whose content
+    /// contains `query`, ignoring case, in the order they were sent. Only
+    /// messages for which `visible` returns true are considered.
+    async fn search(&self, query: &str, limit: usize, visible: Visible<'_>) -> Vec<Arc<Message>>;
+
+    /// Returns the most recent message with the given id.
+    async fn find(&self, id: u64) -> Option<Arc<Message>>;
+}
+
+/// Keeps the most recent of `records`, oldest first, that match `query`.
+pub fn search_records<'a>(
+    records: impl DoubleEndedIterator<Item = &'a Arc<Message>>,
+    query: &str,
+    limit: usize,
+    visible: Visible<'_>,
+) -> Vec<Arc<Message>> {
+    let query = query.to_lowercase();
+    let mut found: Vec<_> = records
+        .rev()
+        .filter(|message| visible(message))
+        .filter(|message| message.content.to_lowercase().contains(&query))
+        .take(limit)
+        .cloned()
+        .collect();
+    found.reverse();
+    found
+}
+
+/// The last of `records` with the given id.
+pub fn find_record<'a>(
+    records: impl DoubleEndedIterator<Item = &'a Arc<Message>>,
+    id: u64,
+) -> Option<Arc<Message>> {
+    records
+        .rev()
+        .find(|message| message.id == Some(id))
+        .cloned()
+}
+
+/// Keeps the last `limit` of a channel's messages, oldest first.
+pub fn recent_records<'a>(
+    records: impl DoubleEndedIterator<Item = &'a (SystemTime, Arc<Message>)>,
+    channel: &str,
+    limit: usize,
+) -> Vec<(SystemTime, Arc<Message>)> {
+    let mut found: Vec<_> = records
+        .rev()
+        .filter(|(_, message)| message.channel.as_deref() == Some(channel))
+        .take(limit)
+        .cloned()
+        .collect();
+    found.reverse();
+    found
+}
+
+/// Recent chat messages kept in memory with the time they were sent, oldest
+/// first.
+#[derive(Debug)]
+pub struct History {
+    messages: Mutex<VecDeque<(SystemTime, Arc<Message>)>>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            messages: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// Adds a message sent at `sent`, dropping the oldest past capacity.
+    pub fn push(&self, sent: SystemTime, message: Arc<Message>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() == self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back((sent, message));
+    }
+
+    /// Every message kept, oldest first.
+    pub fn records(&self) -> Vec<(SystemTime, Arc<Message>)> {
+        self.messages.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[async_trait]
+impl HistoryStore for History {
+    async fn append(&self, message: &Arc<Message>) {
+        self.push(SystemTime::now(), message.clone());
+    }
+
+    async fn recent(&self, channel: &str, limit: usize) -> Vec<(SystemTime, Arc<Message>)> {
+        recent_records(self.messages.lock().unwrap().iter(), channel, limit)
+    }
+
+    async fn search(&self, query: &str, limit: usize, visible: Visible<'_>) -> Vec<Arc<Message>> {
+        let messages = self.messages.lock().unwrap();
+        search_records(
+            messages.iter().map(|(_, message)| message),
+            query,
+            limit,
+            visible,
+        )
+    }
+
+    async fn find(&self, id: u64) -> Option<Arc<Message>> {
+        let messages = self.messages.lock().unwrap();
+        find_record(messages.iter().map(|(_, message)| message), id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::FileHistory;
+
+    /// Runs a check against every store that works without extra features.
+    async fn for_each_store<F, Fut>(check: F)
+    where
+        F: Fn(Arc<dyn HistoryStore>) -> Fut,
+        Fut: std::future::Future<Output = ()>,
+    {
+        check(Arc::new(History::new(10))).await;
+        let dir = tempfile::tempdir().unwrap();
+        let file = FileHistory::open(dir.path().join("history.jsonl"), 10, None).unwrap();
+        check(Arc::new(file)).await;
+        #[cfg(feature = "sqlite")]
+        {
+            let path = dir.path().join("history.db");
+            check(Arc::new(
+                crate::store::sqlite::SqliteHistory::open(&path, 10).unwrap(),
+            ))
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_history_is_bounded() {
+        let history = History::new(2);
+        for content in ["one", "two", "three"] {
+            history
+                .append(&Arc::new(Message::new("alice", content)))
+                .await;
+        }
+        let found = history.search("", 10, &|_| true).await;
+        let contents: Vec<_> = found.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["two", "three"]);
+    }
+
+    #[tokio::test]
+    async fn test_search_is_case_insensitive_and_limited() {
+        for_each_store(|history| async move {
+            for content in ["Rust is great", "go is fine", "I love rust", "RUST!"] {
+                history
+                    .append(&Arc::new(Message::new("alice", content)))
+                    .await;
+            }
+            let found = history.search("rust", 2, &|_| true).await;
+            let contents: Vec<_> = found.iter().map(|m| m.content.as_str()).collect();
+            assert_eq!(contents, ["I love rust", "RUST!"], "{:?}", history);
+
+            let found = history.search("rust", 10, &|m| m.content != "RUST!").await;
+            assert_eq!(found.len(), 2, "{:?}", history);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_recent_in_channel() {
+        for_each_store(|history| async move {
+            for (sender, content, channel) in [
+                ("alice", "one", "rust"),
+                ("bob", "two", "go"),
+                ("carol", "three", "rust"),
+                ("dave", "four", "rust"),
+            ] {
+                let message = Message::new(sender, content).in_channel(channel);
+                history.append(&Arc::new(message)).await;
+            }
+
+            let found = history.recent("rust", 10).await;
+            let contents: Vec<_> = found.iter().map(|(_, m)| m.content.as_str()).collect();
+            assert_eq!(contents, ["one", "three", "four"], "{:?}", history);
+            assert!(found[0].0 <= found[1].0);
+            assert_eq!(found[0].1.sender, "alice");
+
+            let found = history.recent("rust", 2).await;
+            let contents: Vec<_> = found.iter().map(|(_, m)| m.content.as_str()).collect();
+            assert_eq!(contents, ["three", "four"], "{:?}", history);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_find_by_id() {
+        for_each_store(|history| async move {
+            for (id, content) in [(1, "old"), (2, "two"), (1, "new")] {
+                let message = Message {
+                    id: Some(id),
+                    ..Message::new("alice", content).in_channel("rust")
+                };
+                history.append(&Arc::new(message)).await;
+            }
+            // ids start over when the server restarts, so the newest wins
+            let found = history.find(1).await.unwrap();
+            assert_eq!(found.content, "new", "{:?}", history);
+            assert_eq!(found.channel.as_deref(), Some("rust"));
+            assert!(history.find(3).await.is_none(), "{:?}", history);
+        })
+        .await;
+    }
+}
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use tokio::time::{self, Instant};
+
+/// What an idle peer has coming.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleEvent {
+    /// Warn the peer they will be disconnected after this long.
+    Nudge(Duration),
+    /// Disconnect the peer.
+    Timeout,
+}
+
+/// Times a peer's inactivity: first a nudge, then the disconnect. Any
+/// activity starts both over.
+#[derive(Debug)]
+pub struct IdleTimer {
+    timeout: Option<Duration>,
+    /// How long before the timeout the nudge goes out.
+    nudge: Option<Duration>,
+    deadline: Instant,
+    nudged: bool,
+}
+
+impl IdleTimer {
+    /// A `timeout_secs` of 0 never times out; a `nudge_secs` of 0 or at
+    /// least the timeout sends no nudge.
+    pub fn new(timeout_secs: u64, nudge_secs: u64) -> Self {
+        let timeout = (timeout_secs > 0).then(|| Duration::from_secs(timeout_secs));
+        let nudge =
+            (nudge_secs > 0 && nudge_secs < timeout_secs).then(|| Duration::from_secs(nudge_secs));
+        Self {
+            timeout,
+            nudge,
+            deadline: Instant::now() + timeout.unwrap_or_default(),
+            nudged: false,
+        }
+    }
+
+    /// Records activity from the peer.
+    pub fn reset(&mut self) {
+        if let Some(timeout) = self.timeout {
+            self.deadline = Instant::now() + timeout;
+            self.nudged = false;
+        }
+    }
+
+    /// Waits for the next stage; never resolves without a timeout. Safe to
+    /// cancel and call again.
+    pub async fn next(&mut self) -> IdleEvent {
+        if self.timeout.is_none() {
+            return futures::future::pending().await;
+        }
+        match self.nudge {
+            Some(nudge) if !self.nudged => {
+                time::sleep_until(self.deadline - nudge).await;
+                self.nudged = true;
+                IdleEvent::Nudge(nudge)
+            }
+            _ => {
+                time::sleep_until(self.deadline).await;
+                IdleEvent::Timeout
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_nudge_then_timeout() {
+        let start = Instant::now();
+        let mut idle = IdleTimer::new(60, 10);
+        assert_eq!(idle.next().await, IdleEvent::Nudge(Duration::from_secs(10)));
+        assert_eq!(start.elapsed(), Duration::from_secs(50));
+
+        // activity starts the nudge over
+        idle.reset();
+        assert_eq!(idle.next().await, IdleEvent::Nudge(Duration::from_secs(10)));
+        assert_eq!(start.elapsed(), Duration::from_secs(100));
+        assert_eq!(idle.next().await, IdleEvent::Timeout);
+        assert_eq!(start.elapsed(), Duration::from_secs(110));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_without_nudge() {
+        let start = Instant::now();
+        let mut idle = IdleTimer::new(5, 5);
+        assert_eq!(idle.next().await, IdleEvent::Timeout);
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::BTreeMap;
+
+/// Expands a leading `!name` token into the text of the macro with that
+/// name, keeping whatever follows it. Lines that don't start with a defined
+/// macro are left as they are.
+pub fn expand(line: &str, macros: &BTreeMap<String, String>) -> String {
+    let Some(rest) = line.strip_prefix('!') else {
+        return line.to_string();
+    };
+    let (name, tail) = match rest.split_once(' ') {
+        Some((name, tail)) => (name, Some(tail)),
+        None => (rest, None),
+    };
+    match (macros.get(name), tail) {
+        (Some(text), Some(tail)) => format!("{} {}", text, tail),
+        (Some(text), None) => text.clone(),
+        (None, _) => line.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::test_support::{spawn_server, TestClient};
+
+    fn macros() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("rules".to_string(), "Be kind. No spam.".to_string()),
+            ("hours".to_string(), "We're here 9-5 UTC.".to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_expand_defined_macro() {
+        assert_eq!(expand("!rules", &macros()), "Be kind. No spam.");
+        assert_eq!(
+            expand("!hours see you then", &macros()),
+            "We're here 9-5 UTC. see you then"
+        );
+    }
+
+    #[test]
+    fn test_unknown_macro_is_literal() {
+        assert_eq!(expand("!nope", &macros()), "!nope");
+        assert_eq!(expand("!rulesx", &macros()), "!rulesx");
+        assert_eq!(expand("the !rules apply", &macros()), "the !rules apply");
+        assert_eq!(expand("!", &macros()), "!");
+    }
+
+    #[tokio::test]
+    async fn test_macro_expands_before_broadcast() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            macros: macros(),
+            ..Default::default()
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        bob.send_line("!rules").await?;
+        assert_eq!(alice.expect_line().await?, "bob: Be kind. No spam.");
+        bob.send_line("!refund please").await?;
+        assert_eq!(alice.expect_line().await?, "bob: !refund please");
+        Ok(())
+    }
+}
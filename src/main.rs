@@ -1,183 +1,4466 @@
-use std::fs::File;
-use std::{env, fmt, net::SocketAddr, sync::Arc};
+mod accepts;
+mod admin;
+mod attachment;
+mod challenge;
+mod channel;
+mod client;
+mod codec;
+mod command;
+mod config;
+mod dedup;
+mod dice;
+mod drain;
+mod emoji;
+mod export;
+mod fanout;
+mod federation;
+mod hangup;
+mod health;
+mod history;
+mod idle;
+mod macros;
+#[cfg(any(test, feature = "mock-transport"))]
+#[cfg_attr(not(test), allow(dead_code))]
+mod mock;
+mod names;
+mod onboarding;
+mod operator;
+mod outbox;
+mod persistence;
+mod presence;
+mod queue;
+mod raid;
+mod ratelimit;
+mod reaction;
+mod rejection;
+mod reload;
+mod render;
+mod reply;
+mod resume;
+mod runtime;
+mod signing;
+mod store;
+mod telemetry;
+#[cfg(test)]
+mod test_support;
+mod tls;
+mod unix;
+mod watchdog;
+mod webhook;
+mod wrap;
 
-use anyhow::{bail, Result};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
+    fmt, io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
 use dashmap::DashMap;
 use futures::{
-    stream::{SplitStream, StreamExt},
-    SinkExt,
+    stream::{FuturesUnordered, SplitStream, StreamExt},
+    Sink, SinkExt,
 };
 use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::{TcpListener, TcpStream},
-    sync::mpsc,
+    sync::{
+        broadcast,
+        mpsc::{self, error::TrySendError},
+        SemaphorePermit,
+    },
+    task::JoinHandle,
+    time,
+};
+use tokio_util::{
+    codec::{Framed, LinesCodecError},
+    sync::CancellationToken,
 };
-use tokio_util::codec::{Framed, LinesCodec};
 use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::accepts::AcceptLog;
+use crate::attachment::Attachment;
+use crate::challenge::RecentConnects;
+use crate::channel::{channel_name, Channel};
+use crate::client::ClientInfo;
+use crate::codec::{ChatCodec, Traffic};
+use crate::command::Command;
+use crate::config::{BinaryCheck, FlushPolicy, ServerConfig, Settings};
+use crate::dedup::SeenSet;
+use crate::fanout::FanoutLimit;
+use crate::hangup::{Departure, Hangup};
+use crate::history::HistoryStore;
+use crate::idle::{IdleEvent, IdleTimer};
+use crate::names::{NameClaim, Names};
+use crate::outbox::{Inbox, Outbox, Priority, Slot};
+use crate::presence::PendingLeaves;
+use crate::queue::{Admission, ConnectionQueue};
+use crate::raid::RaidMode;
+use crate::ratelimit::{ByteRate, TokenBucket};
+use crate::reaction::{ClientFrame, Reactions};
+use crate::rejection::Rejection;
+use crate::render::{DefaultRenderer, LineFormat, MessageRenderer};
+use crate::resume::Sessions;
+use crate::signing::MessageSigner;
+use crate::tls::TlsListener;
+use crate::unix::UnixSocketListener;
+use crate::webhook::{Event, Webhook};
 
-#[derive(Debug, Clone)]
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+/// How long a broadcast waits on a peer whose queue is full.
+const DELIVERY_TIMEOUT: Duration = Duration::from_millis(250);
+/// Recipients a broadcast queues for before letting other tasks run.
+const DELIVERY_BATCH: usize = 128;
+/// How long a writer keeps trying to get a write out once the session is
+/// over, so a stalled connection still gets closed.
+const HANGUP_WRITE_GRACE: Duration = Duration::from_secs(1);
+const SEARCH_DEFAULT_RESULTS: usize = 10;
+const SEARCH_MAX_RESULTS: usize = 50;
+/// Number of message ids remembered to drop federation echoes.
+const SEEN_MESSAGES: usize = 10_000;
+/// Local channel messages queued for the relay before the oldest are missed.
+const PUBLISH_BUFFER: usize = 1024;
+/// Who server notices are from; no peer can go by it.
+const SERVER_NAME: &str = "Server";
+
+#[derive(Debug)]
 struct State {
     server: ServerConfig,
-    peers: DashMap<SocketAddr, mpsc::Sender<Arc<Message>>>,
+    /// Settings that a config reload can change.
+    settings: RwLock<Arc<Settings>>,
+    signer: Option<MessageSigner>,
+    /// Formats messages for text protocol peers.
+    renderer: Arc<dyn MessageRenderer>,
+    peers: DashMap<SocketAddr, PeerHandle>,
+    channels: DashMap<String, Channel>,
+    /// Held while a channel is created, so that concurrent joins can't go
+    /// past `max_channels`.
+    creating_channel: Mutex<()>,
+    /// Enforces `max_connections` when it is set.
+    connections: Option<ConnectionQueue>,
+    /// Enforces `max_concurrent_broadcasts` when it is set.
+    fanout: Option<FanoutLimit>,
+    message_log: Option<mpsc::Sender<Arc<Message>>>,
+    history: Arc<dyn HistoryStore>,
+    /// Held while a message is queued for its recipients, and while a
+    /// channel message is numbered, so that every peer sees messages in
+    /// one order and channel messages in id order.
+    sequencer: tokio::sync::Mutex<()>,
+    /// Id given to the next channel message.
+    next_message_id: AtomicU64,
+    /// Messages dropped for slow peers since the server started.
+    dropped_messages: AtomicU64,
+    reactions: Reactions,
+    /// Ids of channel messages already delivered locally.
+    seen: SeenSet,
+    /// Local channel messages for the relay, when federation is on.
+    published: Option<broadcast::Sender<Arc<Message>>>,
+    webhook: Option<Webhook>,
+    /// Cancelled once the server starts draining.
+    drain: CancellationToken,
+    /// Set by whichever caller gets to start the drain.
+    drain_started: AtomicBool,
+    /// Logins and nicks in use, and those parked sessions hold on to.
+    names: Names,
+    /// Sessions of disconnected peers that can still be resumed.
+    sessions: Sessions,
+    accepts: AcceptLog,
+    pending_leaves: PendingLeaves,
+    /// Connection arrivals, for the challenge's raid mode.
+    recent_connects: RecentConnects,
+    raid: RaidMode,
+    started_at: time::Instant,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct ServerConfig {
-    pub host: String,
-    pub port: u16,
+#[derive(Debug)]
+struct PeerHandle {
+    /// Login name, stable for the whole session; used to address the peer.
+    username: String,
+    /// Name shown on the peer's messages, changed with `/nick`.
+    nick: String,
+    /// Keeps a nick other than the username from being taken by anyone else.
+    nick_claim: Option<NameClaim>,
+    sender: Outbox,
+    dnd: bool,
+    channels: HashSet<String>,
+    /// The channel chat messages from this peer go to.
+    current: Option<String>,
+    admin: bool,
+    /// Ends the session, from whichever side notices it is over first.
+    hangup: Hangup,
+    /// When the peer logged in.
+    connected_at: time::Instant,
+    /// When the peer last sent a line.
+    last_active: time::Instant,
+    /// Bytes exchanged with the peer since it connected.
+    traffic: Arc<Traffic>,
+    /// Whether the peer's writer shows when chat messages were sent.
+    timestamps: Arc<AtomicBool>,
+    /// Columns the peer's writer wraps text lines to; 0 doesn't wrap.
+    wrap_width: Arc<AtomicUsize>,
+    /// Whether the peer turned on compression.
+    compressed: bool,
+    /// What the client said about itself during the handshake.
+    client: ClientInfo,
 }
 
 impl State {
-    async fn new_tcp_listener(&self) -> Result<TcpListener> {
-        let addr = format!("{}:{}", self.server.host, self.server.port);
-        Ok(TcpListener::bind(addr).await?)
-    }
+    fn new(mut server: ServerConfig) -> Result<Self> {
+        server.default_channel = channel_name(&server.default_channel)?;
+        server.auto_join = server
+            .auto_join
+            .iter()
+            .map(|channel| channel_name(channel))
+            .collect::<Result<_>>()?;
+        let channels = DashMap::new();
+        for channel in std::iter::once(&server.default_channel).chain(&server.auto_join) {
+            channels.insert(channel.clone(), Channel::default());
+        }
 
-    async fn try_load() -> Result<Self> {
-        let config = match (
-            File::open("config.yaml"),
-            File::open("/etc/config.yaml"),
-            env::var("APP_CONFIG_PATH"),
-        ) {
-            (Ok(reader), _, _) => serde_yaml::from_reader(reader),
-            (_, Ok(reader), _) => serde_yaml::from_reader(reader),
-            (_, _, Ok(path)) => serde_yaml::from_reader(File::open(path)?),
-            _ => bail!(anyhow::anyhow!("Config file not found")),
-        };
+        let signer = server.hmac_secret.as_ref().map(MessageSigner::new);
+        if server.federation.is_some() && signer.is_none() {
+            return Err(anyhow!("federation needs hmac_secret to sign messages"));
+        }
+        let message_log = server
+            .persistence
+            .clone()
+            .map(persistence::spawn)
+            .transpose()?;
+        let webhook = server.webhook_url.as_ref().map(Webhook::new).transpose()?;
+        let history_cipher = server
+            .persistence
+            .as_ref()
+            .map(persistence::PersistenceConfig::cipher)
+            .transpose()?
+            .flatten();
 
         Ok(State {
-            server: config?,
+            settings: RwLock::new(Arc::new(Settings::from(&server))),
+            signer,
+            renderer: Arc::new(DefaultRenderer),
             peers: DashMap::new(),
+            channels,
+            creating_channel: Mutex::new(()),
+            connections: (server.max_connections > 0)
+                .then(|| ConnectionQueue::new(server.max_connections, server.max_queue)),
+            fanout: (server.max_concurrent_broadcasts > 0)
+                .then(|| FanoutLimit::new(server.max_concurrent_broadcasts)),
+            message_log,
+            history: store::open(&server.history_store, server.history_size, history_cipher)?,
+            sequencer: tokio::sync::Mutex::new(()),
+            next_message_id: AtomicU64::new(1),
+            dropped_messages: AtomicU64::new(0),
+            reactions: Reactions::new(server.history_size),
+            seen: SeenSet::new(SEEN_MESSAGES),
+            published: server
+                .federation
+                .as_ref()
+                .map(|_| broadcast::channel(PUBLISH_BUFFER).0),
+            webhook,
+            drain: CancellationToken::new(),
+            drain_started: AtomicBool::new(false),
+            names: Names::default(),
+            sessions: Sessions::default(),
+            accepts: AcceptLog::default(),
+            pending_leaves: PendingLeaves::default(),
+            recent_connects: RecentConnects::default(),
+            raid: RaidMode::new(server.raid.as_ref()),
+            started_at: time::Instant::now(),
+            server,
         })
     }
 
+    /// Reports an event to the webhook, if one is configured.
+    fn post_event(&self, event: Event) {
+        if let Some(webhook) = &self.webhook {
+            webhook.notify(event);
+        }
+    }
+
+    /// Replaces how messages are rendered for text protocol peers.
+    #[cfg(test)]
+    fn with_renderer(self, renderer: Arc<dyn MessageRenderer>) -> Self {
+        Self { renderer, ..self }
+    }
+
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    async fn new_tcp_listener(&self) -> Result<TcpListener> {
+        let addr = tokio::net::lookup_host((self.server.host.as_str(), self.server.port))
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("Failed to resolve {}", self.server.host))?;
+
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(true)?;
+        if self.server.reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(self.server.backlog as i32)?;
+
+        Ok(TcpListener::from_std(socket.into())?)
+    }
+
     async fn broadcast(&self, addr: SocketAddr, message: Arc<Message>) {
-        for peer in self.peers.iter() {
-            if peer.key() == &addr {
+        self.deliver(self.recipients(Some(addr)), message).await;
+    }
+
+    async fn broadcast_all(&self, message: Arc<Message>) {
+        self.deliver(self.recipients(None), message).await;
+    }
+
+    /// Snapshots every peer's queue, leaving out `except`, so no map lock is
+    /// held across an await.
+    fn recipients(&self, except: Option<SocketAddr>) -> Vec<(SocketAddr, Outbox)> {
+        self.peers
+            .iter()
+            .filter(|peer| Some(*peer.key()) != except)
+            .map(|peer| (*peer.key(), peer.sender.clone()))
+            .collect()
+    }
+
+    /// Queues the message for each recipient, in the same place in every
+    /// queue relative to other messages. Peers whose queue is closed are
+    /// removed.
+    async fn deliver(&self, recipients: Vec<(SocketAddr, Outbox)>, message: Arc<Message>) {
+        let reserved = self.reserve(recipients, &message).await;
+        let dead = {
+            let _turn = self.sequencer.lock().await;
+            reserved.send(&message)
+        };
+        self.bury(dead).await;
+    }
+
+    /// Takes room for the message in each recipient's queue. Full queues
+    /// get one short grace period between them before the message is
+    /// dropped for those peers, so dying peers can't stall everyone else. Each sender only
+    /// gets a share of a queue, so only the sender that used up theirs has
+    /// to wait. Broadcasts to more than one peer wait their turn when
+    /// `max_concurrent_broadcasts` is reached. Peers in quiet mode only get
+    /// server notices.
+    async fn reserve(
+        &self,
+        recipients: Vec<(SocketAddr, Outbox)>,
+        message: &Message,
+    ) -> Reserved<'_> {
+        let fanout = match &self.fanout {
+            Some(fanout) if recipients.len() > 1 => Some(fanout.acquire().await),
+            _ => None,
+        };
+        let mut reserved = Reserved {
+            slots: Vec::with_capacity(recipients.len()),
+            dead: Vec::new(),
+            _fanout: fanout,
+        };
+
+        let mut full = Vec::new();
+        for (i, (addr, outbox)) in recipients.into_iter().enumerate() {
+            if i > 0 && i % DELIVERY_BATCH == 0 {
+                tokio::task::yield_now().await;
+            }
+            if !outbox.wants(message) {
                 continue;
             }
+            match outbox.try_reserve(message) {
+                Ok(slot) => reserved.slots.push((addr, slot)),
+                Err(TrySendError::Closed(())) => reserved.dead.push(addr),
+                Err(TrySendError::Full(())) => full.push((addr, outbox)),
+            }
+        }
 
-            if peer.value().send(message.clone()).await.is_err() {
-                info!("Failed to send message to peer: {:?}", peer.key());
-                // remove the peer
-                self.peers.remove(peer.key());
+        let deadline = time::Instant::now() + DELIVERY_TIMEOUT;
+        let mut waiting: FuturesUnordered<_> = full
+            .into_iter()
+            .map(|(addr, outbox)| async move {
+                let reserved = time::timeout_at(deadline, outbox.reserve(message)).await;
+                (addr, outbox, reserved)
+            })
+            .collect();
+        while let Some((addr, outbox, result)) = waiting.next().await {
+            match result {
+                Ok(Ok(slot)) => reserved.slots.push((addr, slot)),
+                Ok(Err(_)) => reserved.dead.push(addr),
+                Err(_) => self.record_drop(addr, &outbox),
             }
         }
+        reserved
+    }
+
+    /// Counts a message dropped because the peer's queue stayed full.
+    fn record_drop(&self, addr: SocketAddr, outbox: &Outbox) {
+        let dropped = outbox.record_drop();
+        self.dropped_messages.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "Dropped message for slow peer: {:?} ({} dropped so far)",
+            addr, dropped
+        );
+        if let Some(peer) = self.peers.get(&addr) {
+            let username = peer.username.clone();
+            drop(peer);
+            self.post_event(Event::Dropped { username, dropped });
+        }
+    }
+
+    /// Removes peers found dead while delivering.
+    async fn bury(&self, dead: Vec<SocketAddr>) {
+        for addr in dead {
+            info!("Failed to send message to peer: {:?}", addr);
+            // announcing the departure delivers again, so the recursion
+            // needs boxing
+            Box::pin(self.depart(addr)).await;
+        }
     }
 
-    async fn add_peer(
+    async fn add_peer<S>(
         &self,
         addr: SocketAddr,
-        username: String,
-        stream: Framed<TcpStream, LinesCodec>,
-    ) -> Peer {
-        let (tx, mut rx) = mpsc::channel(16);
+        name: NameClaim,
+        stream: Framed<S, ChatCodec>,
+        unsent: Vec<Arc<Message>>,
+        client: ClientInfo,
+    ) -> Peer<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (tx, mut rx) = outbox::channel(16);
+        // queued before anyone else can see the peer, so it goes out first
+        rx.replay(unsent);
+        let hangup = Hangup::default();
+        let traffic = stream.codec().traffic();
+        let deflate = stream.codec().deflate_switch();
+        let timestamps = Arc::new(AtomicBool::new(self.server.show_timestamps));
+        let wrap_width = if self.server.wrap_lines {
+            self.wrap_width_for(&client)
+        } else {
+            0
+        };
+        let wrap_width = Arc::new(AtomicUsize::new(wrap_width));
+        let username = name.name().to_string();
 
-        self.peers.insert(addr, tx);
+        self.peers.insert(
+            addr,
+            PeerHandle {
+                username: username.clone(),
+                nick: username.clone(),
+                nick_claim: None,
+                sender: tx,
+                dnd: false,
+                channels: HashSet::new(),
+                current: None,
+                admin: false,
+                hangup: hangup.clone(),
+                connected_at: time::Instant::now(),
+                last_active: time::Instant::now(),
+                traffic: traffic.clone(),
+                timestamps: timestamps.clone(),
+                wrap_width: wrap_width.clone(),
+                compressed: false,
+                client,
+            },
+        );
+        self.auto_join(addr);
 
-        let (mut sender, receiver) = stream.split();
+        let (sender, receiver) = stream.split();
+        let format = LineFormat {
+            protocol: self.server.protocol,
+            signer: self.signer.clone(),
+            renderer: self.renderer.clone(),
+            timestamps,
+            wrap_width,
+            deflate,
+        };
+        let options = WriterOptions {
+            flush_policy: self.server.flush_policy,
+            send_timeout: (self.server.send_timeout_secs > 0)
+                .then(|| Duration::from_secs(self.server.send_timeout_secs)),
+            max_bytes_per_sec: (self.server.max_bytes_per_sec > 0)
+                .then_some(self.server.max_bytes_per_sec),
+            report_dropped: self.server.report_dropped_messages,
+        };
+        let writer = tokio::spawn(write_messages(
+            addr,
+            rx,
+            sender,
+            format,
+            options,
+            traffic,
+            hangup.clone(),
+        ));
 
-        tokio::spawn(async move {
-            while let Some(message) = rx.recv().await {
-                if sender.send(message.to_string()).await.is_err() {
-                    info!("Failed to send message to peer: {:?}", addr);
-                    break;
+        Peer {
+            username,
+            name,
+            stream: receiver,
+            hangup,
+            writer,
+        }
+    }
+
+    /// Sends a message to a single peer.
+    async fn notify(&self, addr: SocketAddr, message: Message) {
+        let sender = match self.peers.get(&addr) {
+            Some(peer) => peer.sender.clone(),
+            None => return,
+        };
+        self.deliver(vec![(addr, sender)], Arc::new(message)).await;
+    }
+
+    async fn execute(&self, addr: SocketAddr, command: Command) {
+        match command {
+            Command::Msg { to, text } => self.send_private(addr, &to, text).await,
+            Command::Join(channel) => self.join(addr, &channel).await,
+            Command::Part(channel) => self.part(addr, channel).await,
+            Command::Invite { user, channel } => self.invite(addr, &user, &channel).await,
+            Command::Nick(nick) => self.set_nick(addr, nick).await,
+            Command::Motd => self.send_motd(addr, true).await,
+            Command::InviteOnly(on) => self.set_invite_only(addr, on).await,
+            Command::Search { query, limit } => self.search(addr, &query, limit).await,
+            Command::ReplyTo { id, text } => self.reply_to(addr, id, &text).await,
+            Command::Admin(password) => self.admin_login(addr, &password).await,
+            Command::Slowmode(secs) => self.set_slowmode(addr, secs).await,
+            Command::ClearChannel(channel) => self.clear_channel(addr, &channel).await,
+            Command::Roll(dice) => self.roll(addr, dice).await,
+            Command::Export(channel) => self.export(addr, &channel).await,
+            Command::Drain => self.drain_command(addr).await,
+            Command::Who(page) => self.who(addr, page).await,
+            Command::Whois(user) => self.whois(addr, &user).await,
+            Command::Whoami => self.whoami(addr).await,
+            Command::Compress(on) => self.set_compression(addr, on).await,
+            Command::Connections => self.connections(addr).await,
+            Command::Version => {
+                self.notify(addr, Message::server(self.version())).await;
+            }
+            Command::Names(channel) => self.names(addr, channel).await,
+            Command::Op(user) => self.op(addr, &user).await,
+            Command::Deop(user) => self.deop(addr, &user).await,
+            Command::Topic(topic) => self.topic(addr, topic).await,
+            Command::Format(format) => self.set_format(addr, format).await,
+            Command::Pin(message) => self.pin(addr, Some(message)).await,
+            Command::Unpin => self.pin(addr, None).await,
+            Command::SetWelcome(welcome) => self.set_welcome(addr, welcome).await,
+            Command::Pinned => {
+                if let Some(channel) = self.peers.get(&addr).and_then(|peer| peer.current.clone()) {
+                    self.show_pinned(addr, &channel, true).await;
+                } else {
+                    self.notify(addr, Message::server("You are not in any channel."))
+                        .await;
                 }
             }
-        });
+            Command::Kick(user) => self.kick(addr, &user).await,
+            Command::Stats => {
+                let mut stats = format!(
+                    "Peers: {}, channels: {}, draining: {}, dropped messages: {}",
+                    self.peers.len(),
+                    self.channels.len(),
+                    if self.is_draining() { "yes" } else { "no" },
+                    self.dropped_messages.load(Ordering::Relaxed)
+                );
+                if let Some(fanout) = &self.fanout {
+                    stats.push_str(&format!(
+                        ", broadcasts in flight: {}, waiting: {}",
+                        fanout.in_flight(),
+                        fanout.waiting()
+                    ));
+                }
+                if self.server.raid.is_some() {
+                    let status = if self.raid_active() { "on" } else { "off" };
+                    stats.push_str(&format!(", raid mode: {}", status));
+                }
+                self.notify(addr, Message::server(stats)).await;
+            }
+            Command::Dnd(on) => {
+                if let Some(mut peer) = self.peers.get_mut(&addr) {
+                    peer.dnd = on;
+                }
+                let status = if on { "on" } else { "off" };
+                self.notify(
+                    addr,
+                    Message::server(format!("Do not disturb is {}.", status)),
+                )
+                .await;
+            }
+            Command::Timestamps(on) => {
+                if let Some(peer) = self.peers.get(&addr) {
+                    peer.timestamps.store(on, Ordering::Relaxed);
+                }
+                let status = if on { "on" } else { "off" };
+                self.notify(addr, Message::server(format!("Timestamps are {}.", status)))
+                    .await;
+            }
+            Command::Wrap(on) => self.set_wrap(addr, on).await,
+            Command::Quiet(on) => {
+                if let Some(peer) = self.peers.get(&addr) {
+                    peer.sender.set_quiet(on);
+                }
+                let reply = if on {
+                    "Quiet mode is on: you will only get server notices."
+                } else {
+                    "Quiet mode is off."
+                };
+                self.notify(addr, Message::server(reply)).await;
+            }
+        }
+    }
 
-        Peer {
+    /// Sends the message of the day to a peer. `always` also sends a
+    /// notice when there is none.
+    async fn send_motd(&self, addr: SocketAddr, always: bool) {
+        match &self.settings().motd {
+            Some(motd) => {
+                for line in motd.lines() {
+                    self.notify(addr, Message::server(format!("MOTD: {}", line)))
+                        .await;
+                }
+            }
+            None if always => {
+                self.notify(addr, Message::server("No message of the day set."))
+                    .await
+            }
+            None => {}
+        }
+    }
+
+    fn display_name(&self, addr: SocketAddr) -> String {
+        self.peers
+            .get(&addr)
+            .map(|peer| peer.nick.clone())
+            .unwrap_or_default()
+    }
+
+    /// Whether a name is one only the server may use: what its notices and
+    /// the onboarding bot are shown as, in any case.
+    fn is_reserved_name(&self, name: &str) -> bool {
+        let bot = self.server.onboarding.as_ref().map(|o| o.bot.as_str());
+        std::iter::once(SERVER_NAME)
+            .chain(bot)
+            .any(|reserved| reserved.eq_ignore_ascii_case(name))
+    }
+
+    async fn set_nick(&self, addr: SocketAddr, nick: String) {
+        if self.is_reserved_name(&nick) {
+            let refusal = format!("The name {} is reserved.", nick);
+            self.notify(addr, Message::server(refusal)).await;
+            return;
+        }
+        let Some((username, current)) = self
+            .peers
+            .get(&addr)
+            .map(|peer| (peer.username.clone(), peer.nick.clone()))
+        else {
+            return;
+        };
+        // the peer's own names are already theirs, whatever the case
+        let claim = if nick.eq_ignore_ascii_case(&username) {
+            None
+        } else if nick.eq_ignore_ascii_case(&current) {
+            self.peers
+                .get_mut(&addr)
+                .and_then(|mut peer| peer.nick_claim.take())
+        } else {
+            match self.names.claim(&nick) {
+                Some(claim) => Some(claim),
+                None => {
+                    let refusal = format!("The name {} is already in use.", nick);
+                    self.notify(addr, Message::server(refusal)).await;
+                    return;
+                }
+            }
+        };
+        let old = match self.peers.get_mut(&addr) {
+            Some(mut peer) => {
+                peer.nick_claim = claim;
+                std::mem::replace(&mut peer.nick, nick.clone())
+            }
+            None => return,
+        };
+        self.broadcast(
+            addr,
+            Arc::new(Message::server(format!(
+                "{} is now known as {}.",
+                old, nick
+            ))),
+        )
+        .await;
+        self.notify(
+            addr,
+            Message::server(format!("You are now known as {}.", nick)),
+        )
+        .await;
+    }
+
+    /// Describes the build and what this server is configured to do.
+    fn version(&self) -> String {
+        let mut capabilities = vec![match self.server.protocol {
+            config::Protocol::Text => "text",
+            config::Protocol::Json => "json",
+        }];
+        if self.signer.is_some() {
+            capabilities.push("signing");
+        }
+        if self.message_log.is_some() {
+            capabilities.push("persistence");
+        }
+        if self.published.is_some() {
+            capabilities.push("federation");
+        }
+        if self.server.unix_socket_path.is_some() {
+            capabilities.push("unix-socket");
+        }
+        if cfg!(feature = "console") {
+            capabilities.push("console");
+        }
+        if self.server.compression {
+            capabilities.push("compression");
+        }
+        format!(
+            "{} {} (git {}), capabilities: {}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            option_env!("GIT_HASH").unwrap_or("unknown"),
+            capabilities.join(", ")
+        )
+    }
+
+    async fn whois(&self, addr: SocketAddr, username: &str) {
+        let reply = match self
+            .find_peer(username)
+            .and_then(|target| self.peers.get(&target))
+        {
+            Some(peer) => format!(
+                "{} (nick {}) in {}: received {} bytes, sent {} bytes{}{}",
+                peer.username,
+                peer.nick,
+                peer.current
+                    .as_ref()
+                    .map_or_else(|| "no channel".to_string(), |c| format!("#{}", c)),
+                peer.traffic.received(),
+                peer.traffic.sent(),
+                match peer.sender.dropped() {
+                    0 => String::new(),
+                    dropped => format!(", dropped {} messages", dropped),
+                },
+                if peer.client.is_empty() {
+                    String::new()
+                } else {
+                    format!("; {}", peer.client)
+                }
+            ),
+            None => format!("No such user: {}", username),
+        };
+        self.notify(addr, Message::server(reply)).await;
+    }
+
+    /// Turns on compression of what the peer is sent, starting right after
+    /// the confirmation.
+    async fn set_compression(&self, addr: SocketAddr, on: bool) {
+        let reply = if !self.server.compression {
+            "Compression is not available."
+        } else {
+            let Some(mut peer) = self.peers.get_mut(&addr) else {
+                return;
+            };
+            match (on, peer.compressed) {
+                (true, true) => "Compression is already on.",
+                (true, false) => {
+                    peer.compressed = true;
+                    drop(peer);
+                    let confirmation = Message {
+                        starts_compression: true,
+                        ..Message::server("Compression is on.")
+                    };
+                    self.notify(addr, confirmation).await;
+                    return;
+                }
+                (false, true) => "Compression stays on until you reconnect.",
+                (false, false) => "Compression is off.",
+            }
+        };
+        self.notify(addr, Message::server(reply)).await;
+    }
+
+    /// Tells a peer how the server sees their own session.
+    async fn whoami(&self, addr: SocketAddr) {
+        let Some((username, nick, current, admin, dnd, connected_at)) =
+            self.peers.get(&addr).map(|peer| {
+                (
+                    peer.username.clone(),
+                    peer.nick.clone(),
+                    peer.current.clone(),
+                    peer.admin,
+                    peer.dnd,
+                    peer.connected_at,
+                )
+            })
+        else {
+            return;
+        };
+        let op = current.as_ref().is_some_and(|channel| {
+            self.channels
+                .get(channel)
+                .is_some_and(|existing| existing.ops.contains(&addr))
+        });
+        let roles: Vec<&str> = [(op, "op"), (admin, "admin")]
+            .into_iter()
+            .filter_map(|(has, role)| has.then_some(role))
+            .collect();
+        let reply = format!(
+            "You are {} (nick {}) in {}; roles: {}; do not disturb is {}; connected {}s.",
             username,
-            stream: receiver,
+            nick,
+            current.map_or_else(|| "no channel".to_string(), |c| format!("#{}", c)),
+            if roles.is_empty() {
+                "none".to_string()
+            } else {
+                roles.join(", ")
+            },
+            if dnd { "on" } else { "off" },
+            connected_at.elapsed().as_secs()
+        );
+        self.notify(addr, Message::server(reply)).await;
+    }
+
+    /// Lists a page of the users online by login name.
+    async fn who(&self, addr: SocketAddr, page: usize) {
+        let mut usernames: Vec<String> = self
+            .peers
+            .iter()
+            .map(|peer| peer.username.clone())
+            .collect();
+        usernames.sort_unstable();
+        let page_size = self.server.who_page_size.max(1);
+        let pages = usernames.len().div_ceil(page_size).max(1);
+        if page > pages {
+            let reply = format!("No page {}; there are {} pages.", page, pages);
+            self.notify(addr, Message::server(reply)).await;
+            return;
+        }
+
+        let listed = usernames
+            .chunks(page_size)
+            .nth(page - 1)
+            .unwrap_or_default()
+            .join(", ");
+        let header = format!(
+            "Users online: {} (page {} of {}):",
+            usernames.len(),
+            page,
+            pages
+        );
+        self.notify(addr, Message::server(header)).await;
+        self.notify(addr, Message::server(listed)).await;
+    }
+
+    /// Looks a peer up by login name.
+    fn find_peer(&self, username: &str) -> Option<SocketAddr> {
+        self.peers
+            .iter()
+            .find(|peer| peer.username == username)
+            .map(|peer| *peer.key())
+    }
+
+    async fn send_private(&self, addr: SocketAddr, to: &str, text: String) {
+        let from = match self.peers.get(&addr) {
+            Some(peer) if peer.nick == peer.username => peer.nick.clone(),
+            Some(peer) => format!("{} ({})", peer.nick, peer.username),
+            None => return,
+        };
+        let target = self
+            .find_peer(to)
+            .and_then(|target| self.peers.get(&target))
+            .map(|peer| (*peer.key(), peer.sender.clone(), peer.dnd));
+
+        let reply = match target {
+            None => format!("No such user: {}", to),
+            Some((_, _, true)) => format!("{} is not accepting messages.", to),
+            Some((target, sender, false)) => {
+                let message = Arc::new(Message {
+                    sent: Some(store::unix_millis(SystemTime::now())),
+                    origin: Some(addr),
+                    ..Message::private(from, text)
+                });
+                self.deliver(vec![(target, sender)], message).await;
+                return;
+            }
+        };
+        self.notify(addr, Message::server(reply)).await;
+    }
+
+    /// What a chat line becomes once macros and, if enabled, shortcodes
+    /// are expanded.
+    fn chat_content(&self, line: &str) -> String {
+        let line = macros::expand(line, &self.server.macros);
+        if self.server.emoji_shortcodes {
+            emoji::expand(&line)
+        } else {
+            line
+        }
+    }
+
+    /// Sends a chat message to the peer's current channel, quoting
+    /// `reply_to` if it's a reply.
+    async fn say(
+        &self,
+        addr: SocketAddr,
+        content: String,
+        reply_to: Option<&Message>,
+        attachments: Vec<Attachment>,
+    ) {
+        let (nick, current) = match self.peers.get(&addr) {
+            Some(peer) => (peer.nick.clone(), peer.current.clone()),
+            None => return,
+        };
+        match current {
+            Some(channel) => {
+                let floor = self.raid_slowmode();
+                let wait = self
+                    .channels
+                    .get_mut(&channel)
+                    .and_then(|mut existing| existing.check_slowmode(addr, floor).err());
+                if let Some(wait) = wait {
+                    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                    let notice = format!(
+                        "Slow mode is on in #{}. Wait {}s before sending again.",
+                        channel, secs
+                    );
+                    self.notify(addr, Message::server(notice)).await;
+                    return;
+                }
+
+                let uuid = Uuid::new_v4();
+                self.seen.insert(uuid);
+                let now = SystemTime::now();
+                let mut message = Message {
+                    uuid: Some(uuid),
+                    sent: Some(store::unix_millis(now)),
+                    attachments,
+                    reply_to: reply_to.and_then(|original| original.id),
+                    quote: reply_to.map(reply::quote),
+                    origin: Some(addr),
+                    ..Message::new(nick, content).in_channel(&channel)
+                };
+                let recipients = self.channel_recipients(&channel, Some(addr));
+                let reserved = self.reserve(recipients, &message).await;
+
+                // numbered only once it can be queued, so that no message
+                // with a higher id gets ahead of it
+                let turn = self.sequencer.lock().await;
+                let id = self.next_message_id.fetch_add(1, Ordering::Relaxed);
+                self.reactions.track(id, &channel);
+                message.id = Some(id);
+                let format = self.channels.get(&channel).and_then(|c| c.format.clone());
+                if let Some(format) = format {
+                    let rendered = render::render_template(&format, &message, now);
+                    message.rendered = Some(rendered);
+                }
+                let message = Arc::new(message);
+                let dead = reserved.send(&message);
+                self.keep_for_parked(&message);
+                self.publish(&message);
+                drop(turn);
+
+                // stores can be slow, so chat elsewhere doesn't wait on them
+                self.history.append(&message).await;
+
+                if let Some(log) = &self.message_log {
+                    if log.send(message.clone()).await.is_err() {
+                        warn!("Message log writer has stopped");
+                    }
+                }
+                self.bury(dead).await;
+            }
+            None => {
+                self.notify(
+                    addr,
+                    Message::server("You are not in any channel. Use /join <channel>."),
+                )
+                .await
+            }
+        }
+    }
+
+    /// Replies with recent messages matching the query from the channels
+    /// the peer is in.
+    async fn search(&self, addr: SocketAddr, query: &str, limit: Option<usize>) {
+        let channels = match self.peers.get(&addr) {
+            Some(peer) => peer.channels.clone(),
+            None => return,
+        };
+        let limit = limit
+            .unwrap_or(SEARCH_DEFAULT_RESULTS)
+            .min(SEARCH_MAX_RESULTS);
+        let visible = |message: &Message| {
+            message
+                .channel
+                .as_ref()
+                .is_some_and(|channel| channels.contains(channel))
+        };
+        let found = self.history.search(query, limit, &visible).await;
+
+        self.notify(
+            addr,
+            Message::server(format!("{} result(s) for \"{}\":", found.len(), query)),
+        )
+        .await;
+        for message in found {
+            let channel = message.channel.as_deref().unwrap_or_default();
+            self.notify(addr, Message::server(format!("[#{}] {}", channel, message)))
+                .await;
+        }
+    }
+
+    /// Tells the peer why, then closes their connection.
+    async fn disconnect(&self, addr: SocketAddr, reason: impl Into<String>) {
+        self.notify(addr, Message::server(reason)).await;
+        if let Some(peer) = self.peers.get(&addr) {
+            peer.hangup.hang_up(Departure::Disconnected);
+        }
+    }
+
+    /// Removes the peer and announces that they left. The connection task
+    /// gets here however the session ended, and so can a delivery that
+    /// finds the peer's queue closed; only the first one to remove the peer
+    /// announces it.
+    async fn depart(&self, addr: SocketAddr) {
+        let Some(peer) = self.remove_peer(addr) else {
+            return;
+        };
+        // a closed queue means the writer is gone, and it only stops early
+        // when a write fails
+        peer.hangup.hang_up(Departure::WriteFailed);
+        info!(
+            "{} left ({}): {:?}",
+            peer.username,
+            peer.hangup.reason().unwrap_or(Departure::WriteFailed),
+            addr
+        );
+        let held = self.hold_leave(&peer.username, &peer.nick, addr);
+        self.post_event(Event::Leave {
+            username: peer.username,
+        });
+        if held {
+            return;
+        }
+        self.broadcast(
+            addr,
+            Arc::new(Message::server(format!("{} has left the chat.", peer.nick))),
+        )
+        .await;
+    }
+
+    fn remove_peer(&self, addr: SocketAddr) -> Option<PeerHandle> {
+        let (_, peer) = self.peers.remove(&addr)?;
+        for channel in &peer.channels {
+            self.remove_member(channel, addr);
+        }
+        Some(peer)
+    }
+}
+
+/// Room taken for one message in the queue of every recipient that wants it.
+struct Reserved<'a> {
+    slots: Vec<(SocketAddr, Slot)>,
+    /// Recipients whose queue is closed.
+    dead: Vec<SocketAddr>,
+    _fanout: Option<SemaphorePermit<'a>>,
+}
+
+impl Reserved<'_> {
+    /// Queues the message in every slot, returning the recipients whose
+    /// queue turned out to be closed. Must be called holding the
+    /// sequencer, so that every peer sees messages in the same order.
+    fn send(self, message: &Arc<Message>) -> Vec<SocketAddr> {
+        let mut dead = self.dead;
+        for (addr, slot) in self.slots {
+            if slot.send(message.clone()).is_err() {
+                dead.push(addr);
+            }
         }
+        // the fanout permit goes with `self`: departures deliver too, and
+        // must not wait on our own permit
+        dead
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 struct Message {
     sender: String,
     content: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    private: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    channel: Option<String>,
+    /// Server-assigned id of a channel message, which reactions refer to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    /// Globally unique id of a channel message, used to deliver it only
+    /// once across federated nodes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uuid: Option<Uuid>,
+    /// Reaction counts by emoji, set on reaction updates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reactions: Option<BTreeMap<String, usize>>,
+    /// Milliseconds since the Unix epoch when a chat message was sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sent: Option<u64>,
+    /// Files sent out of band that the message refers to.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<Attachment>,
+    /// Id of the message this one replies to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reply_to: Option<u64>,
+    /// The quoted sender and snippet of the message replied to, shown ahead
+    /// of the content in the text protocol.
+    #[serde(skip)]
+    quote: Option<String>,
+    #[serde(skip)]
+    priority: Priority,
+    /// The local peer that sent the message, whose share of each
+    /// recipient's queue it takes.
+    #[serde(skip)]
+    origin: Option<SocketAddr>,
+    /// The text protocol line, when the channel has its own format.
+    #[serde(skip)]
+    rendered: Option<String>,
+    /// Set on the notice confirming `/compress on`; what the peer is sent
+    /// after it is compressed.
+    #[serde(skip)]
+    starts_compression: bool,
+}
+
+impl Message {
+    fn new(sender: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            sender: sender.into(),
+            content: content.into(),
+            private: false,
+            channel: None,
+            id: None,
+            uuid: None,
+            reactions: None,
+            sent: None,
+            attachments: Vec::new(),
+            reply_to: None,
+            quote: None,
+            priority: Priority::Normal,
+            origin: None,
+            rendered: None,
+            starts_compression: false,
+        }
+    }
+
+    /// A notice from the server, which skips ahead of queued chat.
+    fn server(content: impl Into<String>) -> Self {
+        Self {
+            priority: Priority::High,
+            ..Self::new(SERVER_NAME, content)
+        }
+    }
+
+    fn private(sender: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            private: true,
+            ..Self::new(sender, content)
+        }
+    }
+
+    fn in_channel(self, channel: &str) -> Self {
+        Self {
+            channel: Some(channel.to_string()),
+            ..self
+        }
+    }
+
+    /// The content as text protocol peers see it, after any quote.
+    fn quoted_content(&self) -> Cow<'_, str> {
+        match &self.quote {
+            Some(quote) => format!("[re {}] {}", quote, self.content).into(),
+            None => self.content.as_str().into(),
+        }
+    }
 }
 
 #[derive(Debug)]
-struct Peer {
+struct Peer<S> {
     username: String,
-    stream: SplitStream<Framed<TcpStream, LinesCodec>>,
+    /// Keeps the username taken for the session, and parked after it.
+    name: NameClaim,
+    stream: SplitStream<Framed<S, ChatCodec>>,
+    hangup: Hangup,
+    /// Yields the messages the writer couldn't send once it stops.
+    writer: JoinHandle<Vec<Arc<Message>>>,
+}
+
+trait Listener {
+    type Stream: AsyncRead + AsyncWrite + PeerIdentity + Unpin + Send + 'static;
+
+    async fn accept(&self) -> io::Result<(Self::Stream, SocketAddr)>;
+}
+
+/// A connection whose transport may already vouch for who the peer is,
+/// so they don't have to pick a username.
+trait PeerIdentity {
+    fn identity(&self) -> Option<String> {
+        None
+    }
+}
+
+impl PeerIdentity for TcpStream {}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        TcpListener::accept(self).await
+    }
+}
+
+fn main() -> Result<()> {
+    let config = ServerConfig::try_load()?;
+    runtime::build(&config.runtime)?.block_on(run(config))
+}
+
+async fn run(config: ServerConfig) -> Result<()> {
+    telemetry::init(&config);
+    let state = Arc::new(State::new(config)?);
+    reload::spawn_sighup_handler(state.clone(), ServerConfig::try_load)?;
+    drain::spawn_drain_handler(state.clone())?;
+    watchdog::spawn_watchdog(state.clone());
+    accepts::spawn_accept_summary(state.clone());
+    raid::spawn_raid_monitor(state.clone());
+    federation::spawn_federation(state.clone());
+
+    let tcp = async {
+        if !state.server.listen_tcp {
+            return futures::future::pending().await;
+        }
+        let listener = state.new_tcp_listener().await?;
+        match &state.server.tls {
+            Some(tls) => serve(state.clone(), TlsListener::new(listener, tls)?).await,
+            None => serve(state.clone(), listener).await,
+        }
+    };
+    let unix = async {
+        let Some(path) = &state.server.unix_socket_path else {
+            return futures::future::pending().await;
+        };
+        // dropping the listener on shutdown removes the socket file
+        serve(state.clone(), UnixSocketListener::bind(path)?).await
+    };
+
+    let health = async {
+        let Some(config) = state.server.health.clone() else {
+            return futures::future::pending().await;
+        };
+        health::serve_health(state.clone(), config).await
+    };
+
+    tokio::select! {
+        result = tcp => result,
+        result = unix => result,
+        result = health => result,
+        _ = tokio::signal::ctrl_c() => {
+            info!("Shutting down");
+            Ok(())
+        }
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let state = State::try_load().await?;
-    let listener = state.new_tcp_listener().await?;
+async fn serve(state: Arc<State>, listener: impl Listener) -> Result<()> {
+    let mut backoff = ACCEPT_BACKOFF_MIN;
 
     loop {
-        let (socket, addr) = listener.accept().await?;
-        info!("Accepted connection from: {}", addr);
+        let (socket, addr) = match listener.accept().await {
+            Ok(conn) => {
+                backoff = ACCEPT_BACKOFF_MIN;
+                conn
+            }
+            Err(e) if is_transient_accept_error(&e) => {
+                warn!(
+                    "Failed to accept connection, retrying in {:?}: {}",
+                    backoff, e
+                );
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
         let clone_state = state.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(clone_state, addr, socket).await {
+            let _open = clone_state.log_accept(addr);
+            if let Err(e) = handle_connection(clone_state.clone(), addr, socket).await {
                 warn!("failed to handle connection: {:?}", e);
             }
         });
     }
+}
 
-    #[allow(unreachable_code)]
-    Ok(())
+/// Errors that are about a single connection or a temporary resource
+/// shortage, as opposed to the listener itself being broken.
+fn is_transient_accept_error(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut => true,
+        _ => matches!(
+            e.raw_os_error(),
+            Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+        ),
+    }
 }
 
-async fn handle_connection(state: State, addr: SocketAddr, socket: TcpStream) -> Result<()> {
-    let mut framed = Framed::new(socket, LinesCodec::new());
-    framed.send("Enter your username:").await?;
-    let username = match framed.next().await {
-        Some(Ok(username)) => username,
-        _ => {
-            warn!("Failed to get username from peer: {:?}", addr);
-            return Ok(());
+async fn handle_connection<S>(state: Arc<State>, addr: SocketAddr, socket: S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + PeerIdentity + Unpin + Send + 'static,
+{
+    let identity = socket.identity();
+    let mut framed = Framed::with_capacity(
+        socket,
+        ChatCodec::new(state.server.line_ending),
+        state.server.read_buffer_bytes,
+    );
+    if state.settings().banned_ips.contains(&addr.ip()) {
+        return reject(&state, &mut framed, addr, Rejection::Banned).await;
+    }
+    if state.is_draining() {
+        return reject(&state, &mut framed, addr, Rejection::Draining).await;
+    }
+
+    state.note_connection().await;
+    if let Some(config) = state.challenge_for_connection() {
+        if identity.is_none() && !challenge::pass_challenge(&mut framed, config).await? {
+            return reject(&state, &mut framed, addr, Rejection::ChallengeFailed).await;
         }
+    }
+
+    let _slot = match &state.connections {
+        Some(connections) => match connections.admit(&mut framed).await? {
+            Admission::Admitted(permit) => Some(permit),
+            Admission::QueueFull => {
+                return reject(&state, &mut framed, addr, Rejection::ServerFull).await;
+            }
+            Admission::Left => {
+                info!("Peer left the connection queue: {:?}", addr);
+                return Ok(());
+            }
+        },
+        None => None,
     };
 
-    framed.send(format!("Welcome, {}!", username)).await?;
-    state
-        .broadcast(
-            addr,
-            Arc::new(Message {
-                sender: "Server".to_string(),
-                content: format!("{} has joined the chat.", username),
-            }),
-        )
-        .await;
+    let mut client = ClientInfo::default();
+    let (name, unsent) = match identity {
+        Some(username) if !command::valid_name(&username) => {
+            return reject(&state, &mut framed, addr, Rejection::InvalidUsername).await;
+        }
+        Some(username) if state.is_reserved_name(&username) => {
+            return reject(&state, &mut framed, addr, Rejection::ReservedUsername).await;
+        }
+        Some(username) => match state.names.claim(&username) {
+            Some(name) => (name, Vec::new()),
+            None => return reject(&state, &mut framed, addr, Rejection::UsernameTaken).await,
+        },
+        None => match prompt_username(&state, &mut framed, addr, &mut client).await? {
+            Some(login) => login,
+            None => return Ok(()),
+        },
+    };
 
-    let mut peer = state.add_peer(addr, username, framed).await;
-    while let Some(line) = peer.stream.next().await {
-        let line = line.unwrap();
-        state
-            .broadcast(
-                addr,
-                Arc::new(Message {
-                    sender: peer.username.clone(),
-                    content: line,
-                }),
-            )
-            .await;
+    if let Some(terms) = &state.server.terms {
+        if !accept_terms(&state, &mut framed, terms).await? {
+            return reject(&state, &mut framed, addr, Rejection::TermsNotAccepted).await;
+        }
     }
 
-    state
-        .broadcast(
-            addr,
-            Arc::new(Message {
-                sender: "Server".to_string(),
-                content: format!("{} has left the chat.", peer.username),
-            }),
-        )
-        .await;
+    framed.send(format!("Welcome, {}!", name.name())).await?;
+    let resume_token = state.server.resume.map(|_| resume::new_token());
+    if let Some(token) = &resume_token {
+        framed.send(format!("Resume token: {}", token)).await?;
+    }
+    if state.server.compression {
+        framed.send("Compression available: /compress on").await?;
+    }
+
+    if !client.is_empty() {
+        info!("{} ({:?}) connected with {}", name.name(), addr, client);
+    }
+    let mut peer = state.add_peer(addr, name, framed, unsent, client).await;
+    state.post_event(Event::Join {
+        username: peer.username.clone(),
+    });
+    state.send_motd(addr, false).await;
+    state.onboard(addr);
+    if !state.take_rejoin(&peer.username) {
+        state
+            .broadcast(
+                addr,
+                Arc::new(Message::server(format!(
+                    "{} has joined the chat.",
+                    peer.username
+                ))),
+            )
+            .await;
+    }
+    state.announce_auto_join(addr).await;
+
+    let session_end = async {
+        match state.server.max_session_secs {
+            0 => futures::future::pending().await,
+            secs => time::sleep(Duration::from_secs(secs)).await,
+        }
+    };
+    tokio::pin!(session_end);
+
+    let mut bucket = TokenBucket::new();
+    let mut idle = IdleTimer::new(state.server.idle_timeout_secs, state.server.idle_nudge_secs);
+    loop {
+        let line = tokio::select! {
+            line = peer.stream.next() => line,
+            _ = peer.hangup.wait() => break,
+            event = idle.next() => match event {
+                IdleEvent::Nudge(left) => {
+                    let nudge = format!(
+                        "You'll be disconnected in {}s due to inactivity.",
+                        left.as_secs()
+                    );
+                    state.notify(addr, Message::server(nudge)).await;
+                    continue;
+                }
+                IdleEvent::Timeout => {
+                    info!("Idle timeout reached: {:?}", addr);
+                    state
+                        .notify(addr, Message::server("Disconnected due to inactivity."))
+                        .await;
+                    peer.hangup.hang_up(Departure::IdleTimeout);
+                    break;
+                }
+            },
+            _ = &mut session_end => {
+                info!("Session time limit reached: {:?}", addr);
+                state
+                    .notify(addr, Message::server("Your session has expired."))
+                    .await;
+                peer.hangup.hang_up(Departure::SessionExpired);
+                break;
+            }
+        };
+        let line = match line {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => {
+                info!("Failed to read from peer {:?}: {}", addr, e);
+                peer.hangup.hang_up(Departure::ReadClosed);
+                break;
+            }
+            None => {
+                peer.hangup.hang_up(Departure::ReadClosed);
+                break;
+            }
+        };
+        idle.reset();
+        if let Some(mut handle) = state.peers.get_mut(&addr) {
+            handle.last_active = time::Instant::now();
+        }
+        let line = line.trim_end();
+        if line.is_empty() && state.server.suppress_empty_messages {
+            continue;
+        }
+        if let Some(limit) = state.settings().rate_limit {
+            if let Err(limited) = bucket.try_take(&limit, line.len()) {
+                state.notify(addr, Message::server(limited.reason())).await;
+                continue;
+            }
+        }
+        if state.server.protocol == config::Protocol::Json {
+            if let Ok(frame) = serde_json::from_str::<ClientFrame>(line) {
+                state.handle_frame(addr, frame).await;
+                continue;
+            }
+        }
+        match Command::parse_enabled(line, &state.server.commands) {
+            Some(Ok(command)) => state.execute(addr, command).await,
+            Some(Err(e)) => state.notify(addr, Message::server(e.to_string())).await,
+            None => {
+                state
+                    .say(addr, state.chat_content(line), None, Vec::new())
+                    .await
+            }
+        }
+    }
+
+    let lost = matches!(
+        peer.hangup.reason(),
+        Some(Departure::ReadClosed | Departure::WriteFailed | Departure::Stalled)
+    );
+    let resume_token = resume_token.filter(|_| lost);
+    if let Some(token) = &resume_token {
+        state.hold_session(token, addr);
+    }
+    state.depart(addr).await;
+    state.settle_leave(&peer.username);
+    if let Some(token) = resume_token {
+        state.park_session(token, peer.name, peer.writer).await;
+    }
+    Ok(())
+}
+
+/// How a peer's writer task paces its writes.
+#[derive(Debug, Clone, Copy, Default)]
+struct WriterOptions {
+    flush_policy: FlushPolicy,
+    send_timeout: Option<Duration>,
+    /// Egress cap; messages back up in the queue, and are eventually
+    /// dropped, while the peer is over it.
+    max_bytes_per_sec: Option<u64>,
+    /// Tell the peer how many messages it missed once it catches up.
+    report_dropped: bool,
+}
+
+/// Writes queued messages to a peer until the queue is closed or the
+/// connection fails, in which case it hangs up so that the connection task
+/// cleans up. Returns the messages it couldn't send.
+///
+/// The writer is the only reader of the queue, whose chat is FIFO and only
+/// filled under the sequencer, so a peer sees chat in the order it was
+/// broadcast, and channel messages in id order. Notices skip ahead of it.
+async fn write_messages<W>(
+    addr: SocketAddr,
+    mut rx: Inbox,
+    mut sink: W,
+    format: LineFormat,
+    options: WriterOptions,
+    traffic: Arc<Traffic>,
+    hangup: Hangup,
+) -> Vec<Arc<Message>>
+where
+    W: Sink<String, Error = LinesCodecError> + Unpin,
+{
+    let mut egress = options.max_bytes_per_sec.map(ByteRate::new);
+    loop {
+        let next = match missed_notice(&rx, options) {
+            Some(notice) => Some(notice),
+            None => rx.recv().await,
+        };
+        let Some(message) = next else {
+            break;
+        };
+        let sent = traffic.sent();
+        let mut batch = vec![message];
+        if options.flush_policy == FlushPolicy::Coalesced {
+            while let Some(message) = rx.try_recv() {
+                batch.push(message);
+            }
+        }
+        // nothing written after the peer closed the connection reliably
+        // reaches them
+        if hangup.reason() == Some(Departure::ReadClosed) {
+            return take_unsent(batch, &mut rx);
+        }
+
+        let write = write_batch(addr, &mut sink, &format, &batch);
+        let write = async {
+            match options.send_timeout {
+                Some(limit) => time::timeout(limit, write).await.unwrap_or_else(|_| {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "send timed out").into())
+                }),
+                None => write.await,
+            }
+        };
+        let gave_up = async {
+            hangup.wait().await;
+            time::sleep(HANGUP_WRITE_GRACE).await;
+        };
+        let result = tokio::select! {
+            result = write => result,
+            _ = gave_up => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "write stalled after hang-up").into())
+            }
+        };
+        if let Err(e) = result {
+            let unsent = take_unsent(batch, &mut rx);
+            if is_disconnect(&e) {
+                info!(
+                    "Peer {:?} went away with {} messages unsent",
+                    addr,
+                    unsent.len()
+                );
+            } else {
+                warn!(
+                    "Failed to write to peer {:?}: {}; {} messages unsent",
+                    addr,
+                    e,
+                    unsent.len()
+                );
+            }
+            hangup.hang_up(Departure::WriteFailed);
+            return unsent;
+        }
+
+        if let Some(egress) = &mut egress {
+            let pause = egress.record(traffic.sent() - sent);
+            if !pause.is_zero() {
+                tokio::select! {
+                    _ = time::sleep(pause) => {}
+                    _ = hangup.wait() => return take_unsent(Vec::new(), &mut rx),
+                }
+            }
+        }
+    }
+    Vec::new()
+}
+
+/// A notice of the messages dropped for the peer, once its queue has
+/// drained, if it should hear about them.
+fn missed_notice(rx: &Inbox, options: WriterOptions) -> Option<Arc<Message>> {
+    if !options.report_dropped || rx.len() > 0 {
+        return None;
+    }
+    match rx.take_missed() {
+        0 => None,
+        missed => Some(Arc::new(Message::server(format!(
+            "You missed {} messages while your connection was falling behind.",
+            missed
+        )))),
+    }
+}
+
+/// The batch that failed followed by everything still queued.
+fn take_unsent(mut batch: Vec<Arc<Message>>, rx: &mut Inbox) -> Vec<Arc<Message>> {
+    while let Some(message) = rx.try_recv() {
+        batch.push(message);
+    }
+    batch
+}
+
+async fn write_batch<W>(
+    addr: SocketAddr,
+    sink: &mut W,
+    format: &LineFormat,
+    batch: &[Arc<Message>],
+) -> Result<(), LinesCodecError>
+where
+    W: Sink<String, Error = LinesCodecError> + Unpin,
+{
+    for message in batch {
+        match format.encode_lines(message) {
+            Ok(lines) => {
+                for line in lines {
+                    sink.feed(line).await?;
+                }
+            }
+            Err(e) => warn!("Failed to encode message for peer {:?}: {:?}", addr, e),
+        }
+        if message.starts_compression {
+            // the confirmation itself goes out plain
+            sink.flush().await?;
+            format.deflate.store(true, Ordering::Relaxed);
+        }
+    }
+    sink.flush().await
+}
+
+/// Whether a write failed because the peer closed or reset the connection,
+/// rather than for some other reason worth a warning.
+fn is_disconnect(error: &LinesCodecError) -> bool {
+    match error {
+        LinesCodecError::Io(e) => matches!(
+            e.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::UnexpectedEof
+        ),
+        LinesCodecError::MaxLineLengthExceeded => false,
+    }
+}
 
+/// Tells a peer why they are being turned away before the connection is
+/// closed.
+async fn reject<S>(
+    state: &State,
+    framed: &mut Framed<S, ChatCodec>,
+    addr: SocketAddr,
+    rejection: Rejection,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    info!("Refused peer {:?}: {}", addr, rejection);
+    let line = rejection.render(
+        &state.server.rejection_template,
+        state.server.operator_contact.as_deref(),
+    );
+    framed.send(line).await?;
     Ok(())
 }
 
+/// Asks for a username until the peer picks a valid one that isn't taken,
+/// up to `max_username_attempts` times. Returns `None` when the peer gave up
+/// or ran out of attempts. A `/client` line sent ahead of the username fills
+/// in `client`.
+async fn prompt_username<S>(
+    state: &State,
+    framed: &mut Framed<S, ChatCodec>,
+    addr: SocketAddr,
+    client: &mut ClientInfo,
+) -> Result<Option<(NameClaim, Vec<Arc<Message>>)>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let max_attempts = state.server.max_username_attempts.max(1);
+    for attempt in 1..=max_attempts {
+        framed.send("Enter your username:").await?;
+        let check = state.server.binary_check;
+        let mut line = framed.next().await;
+        let info = match &line {
+            Some(Ok(line)) if client.is_empty() => line.strip_prefix("/client ").map(str::parse),
+            _ => None,
+        };
+        if let Some(info) = info {
+            match info {
+                Ok(info) => *client = info,
+                Err(e) => framed.send(format!("Ignored client info: {}.", e)).await?,
+            }
+            line = framed.next().await;
+        }
+        let username = match line {
+            Some(Ok(username)) if attempt == 1 && !check.accepts(&username) => {
+                reject(state, framed, addr, Rejection::UnsupportedClient).await?;
+                return Ok(None);
+            }
+            Some(Ok(username)) => username,
+            Some(Err(LinesCodecError::Io(e)))
+                if e.kind() == io::ErrorKind::InvalidData && check != BinaryCheck::Off =>
+            {
+                reject(state, framed, addr, Rejection::UnsupportedClient).await?;
+                return Ok(None);
+            }
+            _ => {
+                warn!("Failed to get username from peer: {:?}", addr);
+                return Ok(None);
+            }
+        };
+
+        let token = username
+            .strip_prefix("/resume ")
+            .filter(|_| state.server.resume.is_some());
+        let rejection = if let Some(token) = token {
+            match state.claim_session(token.trim()) {
+                Ok(session) => return Ok(Some(session)),
+                Err(rejection) => rejection,
+            }
+        } else if !command::valid_name(&username) {
+            Rejection::InvalidUsername
+        } else if state.is_reserved_name(&username) {
+            Rejection::ReservedUsername
+        } else {
+            match state.names.claim(&username) {
+                Some(name) => return Ok(Some((name, Vec::new()))),
+                None => Rejection::UsernameTaken,
+            }
+        };
+        if attempt == max_attempts {
+            reject(state, framed, addr, rejection).await?;
+            return Ok(None);
+        }
+        framed.send(rejection.reason()).await?;
+    }
+    Ok(None)
+}
+
+/// Shows the terms and waits for `/accept`. Returns whether the peer
+/// accepted before the timeout.
+async fn accept_terms<S>(
+    state: &State,
+    framed: &mut Framed<S, ChatCodec>,
+    terms: &str,
+) -> Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    for line in terms.lines() {
+        framed.send(line).await?;
+    }
+    framed.send("Type /accept to agree to the terms.").await?;
+
+    let deadline = time::sleep(Duration::from_secs(state.server.terms_timeout_secs));
+    tokio::pin!(deadline);
+    loop {
+        let line = tokio::select! {
+            line = framed.next() => line,
+            _ = &mut deadline => return Ok(false),
+        };
+        match line {
+            Some(Ok(line)) if line.trim() == "/accept" => return Ok(true),
+            Some(Ok(_)) => {
+                framed
+                    .send("You must /accept the terms before chatting.")
+                    .await?
+            }
+            _ => return Ok(false),
+        }
+    }
+}
+
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}: {}", self.sender, self.content)
+        if self.private {
+            write!(f, "[PM] {}: {}", self.sender, self.quoted_content())?;
+        } else {
+            write!(f, "{}: {}", self.sender, self.quoted_content())?;
+        }
+        for attachment in &self.attachments {
+            write!(f, " {}", attachment)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::channel::DEFAULT_CHANNEL;
+    use crate::codec::LineEnding;
+    use crate::config::ClearChannelAction;
+    use crate::test_support::{self, spawn_server, TestClient};
+
+    struct FlakyListener {
+        inner: TcpListener,
+        failures: AtomicUsize,
+        error: fn() -> io::Error,
+    }
+
+    impl Listener for FlakyListener {
+        type Stream = TcpStream;
+
+        async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+            if self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return Err((self.error)());
+            }
+            self.inner.accept().await
+        }
+    }
+
+    async fn flaky_listener(failures: usize, error: fn() -> io::Error) -> Result<FlakyListener> {
+        Ok(FlakyListener {
+            inner: TcpListener::bind("127.0.0.1:0").await?,
+            failures: AtomicUsize::new(failures),
+            error,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_serve_survives_transient_accept_errors() -> Result<()> {
+        let listener = flaky_listener(3, || io::Error::from_raw_os_error(libc::EMFILE)).await?;
+        let addr = listener.inner.local_addr()?;
+        let state = Arc::new(State::new(ServerConfig::default())?);
+        let server = tokio::spawn(serve(state, listener));
+
+        let mut client = TestClient::connect_raw(addr).await?;
+        assert_eq!(client.expect_line().await?, "Enter your username:");
+        assert!(!server.is_finished());
+
+        server.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_serve_exits_on_fatal_accept_error() -> Result<()> {
+        let listener = flaky_listener(1, || io::Error::other("listener closed")).await?;
+        let state = Arc::new(State::new(ServerConfig::default())?);
+        let result = time::timeout(Duration::from_secs(5), serve(state, listener)).await?;
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_and_welcome() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        assert_eq!(alice.try_recv().await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_between_clients() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: bob has joined the chat."
+        );
+
+        alice.send_line("hi bob").await?;
+        assert_eq!(bob.expect_line().await?, "alice: hi bob");
+        bob.send_line("hi alice").await?;
+        assert_eq!(alice.expect_line().await?, "bob: hi alice");
+        assert_eq!(alice.try_recv().await?, None);
+        assert_eq!(bob.try_recv().await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_buffer_sizes() -> Result<()> {
+        let long = "x".repeat(10_000);
+        for read_buffer_bytes in [1, 64, 1024 * 1024] {
+            let addr = spawn_server(ServerConfig {
+                read_buffer_bytes,
+                ..Default::default()
+            })
+            .await?;
+            let mut alice = TestClient::connect(addr, "alice").await?;
+            let mut bob = TestClient::connect(addr, "bob").await?;
+            alice.expect_line().await?; // bob joined
+
+            bob.send_line("hi").await?;
+            assert_eq!(alice.expect_line().await?, "bob: hi");
+            bob.send_line(&long).await?;
+            assert_eq!(alice.expect_line().await?, format!("bob: {}", long));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_version() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        bob.send_line("/version").await?;
+        let reply = bob.expect_line().await?;
+        let expected = format!("Server: concurrency {} (git ", env!("CARGO_PKG_VERSION"));
+        assert!(reply.starts_with(&expected), "{}", reply);
+        assert!(reply.contains("capabilities: text"), "{}", reply);
+        assert_eq!(alice.try_recv().await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_cleans_up() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig::default())?);
+        let addr = test_support::spawn_state(state.clone()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+        assert_eq!(state.peers.len(), 2);
+
+        drop(bob);
+        assert_eq!(alice.expect_line().await?, "Server: bob has left the chat.");
+        assert_eq!(state.peers.len(), 1);
+        assert!(state.find_peer("bob").is_none());
+        let general = state.channels.get(DEFAULT_CHANNEL).unwrap();
+        assert_eq!(general.members.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dnd_blocks_private_messages_only() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: bob has joined the chat."
+        );
+
+        bob.send_line("/dnd on").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Do not disturb is on.");
+
+        alice.send_line("/msg bob psst").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: bob is not accepting messages."
+        );
+        alice.send_line("hello everyone").await?;
+        assert_eq!(bob.expect_line().await?, "alice: hello everyone");
+
+        bob.send_line("/dnd off").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Do not disturb is off.");
+        alice.send_line("/msg bob psst").await?;
+        assert_eq!(bob.expect_line().await?, "[PM] alice: psst");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_channels_per_user() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            max_channels_per_user: 3,
+            ..Default::default()
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+
+        alice.send_line("/join rust").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #rust.");
+        alice.send_line("/join go").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #go.");
+        alice.send_line("/join zig").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: You can't be in more than 3 channels."
+        );
+
+        // switching to a joined channel doesn't count against the limit
+        alice.send_line("/join rust").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Now talking in #rust.");
+        alice.send_line("/part go").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Left #go.");
+        alice.send_line("/join zig").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #zig.");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_channels_refuses_new_channels() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            max_channels: 3,
+            ..Default::default()
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        // #general is the first
+        alice.send_line("/join rust").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #rust.");
+        alice.send_line("/join go").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #go.");
+        bob.send_line("/join zig").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Channel limit reached.");
+        bob.send_line("/names").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: Members of #general (2): alice, bob"
+        );
+
+        // existing channels can still be joined
+        bob.send_line("/join rust").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Joined #rust.");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_channels_are_reclaimed() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig {
+            max_channels: 2,
+            ..Default::default()
+        })?);
+        let addr = test_support::spawn_state(state.clone()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+
+        alice.send_line("/join rust").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #rust.");
+        alice.send_line("/join go").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Channel limit reached.");
+        alice.send_line("/part rust").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Left #rust.");
+        assert!(!state.channels.contains_key("rust"));
+
+        alice.send_line("/join go").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #go.");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_joins_stay_within_max_channels() -> Result<()> {
+        let state = State::new(ServerConfig {
+            max_channels: 4,
+            ..Default::default()
+        })?;
+        let _inboxes: Vec<Inbox> = (1..=16).map(|port| fake_peer(&state, port, 1)).collect();
+
+        let barrier = std::sync::Barrier::new(16);
+        let created = std::thread::scope(|scope| {
+            let joins: Vec<_> = (1..=16)
+                .map(|port| {
+                    let (state, barrier) = (&state, &barrier);
+                    scope.spawn(move || {
+                        barrier.wait();
+                        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+                        state.try_join(addr, &format!("room{}", port)).is_ok()
+                    })
+                })
+                .collect();
+            joins
+                .into_iter()
+                .map(|join| join.join().unwrap())
+                .filter(|&created| created)
+                .count()
+        });
+        assert_eq!(created, 4);
+        let occupied = state.channels.iter().filter(|c| !c.members.is_empty());
+        assert_eq!(occupied.count(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_messages_stay_in_channel() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        let mut carol = TestClient::connect(addr, "carol").await?;
+        alice.expect_line().await?; // bob joined
+        alice.expect_line().await?; // carol joined
+        bob.expect_line().await?; // carol joined
+
+        alice.send_line("/join rust").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #rust.");
+        bob.send_line("/join rust").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Joined #rust.");
+        assert_eq!(alice.expect_line().await?, "Server: bob has joined #rust.");
+
+        alice.send_line("hello rustaceans").await?;
+        assert_eq!(bob.expect_line().await?, "alice: hello rustaceans");
+        carol.send_line("hello general").await?;
+        assert_eq!(alice.expect_line().await?, "carol: hello general");
+        assert_eq!(bob.expect_line().await?, "carol: hello general");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auto_join_channels() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig {
+            default_channel: "#Lobby".to_string(),
+            auto_join: vec!["rust".to_string()],
+            ..Default::default()
+        })?);
+        assert!(state.channels.contains_key("rust"));
+        let addr = test_support::spawn_state(state.clone()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: bob has joined the chat."
+        );
+        assert_eq!(alice.expect_line().await?, "Server: bob has joined #rust.");
+
+        let alice_addr = state.find_peer("alice").unwrap();
+        let peer = state.peers.get(&alice_addr).unwrap();
+        assert_eq!(peer.current.as_deref(), Some("lobby"));
+        assert_eq!(
+            peer.channels,
+            HashSet::from(["lobby".to_string(), "rust".to_string()])
+        );
+        drop(peer);
+
+        bob.send_line("/join rust").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Now talking in #rust.");
+        bob.send_line("hello rust").await?;
+        assert_eq!(alice.expect_line().await?, "bob: hello rust");
+
+        assert!(State::new(ServerConfig {
+            auto_join: vec!["no spaces".to_string()],
+            ..Default::default()
+        })
+        .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_channel_welcome_is_shown_on_join() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+        alice.send_line("/join support").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #support.");
+        alice
+            .send_line("/setwelcome Describe your problem and be patient.")
+            .await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Welcome message for #support set."
+        );
+
+        bob.send_line("/join random").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Joined #random.");
+        bob.send_line("/join support").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Joined #support.");
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: Welcome to #support: Describe your problem and be patient."
+        );
+        bob.send_line("/setwelcome Anything goes.").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: Permission denied: operators of #support only."
+        );
+
+        alice.expect_line().await?; // bob joined #support
+        alice.send_line("/setwelcome").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Welcome message for #support cleared."
+        );
+        bob.send_line("/part").await?;
+        bob.expect_line().await?;
+        bob.send_line("/join support").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Joined #support.");
+        assert_eq!(bob.try_recv().await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pinned_message() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+        alice.send_line("/join rust").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #rust.");
+        alice.send_line("/pinned").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Nothing is pinned in #rust."
+        );
+        alice.send_line("/pin Read the rules first").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: alice pinned in #rust: Read the rules first"
+        );
+
+        bob.send_line("/join rust").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Joined #rust.");
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: Pinned in #rust: Read the rules first"
+        );
+        alice.expect_line().await?; // bob joined #rust
+        bob.send_line("/pinned").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: Pinned in #rust: Read the rules first"
+        );
+        bob.send_line("/unpin").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: Permission denied: operators of #rust only."
+        );
+
+        alice.send_line("/unpin").await?;
+        let unpinned = "Server: alice unpinned the message in #rust.";
+        assert_eq!(alice.expect_line().await?, unpinned);
+        assert_eq!(bob.expect_line().await?, unpinned);
+        bob.send_line("/pinned").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: Nothing is pinned in #rust."
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_channel_operators() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+        alice.send_line("/join rust").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #rust.");
+        bob.send_line("/join rust").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Joined #rust.");
+        alice.expect_line().await?; // bob joined #rust
+
+        bob.send_line("/topic bob was here").await?;
+        let denied = "Server: Permission denied: operators of #rust only.";
+        assert_eq!(bob.expect_line().await?, denied);
+
+        // the creator is an op and can make others ops
+        alice.send_line("/op bob").await?;
+        let opped = "Server: alice made bob an operator of #rust.";
+        assert_eq!(alice.expect_line().await?, opped);
+        assert_eq!(bob.expect_line().await?, opped);
+        bob.send_line("/topic Rust 2024").await?;
+        let topic = "Server: bob set the topic of #rust to: Rust 2024";
+        assert_eq!(bob.expect_line().await?, topic);
+        assert_eq!(alice.expect_line().await?, topic);
+        alice.send_line("/topic").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Topic of #rust: Rust 2024"
+        );
+
+        alice.send_line("/deop bob").await?;
+        let deopped = "Server: alice removed bob's operator status in #rust.";
+        assert_eq!(alice.expect_line().await?, deopped);
+        assert_eq!(bob.expect_line().await?, deopped);
+        bob.send_line("/topic bob was here").await?;
+        assert_eq!(bob.expect_line().await?, denied);
+        bob.send_line("/kick alice").await?;
+        assert_eq!(bob.expect_line().await?, denied);
+
+        alice.send_line("/kick bob").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: You were kicked from #rust by alice."
+        );
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: bob was kicked from #rust by alice."
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_operator_status_ends_when_the_op_leaves() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+        alice.send_line("/join rust").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #rust.");
+        bob.send_line("/join rust").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Joined #rust.");
+        alice.expect_line().await?; // bob joined #rust
+
+        // parting and coming back doesn't restore it
+        alice.send_line("/part").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Left #rust.");
+        bob.expect_line().await?; // alice left #rust
+        alice.send_line("/join rust").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #rust.");
+        bob.expect_line().await?; // alice joined #rust
+        alice.send_line("/kick bob").await?;
+        let denied = "Server: Permission denied: operators of #rust only.";
+        assert_eq!(alice.expect_line().await?, denied);
+
+        // nor does someone else logging in under the same name
+        drop(alice);
+        assert_eq!(bob.expect_line().await?, "Server: alice has left the chat.");
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        bob.expect_line().await?; // alice joined
+        alice.send_line("/join rust").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #rust.");
+        bob.expect_line().await?; // alice joined #rust
+        alice.send_line("/kick bob").await?;
+        assert_eq!(alice.expect_line().await?, denied);
+        alice.send_line("/names").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Members of #rust (2): alice, bob"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_names_lists_one_channel() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut carol = TestClient::connect(addr, "carol").await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        carol.expect_line().await?; // alice joined
+        carol.expect_line().await?; // bob joined
+        alice.expect_line().await?; // bob joined
+
+        bob.send_line("/join rust").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Joined #rust.");
+        alice.send_line("/join rust").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #rust.");
+        bob.expect_line().await?; // alice joined #rust
+
+        carol.send_line("/names #rust").await?;
+        assert_eq!(
+            carol.expect_line().await?,
+            "Server: Members of #rust (2): alice, @bob"
+        );
+        alice.send_line("/names").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Members of #rust (2): alice, @bob"
+        );
+        carol.send_line("/names").await?;
+        assert_eq!(
+            carol.expect_line().await?,
+            "Server: Members of #general (3): alice, bob, carol"
+        );
+        carol.send_line("/names nowhere").await?;
+        assert_eq!(
+            carol.expect_line().await?,
+            "Server: No such channel: #nowhere"
+        );
+        assert_eq!(bob.try_recv().await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_channel_message_format() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+        alice.send_line("/join support").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #support.");
+        bob.send_line("/join support").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Joined #support.");
+        alice.expect_line().await?; // bob joined #support
+
+        alice
+            .send_line("/format [{channel}] {sender}: {content}")
+            .await?;
+        let notice = "Server: alice set the message format of #support to: \
+                      [{channel}] {sender}: {content}";
+        assert_eq!(alice.expect_line().await?, notice);
+        assert_eq!(bob.expect_line().await?, notice);
+        alice.send_line("hello").await?;
+        assert_eq!(bob.expect_line().await?, "[support] alice: hello");
+
+        // other channels keep the server's format
+        bob.send_line("/join general").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Now talking in #general.");
+        bob.send_line("hi").await?;
+        assert_eq!(alice.expect_line().await?, "bob: hi");
+
+        alice.send_line("/format").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: alice reset the message format of #support."
+        );
+        bob.expect_line().await?;
+        alice.send_line("plain again").await?;
+        assert_eq!(bob.expect_line().await?, "alice: plain again");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invite_only_channel() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        let mut carol = TestClient::connect(addr, "carol").await?;
+        alice.expect_line().await?; // bob joined
+        alice.expect_line().await?; // carol joined
+        bob.expect_line().await?; // carol joined
+
+        alice.send_line("/join secret").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #secret.");
+        alice.send_line("/inviteonly on").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: #secret is now invite-only."
+        );
+
+        bob.send_line("/join secret").await?;
+        assert_eq!(bob.expect_line().await?, "Server: #secret is invite-only.");
+
+        alice.send_line("/invite bob secret").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Invited bob to #secret."
+        );
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: alice invited you to #secret. Type /join secret to accept."
+        );
+        bob.send_line("/join secret").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Joined #secret.");
+
+        carol.send_line("/join secret").await?;
+        assert_eq!(
+            carol.expect_line().await?,
+            "Server: #secret is invite-only."
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_search_returns_matches_in_order() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        for line in ["I like Rust", "what about go?", "rust all the way"] {
+            alice.send_line(line).await?;
+            assert_eq!(bob.expect_line().await?, format!("alice: {}", line));
+        }
+
+        bob.send_line("/search rust").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: 2 result(s) for \"rust\":"
+        );
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: [#general] alice: I like Rust"
+        );
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: [#general] alice: rust all the way"
+        );
+
+        bob.send_line("/search rust 1").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: 1 result(s) for \"rust\":"
+        );
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: [#general] alice: rust all the way"
+        );
+        Ok(())
+    }
+
+    fn fake_peer(state: &State, port: u16, capacity: usize) -> Inbox {
+        let (tx, rx) = outbox::channel(capacity);
+        state.peers.insert(
+            SocketAddr::from(([127, 0, 0, 1], port)),
+            PeerHandle {
+                username: format!("peer{}", port),
+                nick: format!("peer{}", port),
+                nick_claim: None,
+                sender: tx,
+                dnd: false,
+                channels: HashSet::new(),
+                current: None,
+                admin: false,
+                hangup: Hangup::default(),
+                connected_at: time::Instant::now(),
+                last_active: time::Instant::now(),
+                traffic: Arc::default(),
+                timestamps: Arc::default(),
+                wrap_width: Arc::default(),
+                compressed: false,
+                client: ClientInfo::default(),
+            },
+        );
+        rx
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_concurrent_broadcasts_are_capped() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig {
+            max_concurrent_broadcasts: 2,
+            ..Default::default()
+        })?);
+        // never drained, so every broadcast after the first waits on them
+        let _stalled = [fake_peer(&state, 1, 1), fake_peer(&state, 2, 1)];
+
+        let broadcasts: Vec<_> = (0..8)
+            .map(|i| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let message = Message::new("alice", i.to_string());
+                    state.broadcast_all(Arc::new(message)).await;
+                })
+            })
+            .collect();
+        let fanout = state.fanout.as_ref().unwrap();
+        let mut most_waiting = 0;
+        while !broadcasts.iter().all(|broadcast| broadcast.is_finished()) {
+            assert!(fanout.in_flight() <= 2);
+            most_waiting = most_waiting.max(fanout.waiting());
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(most_waiting >= 4, "{}", most_waiting);
+        assert_eq!(fanout.waiting(), 0);
+        assert_eq!(fanout.in_flight(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_skips_dead_and_stalled_peers() -> Result<()> {
+        let state = State::new(ServerConfig::default())?;
+        drop(fake_peer(&state, 1, 1)); // writer task gone
+        let _stalled = fake_peer(&state, 2, 1); // never drained
+        let mut healthy = fake_peer(&state, 3, 16);
+
+        let sender = SocketAddr::from(([127, 0, 0, 1], 4));
+        for i in 0..3 {
+            let message = Arc::new(Message::new("alice", format!("msg {}", i)));
+            time::timeout(Duration::from_secs(2), state.broadcast(sender, message)).await?;
+        }
+
+        assert!(!state
+            .peers
+            .contains_key(&SocketAddr::from(([127, 0, 0, 1], 1))));
+        assert!(state
+            .peers
+            .contains_key(&SocketAddr::from(([127, 0, 0, 1], 2))));
+        // the dead peer is announced as gone once, ahead of the queued chat
+        for expected in ["peer1 has left the chat.", "msg 0", "msg 1", "msg 2"] {
+            assert_eq!(healthy.recv().await.unwrap().content, expected);
+        }
+        assert!(healthy.try_recv().is_none());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_full_queues_are_waited_on_together() -> Result<()> {
+        let state = State::new(ServerConfig::default())?;
+        let _stalled: Vec<Inbox> = (1..=8).map(|port| fake_peer(&state, port, 1)).collect();
+        let sender = SocketAddr::from(([127, 0, 0, 1], 9));
+        state
+            .broadcast(sender, Arc::new(Message::new("alice", "fills them")))
+            .await;
+
+        let started = time::Instant::now();
+        state
+            .broadcast(sender, Arc::new(Message::new("alice", "dropped")))
+            .await;
+        assert!(
+            started.elapsed() < DELIVERY_TIMEOUT * 2,
+            "{:?}",
+            started.elapsed()
+        );
+        for peer in state.peers.iter() {
+            assert_eq!(peer.sender.dropped(), 1);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drops_are_posted_and_totalled() -> Result<()> {
+        let (url, mut payloads) = webhook::tests::mock_server(&[200]).await?;
+        let state = State::new(ServerConfig {
+            webhook_url: Some(url),
+            ..Default::default()
+        })?;
+        let _stalled = fake_peer(&state, 1, 1);
+        let mut asker = fake_peer(&state, 2, 16);
+        let sender = SocketAddr::from(([127, 0, 0, 1], 3));
+        for i in 0..2 {
+            let message = Arc::new(Message::new("alice", format!("msg {}", i)));
+            state.broadcast(sender, message).await;
+        }
+        assert_eq!(
+            webhook::tests::next_payload(&mut payloads).await?,
+            serde_json::json!({"event": "dropped", "username": "peer1", "dropped": 1})
+        );
+
+        while asker.try_recv().is_some() {} // the chat
+        state
+            .execute(SocketAddr::from(([127, 0, 0, 1], 2)), Command::Stats)
+            .await;
+        assert_eq!(
+            asker.try_recv().unwrap().content,
+            "Peers: 2, channels: 1, draining: no, dropped messages: 1"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_who_paginates_many_peers() -> Result<()> {
+        let state = State::new(ServerConfig {
+            who_page_size: 100,
+            ..Default::default()
+        })?;
+        let mut inboxes: Vec<_> = (1..=3000).map(|port| fake_peer(&state, port, 16)).collect();
+        let asker = SocketAddr::from(([127, 0, 0, 1], 1));
+
+        let started = time::Instant::now();
+        let message = Arc::new(Message::new("alice", "hi all"));
+        state.broadcast(asker, message).await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(inboxes[0].try_recv().is_none());
+        assert!(inboxes
+            .iter_mut()
+            .skip(1)
+            .all(|inbox| inbox.try_recv().is_some()));
+
+        state.who(asker, 2).await;
+        let header = inboxes[0].recv().await.unwrap();
+        assert_eq!(header.content, "Users online: 3000 (page 2 of 30):");
+        let listed = inboxes[0].recv().await.unwrap();
+        let names: Vec<_> = listed.content.split(", ").collect();
+        assert_eq!(names.len(), 100);
+        assert!(names.windows(2).all(|pair| pair[0] < pair[1]));
+
+        state.who(asker, 31).await;
+        assert_eq!(
+            inboxes[0].recv().await.unwrap().content,
+            "No page 31; there are 30 pages."
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_during_broadcast_does_not_deadlock() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        let carol = TestClient::connect(addr, "carol").await?;
+        alice.expect_line().await?; // bob joined
+        alice.expect_line().await?; // carol joined
+        bob.expect_line().await?; // carol joined
+
+        let flood = tokio::spawn(async move {
+            for i in 0..200 {
+                alice.send_line(format!("msg {}", i)).await?;
+            }
+            anyhow::Ok(alice)
+        });
+        drop(carol);
+
+        let mut alice = flood.await??;
+        loop {
+            let line = bob.expect_line().await?;
+            if line == "alice: msg 199" {
+                break;
+            }
+        }
+
+        // the server is still responsive
+        alice.send_line("/dnd on").await?;
+        loop {
+            if alice.expect_line().await? == "Server: Do not disturb is on." {
+                break;
+            }
+        }
+        TestClient::connect(addr, "dave").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inbound_lines_are_trimmed() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        alice.send_raw(b"hello there \r\r\n").await?;
+        assert_eq!(bob.expect_line().await?, "alice: hello there");
+
+        alice.send_raw(b"/dnd on\t\r\n").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Do not disturb is on.");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_lines_are_suppressed() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        alice.send_line("").await?;
+        alice.send_line("   \r").await?;
+        alice.send_line("after").await?;
+        assert_eq!(bob.expect_line().await?, "alice: after");
+
+        let addr = spawn_server(ServerConfig {
+            suppress_empty_messages: false,
+            ..Default::default()
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+        alice.send_line("").await?;
+        assert_eq!(bob.expect_line().await?, "alice: ");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_private_message_routes_after_nick_change() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        alice.send_line("/nick ally").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: You are now known as ally."
+        );
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: alice is now known as ally."
+        );
+
+        bob.send_line("/msg alice still there?").await?;
+        assert_eq!(alice.expect_line().await?, "[PM] bob: still there?");
+        bob.send_line("/msg ally hello?").await?;
+        assert_eq!(bob.expect_line().await?, "Server: No such user: ally");
+
+        alice.send_line("/msg bob yes").await?;
+        assert_eq!(bob.expect_line().await?, "[PM] ally (alice): yes");
+        alice.send_line("hi all").await?;
+        assert_eq!(bob.expect_line().await?, "ally: hi all");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nick_cannot_pose_as_server_or_others() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        for (nick, refusal) in [
+            ("Server", "Server: The name Server is reserved."),
+            ("SERVER", "Server: The name SERVER is reserved."),
+            ("alice", "Server: The name alice is already in use."),
+            ("Alice", "Server: The name Alice is already in use."),
+        ] {
+            bob.send_line(format!("/nick {}", nick)).await?;
+            assert_eq!(bob.expect_line().await?, refusal);
+        }
+        alice.send_line("/nick ally").await?;
+        alice.expect_line().await?;
+        bob.expect_line().await?; // alice is now known as ally
+        bob.send_line("/nick ally").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: The name ally is already in use."
+        );
+        // going back to your own login is fine
+        alice.send_line("/nick alice").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: You are now known as alice."
+        );
+
+        // nor can a new login take someone's nick or the server's name
+        bob.send_line("/nick robert").await?;
+        bob.expect_line().await?;
+        for (name, refusal) in [
+            ("robert", "That username is already taken."),
+            ("server", "That username is reserved."),
+        ] {
+            let mut client = TestClient::connect_raw(addr).await?;
+            assert_eq!(client.expect_line().await?, "Enter your username:");
+            client.send_line(name).await?;
+            assert_eq!(client.expect_line().await?, refusal);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_motd() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            motd: Some("Be nice.\nHave fun.".to_string()),
+            ..Default::default()
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        assert_eq!(alice.expect_line().await?, "Server: MOTD: Be nice.");
+        assert_eq!(alice.expect_line().await?, "Server: MOTD: Have fun.");
+
+        alice.send_line("/motd").await?;
+        assert_eq!(alice.expect_line().await?, "Server: MOTD: Be nice.");
+        assert_eq!(alice.expect_line().await?, "Server: MOTD: Have fun.");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_motd_unset() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        alice.send_line("/motd").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: No message of the day set."
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_banned_ip_is_refused() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            banned_ips: vec!["127.0.0.1".parse()?],
+            ..Default::default()
+        })
+        .await?;
+        let mut client = TestClient::connect_raw(addr).await?;
+        assert_eq!(
+            client.expect_line().await?,
+            "Connection refused (banned): You are banned from this server."
+        );
+        assert!(client.expect_line().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_counts_lines_and_bytes() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            rate_limit: Some(config::RateLimit {
+                messages_per_sec: 0.0,
+                burst: 3,
+                bytes_per_sec: 0.001,
+                burst_bytes: 1000,
+            }),
+            ..Default::default()
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        // two large lines spend the byte budget before the line budget
+        let long = "x".repeat(600);
+        bob.send_line(&long).await?;
+        bob.send_line(&long).await?;
+        assert_eq!(alice.expect_line().await?, format!("bob: {}", long));
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: You are sending too much text too fast."
+        );
+
+        // while short ones run out of lines first
+        for line in ["a", "b", "c"] {
+            alice.send_line(line).await?;
+        }
+        for line in ["alice: a", "alice: b", "alice: c"] {
+            assert_eq!(bob.expect_line().await?, line);
+        }
+        alice.send_line("d").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: You are sending messages too fast."
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sighup_reloads_rate_limit() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.yaml");
+        let strict = "host: 127.0.0.1\nport: 0\nrate_limit:\n  messages_per_sec: 0.0\n  burst: 1\n";
+        std::fs::write(&path, strict)?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(State::new(ServerConfig::load(&path)?)?);
+        let load_path = path.clone();
+        reload::spawn_sighup_handler(state.clone(), move || ServerConfig::load(&load_path))?;
+        tokio::spawn(serve(state.clone(), listener));
+
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        alice.send_line("one").await?;
+        assert_eq!(bob.expect_line().await?, "alice: one");
+        alice.send_line("two").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: You are sending messages too fast."
+        );
+
+        let loose = strict
+            .replace("burst: 1", "burst: 10")
+            .replace("0.0", "100.0");
+        std::fs::write(&path, loose)?;
+        unsafe { libc::raise(libc::SIGHUP) };
+        time::timeout(Duration::from_secs(5), async {
+            while state.settings().rate_limit.map(|limit| limit.burst) != Some(10) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        // the bucket refills up to the new burst, and nobody got disconnected
+        time::sleep(Duration::from_millis(10)).await;
+        alice.send_line("three").await?;
+        assert_eq!(bob.expect_line().await?, "alice: three");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_slowmode() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            admin_password: Some("hunter2".to_string()),
+            ..Default::default()
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        bob.send_line("/slowmode 60").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: Permission denied: operators of #general only."
+        );
+
+        alice.send_line("/admin hunter2").await?;
+        assert_eq!(alice.expect_line().await?, "Server: You are now an admin.");
+        alice.send_line("/slowmode 60").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Slow mode in #general is now 60 seconds."
+        );
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: Slow mode in #general is now 60 seconds."
+        );
+
+        bob.send_line("first").await?;
+        assert_eq!(alice.expect_line().await?, "bob: first");
+        bob.send_line("second").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: Slow mode is on in #general. Wait 60s before sending again."
+        );
+
+        alice.send_line("/slowmode 0").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Slow mode in #general is off."
+        );
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: Slow mode in #general is off."
+        );
+        bob.send_line("third").await?;
+        assert_eq!(alice.expect_line().await?, "bob: third");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket_chat() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("chat.sock");
+        let listener = UnixSocketListener::bind(&path)?;
+        let state = Arc::new(State::new(ServerConfig::default())?);
+        tokio::spawn(serve(state, listener));
+
+        let mut alice =
+            TestClient::login(tokio::net::UnixStream::connect(&path).await?, "alice").await?;
+        let mut bob =
+            TestClient::login(tokio::net::UnixStream::connect(&path).await?, "bob").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: bob has joined the chat."
+        );
+        bob.send_line("hello over uds").await?;
+        assert_eq!(alice.expect_line().await?, "bob: hello over uds");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crlf_line_ending_on_the_wire() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            line_ending: LineEnding::Crlf,
+            ..Default::default()
+        })
+        .await?;
+        let mut stream = TcpStream::connect(addr).await?;
+        let expected = b"Enter your username:\r\n";
+        let mut buf = vec![0; expected.len()];
+        time::timeout(Duration::from_secs(5), stream.read_exact(&mut buf)).await??;
+        assert_eq!(&buf, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_idle_peer_is_nudged_then_disconnected() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            idle_timeout_secs: 2,
+            idle_nudge_secs: 1,
+            ..Default::default()
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+        let nudge = "Server: You'll be disconnected in 1s due to inactivity.";
+
+        let started = time::Instant::now();
+        assert_eq!(bob.expect_line().await?, nudge);
+        assert!(started.elapsed() >= Duration::from_millis(900));
+        // answering the nudge starts the clock over
+        time::sleep(Duration::from_millis(500)).await;
+        bob.send_line("/motd").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: No message of the day set."
+        );
+
+        assert_eq!(alice.expect_line().await?, nudge);
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Disconnected due to inactivity."
+        );
+        alice.expect_closed().await?;
+        assert!(started.elapsed() >= Duration::from_millis(1900));
+
+        assert_eq!(bob.expect_line().await?, "Server: alice has left the chat.");
+        assert_eq!(bob.expect_line().await?, nudge);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_session_disconnects_active_peer() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            max_session_secs: 1,
+            ..Default::default()
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+
+        // keep chatting for most of the session; stopping short of the limit
+        // leaves nothing unread on the server side that would reset the
+        // connection before the notice arrives
+        let started = time::Instant::now();
+        for _ in 0..7 {
+            alice.send_line("/motd").await?;
+            assert_eq!(
+                alice.expect_line().await?,
+                "Server: No message of the day set."
+            );
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Your session has expired."
+        );
+        assert!(started.elapsed() >= Duration::from_millis(900));
+        alice.expect_closed().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_join_posts_webhook() -> Result<()> {
+        let (url, mut payloads) = webhook::tests::mock_server(&[200]).await?;
+        let addr = spawn_server(ServerConfig {
+            webhook_url: Some(url),
+            ..Default::default()
+        })
+        .await?;
+        let _alice = TestClient::connect(addr, "alice").await?;
+
+        assert_eq!(
+            webhook::tests::next_payload(&mut payloads).await?,
+            serde_json::json!({"event": "join", "username": "alice"})
+        );
+        Ok(())
+    }
+
+    fn terms_config(timeout: u64) -> ServerConfig {
+        ServerConfig {
+            terms: Some("Be nice.\nNo spam.".to_string()),
+            terms_timeout_secs: timeout,
+            ..Default::default()
+        }
+    }
+
+    /// Logs in, reading the terms up to the `/accept` prompt.
+    async fn connect_to_terms(addr: SocketAddr, username: &str) -> Result<TestClient> {
+        let mut client = TestClient::connect_raw(addr).await?;
+        assert_eq!(client.expect_line().await?, "Enter your username:");
+        client.send_line(username).await?;
+        assert_eq!(client.expect_line().await?, "Be nice.");
+        assert_eq!(client.expect_line().await?, "No spam.");
+        assert_eq!(
+            client.expect_line().await?,
+            "Type /accept to agree to the terms."
+        );
+        Ok(client)
+    }
+
+    #[tokio::test]
+    async fn test_accept_terms_then_chat() -> Result<()> {
+        let addr = spawn_server(terms_config(5)).await?;
+        let mut alice = connect_to_terms(addr, "alice").await?;
+        alice.send_line("/accept").await?;
+        assert_eq!(alice.expect_line().await?, "Welcome, alice!");
+
+        let mut bob = connect_to_terms(addr, "bob").await?;
+        bob.send_line("hi").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "You must /accept the terms before chatting."
+        );
+        bob.send_line("/accept").await?;
+        assert_eq!(bob.expect_line().await?, "Welcome, bob!");
+
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: bob has joined the chat."
+        );
+        bob.send_line("hello").await?;
+        assert_eq!(alice.expect_line().await?, "bob: hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_username_is_held_while_reading_terms() -> Result<()> {
+        let addr = spawn_server(terms_config(5)).await?;
+        let mut alice = connect_to_terms(addr, "alice").await?;
+
+        // neither a second login nor a nick can take the name meanwhile
+        let mut other = TestClient::connect_raw(addr).await?;
+        assert_eq!(other.expect_line().await?, "Enter your username:");
+        other.send_line("Alice").await?;
+        assert_eq!(
+            other.expect_line().await?,
+            "That username is already taken."
+        );
+        assert_eq!(other.expect_line().await?, "Enter your username:");
+        other.send_line("carol").await?;
+        for _ in 0..3 {
+            other.expect_line().await?; // the terms
+        }
+        other.send_line("/accept").await?;
+        assert_eq!(other.expect_line().await?, "Welcome, carol!");
+        other.send_line("/nick alice").await?;
+        assert_eq!(
+            other.expect_line().await?,
+            "Server: The name alice is already in use."
+        );
+
+        alice.send_line("/accept").await?;
+        assert_eq!(alice.expect_line().await?, "Welcome, alice!");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_terms_timeout_closes_connection() -> Result<()> {
+        let addr = spawn_server(terms_config(1)).await?;
+        let mut bob = connect_to_terms(addr, "bob").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Connection refused (terms_not_accepted): Timed out waiting for /accept."
+        );
+        bob.expect_closed().await?;
+        Ok(())
+    }
+
+    /// Collects written bytes and counts flushes.
+    #[derive(Default)]
+    struct CountingWriter {
+        written: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<io::Result<usize>> {
+            self.written.extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            self.flushes += 1;
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    async fn write_burst(options: WriterOptions) -> Result<CountingWriter> {
+        let (tx, rx) = outbox::channel(20);
+        for i in 0..10 {
+            let message = Message::new("alice", i.to_string());
+            tx.send(Arc::new(message)).await?;
+        }
+        drop(tx);
+
+        let mut framed = tokio_util::codec::FramedWrite::new(
+            CountingWriter::default(),
+            ChatCodec::new(LineEnding::Lf),
+        );
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let traffic = framed.encoder().traffic();
+        write_messages(
+            addr,
+            rx,
+            &mut framed,
+            LineFormat {
+                protocol: config::Protocol::Text,
+                signer: None,
+                renderer: Arc::new(DefaultRenderer),
+                timestamps: Arc::default(),
+                wrap_width: Arc::default(),
+                deflate: Arc::default(),
+            },
+            options,
+            traffic,
+            Hangup::default(),
+        )
+        .await;
+        Ok(framed.into_inner())
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_flushes_less_under_burst() -> Result<()> {
+        let immediate = write_burst(WriterOptions::default()).await?;
+        let coalesced = write_burst(WriterOptions {
+            flush_policy: FlushPolicy::Coalesced,
+            ..Default::default()
+        })
+        .await?;
+
+        assert_eq!(immediate.flushes, 10);
+        assert!(coalesced.flushes < immediate.flushes);
+        let expected: String = (0..10).map(|i| format!("alice: {}\n", i)).collect();
+        assert_eq!(String::from_utf8(coalesced.written)?, expected);
+        assert_eq!(String::from_utf8(immediate.written)?, expected);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_egress_cap_slows_writer() -> Result<()> {
+        let start = time::Instant::now();
+        write_burst(WriterOptions::default()).await?;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // ten 9 byte lines at 30 bytes a second, with a second's worth of
+        // burst, take two more seconds
+        let start = time::Instant::now();
+        let written = write_burst(WriterOptions {
+            max_bytes_per_sec: Some(30),
+            ..Default::default()
+        })
+        .await?;
+        assert_eq!(written.written.len(), 90);
+        assert!(start.elapsed() >= Duration::from_millis(1990));
+        assert!(start.elapsed() < Duration::from_millis(2100));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_whois_reports_traffic() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        alice.send_line("hello").await?;
+        alice.send_line("/whois alice").await?;
+        let reply = alice.expect_line().await?;
+        // the username, "hello" and the command itself were received; the
+        // prompt and the welcome were sent
+        let received = "alice\nhello\n/whois alice\n".len();
+        let sent = "Enter your username:\nWelcome, alice!\n".len();
+        assert_eq!(
+            reply,
+            format!(
+                "Server: alice (nick alice) in #general: received {} bytes, sent {} bytes",
+                received, sent
+            )
+        );
+
+        alice.send_line("/whois nobody").await?;
+        assert_eq!(alice.expect_line().await?, "Server: No such user: nobody");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_each_peer_sees_messages_in_id_order() -> Result<()> {
+        const SENDERS: usize = 3;
+        const MESSAGES: usize = 100;
+        let addr = spawn_server(ServerConfig {
+            protocol: config::Protocol::Json,
+            ..Default::default()
+        })
+        .await?;
+        let mut receivers = Vec::new();
+        for i in 0..3 {
+            receivers.push(TestClient::connect(addr, &format!("reader{}", i)).await?);
+        }
+        let mut senders = Vec::new();
+        for i in 0..SENDERS {
+            senders.push(TestClient::connect(addr, &format!("writer{}", i)).await?);
+        }
+
+        // the writers all talk at once, and never read
+        let writing: Vec<_> = senders
+            .into_iter()
+            .map(|mut sender| {
+                tokio::spawn(async move {
+                    for seq in 0..MESSAGES {
+                        sender.send_line(format!("seq {}", seq)).await?;
+                    }
+                    Ok::<_, anyhow::Error>(sender)
+                })
+            })
+            .collect();
+        let reading: Vec<_> = receivers
+            .into_iter()
+            .map(|mut receiver| {
+                tokio::spawn(async move {
+                    let mut last_id = None;
+                    let mut last = [None; SENDERS];
+                    let mut seen = 0;
+                    while seen < SENDERS * MESSAGES {
+                        let line = receiver.expect_line().await?;
+                        let message: serde_json::Value = serde_json::from_str(&line)?;
+                        let Some(id) = message["id"].as_u64() else {
+                            continue;
+                        };
+                        assert!(last_id < Some(id), "{:?} then {}", last_id, line);
+                        last_id = Some(id);
+
+                        let sender = message["sender"].as_str().unwrap_or_default();
+                        let sender: usize = sender.trim_start_matches("writer").parse()?;
+                        let content = message["content"].as_str().unwrap_or_default();
+                        let seq: usize = content.trim_start_matches("seq ").parse()?;
+                        assert!(last[sender] < Some(seq), "{:?} then {}", last, line);
+                        last[sender] = Some(seq);
+                        seen += 1;
+                    }
+                    Ok::<_, anyhow::Error>(())
+                })
+            })
+            .collect();
+
+        for reader in reading {
+            reader.await??;
+        }
+        for writer in writing {
+            writer.await??;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_info_shows_in_whois() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect_raw(addr).await?;
+        // sent ahead of the username, without waiting for the prompt
+        alice
+            .send_line("/client name=termchat version=1.2 locale=en_US width=120")
+            .await?;
+        alice.send_line("alice").await?;
+        assert_eq!(alice.expect_line().await?, "Enter your username:");
+        assert_eq!(alice.expect_line().await?, "Welcome, alice!");
+
+        let mut bob = TestClient::connect_raw(addr).await?;
+        assert_eq!(bob.expect_line().await?, "Enter your username:");
+        bob.send_line("/client width=2").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Ignored client info: width must be between 20 and 1000."
+        );
+        bob.send_line("bob").await?;
+        assert_eq!(bob.expect_line().await?, "Welcome, bob!");
+        alice.expect_line().await?; // bob joined
+
+        alice.send_line("/whois alice").await?;
+        let reply = alice.expect_line().await?;
+        assert!(
+            reply.ends_with("; client termchat 1.2, locale en_US, width 120"),
+            "{}",
+            reply
+        );
+        alice.send_line("/whois bob").await?;
+        let reply = alice.expect_line().await?;
+        assert!(reply.ends_with(" bytes"), "{}", reply);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dropped_messages_are_counted_and_reported() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig {
+            report_dropped_messages: true,
+            ..Default::default()
+        })?);
+        let addr = test_support::spawn_state(state.clone()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+
+        // bob stops reading, and his small pipe fills up at once
+        let bob_addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let (client, server) = tokio::io::duplex(64);
+        tokio::spawn(handle_connection(state.clone(), bob_addr, server));
+        let mut bob = TestClient::login(client, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        for i in 0..24 {
+            alice.send_line(format!("message {}", i)).await?;
+        }
+        alice.send_line("/whois bob").await?;
+        let reply = alice.expect_line().await?;
+        let dropped = state.peers.get(&bob_addr).unwrap().sender.dropped();
+        assert!(dropped > 0);
+        assert!(
+            reply.ends_with(&format!(", dropped {} messages", dropped)),
+            "{}",
+            reply
+        );
+
+        // once bob reads again he catches up and hears what he missed
+        let mut received = Vec::new();
+        while let Some(line) = bob.try_recv().await? {
+            received.push(line);
+        }
+        assert_eq!(
+            received.last().unwrap(),
+            &format!(
+                "Server: You missed {} messages while your connection was falling behind.",
+                dropped
+            )
+        );
+        let chat = received.iter().filter(|line| line.starts_with("alice: "));
+        assert_eq!(chat.count() as u64 + dropped, 24);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compression_is_negotiated_per_peer() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            compression: true,
+            ..Default::default()
+        })
+        .await?;
+        let banner = "Compression available: /compress on";
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        assert_eq!(alice.expect_line().await?, banner);
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        assert_eq!(bob.expect_line().await?, banner);
+        alice.expect_line().await?; // bob joined
+
+        alice.send_line("/compress on").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Compression is on.");
+        let (mut stream, mut compressed) = alice.into_parts();
+
+        bob.send_line("hello, compressed world").await?;
+        // inflate everything so far until a whole line comes out
+        let mut plain = Vec::with_capacity(1024);
+        while !plain.ends_with(b"\n") {
+            let mut chunk = [0; 256];
+            let n = time::timeout(Duration::from_secs(5), stream.read(&mut chunk)).await??;
+            assert!(n > 0, "connection closed");
+            compressed.extend_from_slice(&chunk[..n]);
+            plain.clear();
+            flate2::Decompress::new(false).decompress_vec(
+                &compressed,
+                &mut plain,
+                flate2::FlushDecompress::Sync,
+            )?;
+        }
+        assert_eq!(
+            String::from_utf8_lossy(&plain),
+            "bob: hello, compressed world\n"
+        );
+
+        // what alice sends stays plain, and bob's side is untouched
+        stream.write_all(b"hi bob\n").await?;
+        assert_eq!(bob.expect_line().await?, "alice: hi bob");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_whoami_reflects_nick_and_channel() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        alice.send_line("/whoami").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: You are alice (nick alice) in #general; roles: none; \
+             do not disturb is off; connected 0s."
+        );
+
+        alice.send_line("/nick ali").await?;
+        alice.expect_line().await?;
+        alice.send_line("/join rust").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #rust.");
+        alice.send_line("/whoami").await?;
+        // creating #rust made her its operator
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: You are alice (nick ali) in #rust; roles: op; \
+             do not disturb is off; connected 0s."
+        );
+        Ok(())
+    }
+
+    async fn admin_with_room(config: ServerConfig) -> Result<(TestClient, TestClient)> {
+        let addr = spawn_server(ServerConfig {
+            admin_password: Some("hunter2".to_string()),
+            ..config
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+        alice.send_line("/admin hunter2").await?;
+        alice.expect_line().await?;
+        bob.send_line("/join room").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Joined #room.");
+        Ok((alice, bob))
+    }
+
+    #[tokio::test]
+    async fn test_connections_lists_peers_for_admins_only() -> Result<()> {
+        let (mut alice, mut bob) = admin_with_room(ServerConfig::default()).await?;
+        bob.send_line("/connections").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: Permission denied: admins only."
+        );
+
+        alice.send_line("/connections").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Connections (2):");
+        for (user, channel) in [("alice", "#general"), ("bob", "#room")] {
+            let line = alice.expect_line().await?;
+            let rest = line
+                .strip_prefix(&format!("Server: {} 127.0.0.1:", user))
+                .unwrap_or_else(|| panic!("{}", line));
+            let (port, rest) = rest.split_once(' ').unwrap();
+            assert!(port.parse::<u16>().is_ok(), "{}", line);
+            assert!(
+                rest.starts_with(&format!("in {}, connected ", channel)),
+                "{}",
+                line
+            );
+            assert!(rest.ends_with("s, 0 queued"), "{}", line);
+        }
+        assert_eq!(alice.try_recv().await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clear_channel_moves_members() -> Result<()> {
+        let (mut alice, mut bob) = admin_with_room(ServerConfig::default()).await?;
+        bob.send_line("/clearchannel room").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: Permission denied: admins only."
+        );
+        alice.send_line("/clearchannel nowhere").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: No such channel: #nowhere"
+        );
+
+        alice.send_line("/clearchannel room").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: #room was closed by an admin. You are now in #general."
+        );
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Cleared #room (1 members)."
+        );
+        bob.send_line("back in general").await?;
+        assert_eq!(alice.expect_line().await?, "bob: back in general");
+
+        alice.send_line("/clearchannel room").await?;
+        assert_eq!(alice.expect_line().await?, "Server: No such channel: #room");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clear_channel_disconnects_members() -> Result<()> {
+        let (mut alice, mut bob) = admin_with_room(ServerConfig {
+            clear_channel_action: ClearChannelAction::Disconnect,
+            ..Default::default()
+        })
+        .await?;
+        alice.send_line("/clearchannel room").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: #room was closed by an admin."
+        );
+        bob.expect_closed().await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Cleared #room (1 members)."
+        );
+        assert_eq!(alice.expect_line().await?, "Server: bob has left the chat.");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auto_join_channels_cant_be_cleared() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig {
+            admin_password: Some("hunter2".to_string()),
+            auto_join: vec!["ops".to_string()],
+            ..Default::default()
+        })?);
+        let addr = test_support::spawn_state(state.clone()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+        alice.expect_line().await?; // bob joined #ops
+        alice.send_line("/admin hunter2").await?;
+        alice.expect_line().await?;
+
+        for channel in ["general", "ops"] {
+            alice
+                .send_line(format!("/clearchannel {}", channel))
+                .await?;
+            assert_eq!(
+                alice.expect_line().await?,
+                format!("Server: #{} can't be cleared.", channel)
+            );
+        }
+        assert!(state.channels.contains_key("ops"));
+        bob.send_line("/join ops").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Now talking in #ops.");
+        bob.send_line("still here").await?;
+        assert_eq!(alice.expect_line().await?, "bob: still here");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reaction_updates_channel() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            protocol: config::Protocol::Json,
+            ..Default::default()
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        bob.send_line("ship it").await?;
+        let message: serde_json::Value = serde_json::from_str(&alice.expect_line().await?)?;
+        assert_eq!(message["content"], "ship it");
+        let id = message["id"].as_u64().expect("channel messages have an id");
+
+        let react = serde_json::json!({"type": "react", "target": id, "emoji": "👍"});
+        alice.send_line(react.to_string()).await?;
+        for client in [&mut alice, &mut bob] {
+            let update: serde_json::Value = serde_json::from_str(&client.expect_line().await?)?;
+            assert_eq!(update["id"], id);
+            assert_eq!(update["reactions"], serde_json::json!({"👍": 1}));
+        }
+
+        let react = serde_json::json!({"type": "react", "target": id + 100, "emoji": "👍"});
+        alice.send_line(react.to_string()).await?;
+        let reply: serde_json::Value = serde_json::from_str(&alice.expect_line().await?)?;
+        assert_eq!(reply["content"], format!("No such message: {}", id + 100));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_queued_connection_admitted_when_slot_frees() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            max_connections: 1,
+            max_queue: 1,
+            ..Default::default()
+        })
+        .await?;
+        let alice = TestClient::connect(addr, "alice").await?;
+
+        let mut bob = TestClient::connect_raw(addr).await?;
+        assert_eq!(bob.expect_line().await?, "You are in queue, position 1.");
+        let mut carol = TestClient::connect_raw(addr).await?;
+        assert_eq!(
+            carol.expect_line().await?,
+            "Connection refused (server_full): Server is full, try again later."
+        );
+        carol.expect_closed().await?;
+
+        drop(alice);
+        assert_eq!(bob.expect_line().await?, "Enter your username:");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_queued_connection_that_hangs_up_leaves_the_queue() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig {
+            max_connections: 1,
+            max_queue: 1,
+            ..Default::default()
+        })?);
+        let addr = test_support::spawn_state(state.clone()).await?;
+        let alice = TestClient::connect(addr, "alice").await?;
+
+        let mut bob = TestClient::connect_raw(addr).await?;
+        assert_eq!(bob.expect_line().await?, "You are in queue, position 1.");
+        bob.send_line("bob").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "You are in queue, position 1; wait for the username prompt."
+        );
+        drop(bob);
+        let started = time::Instant::now();
+        while state.connections.as_ref().unwrap().waiting() > 0 {
+            time::sleep(Duration::from_millis(10)).await;
+            assert!(started.elapsed() < Duration::from_secs(5), "still queued");
+        }
+
+        // bob's place went with him, so carol gets it rather than a refusal
+        let mut carol = TestClient::connect_raw(addr).await?;
+        assert_eq!(carol.expect_line().await?, "You are in queue, position 1.");
+        drop(alice);
+        assert_eq!(carol.expect_line().await?, "Enter your username:");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_federated_messages_delivered_once() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig {
+            hmac_secret: Some("secret".to_string()),
+            ..Default::default()
+        })?);
+        let signer = MessageSigner::new("secret");
+        let addr = test_support::spawn_state(state.clone()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        bob.send_line("local hello").await?;
+        assert_eq!(alice.expect_line().await?, "bob: local hello");
+
+        // our own message coming back from pub/sub
+        let echo = state.history.search("local hello", 1, &|_| true).await;
+        let signature = signer.sign(&echo[0]);
+        state
+            .relay_federated((*echo[0]).clone(), Some(&signature))
+            .await;
+        assert_eq!(alice.try_recv().await?, None);
+
+        let remote = Message {
+            uuid: Some(Uuid::new_v4()),
+            ..Message::new("carol", "remote hello").in_channel(DEFAULT_CHANNEL)
+        };
+        let signature = signer.sign(&remote);
+        state
+            .relay_federated(remote.clone(), Some(&signature))
+            .await;
+        state.relay_federated(remote, Some(&signature)).await;
+        assert_eq!(alice.expect_line().await?, "carol: remote hello");
+        assert_eq!(bob.expect_line().await?, "carol: remote hello");
+        assert_eq!(alice.try_recv().await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejected_usernames() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            max_username_attempts: 1,
+            rejection_template: "{reason} [{code}] Contact: {contact}".to_string(),
+            operator_contact: Some("ops@example.com".to_string()),
+            ..Default::default()
+        })
+        .await?;
+        let _alice = TestClient::connect(addr, "alice").await?;
+
+        for (name, expected) in [
+            (
+                "alice",
+                "That username is already taken. [username_taken] Contact: ops@example.com",
+            ),
+            (
+                "no spaces",
+                "Usernames must be 1 to 32 characters without spaces. [invalid_username] \
+                 Contact: ops@example.com",
+            ),
+        ] {
+            let mut client = TestClient::connect_raw(addr).await?;
+            assert_eq!(client.expect_line().await?, "Enter your username:");
+            client.send_line(name).await?;
+            assert_eq!(client.expect_line().await?, expected);
+            client.expect_closed().await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_binary_clients_are_refused() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let refused = "Connection refused (unsupported_client): \
+                       Unsupported client: expected lines of UTF-8 text.";
+        for junk in [
+            &b"\x16\x03\x01\x02\x00\x01\xfc\x03\n"[..],
+            b"\x01\x02\x03ab\n",
+        ] {
+            let mut client = TestClient::connect_raw(addr).await?;
+            assert_eq!(client.expect_line().await?, "Enter your username:");
+            client.send_raw(junk).await?;
+            assert_eq!(client.expect_line().await?, refused);
+            client.expect_closed().await?;
+        }
+
+        TestClient::connect(addr, "alice").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_binary_check_strictness() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            binary_check: BinaryCheck::Strict,
+            ..Default::default()
+        })
+        .await?;
+        let mut client = TestClient::connect_raw(addr).await?;
+        assert_eq!(client.expect_line().await?, "Enter your username:");
+        client.send_raw(b"alice\x07\n").await?;
+        assert!(client
+            .expect_line()
+            .await?
+            .starts_with("Connection refused (unsupported_client)"));
+
+        assert!(BinaryCheck::Lenient.accepts("alice\x07"));
+        assert!(BinaryCheck::Off.accepts("\x01\x02\x03"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_roll_is_shown_to_channel() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        bob.send_line("/roll 3d1").await?;
+        for client in [&mut alice, &mut bob] {
+            assert_eq!(
+                client.expect_line().await?,
+                "Server: * bob rolled 3d1: 1, 1, 1 (total 3)"
+            );
+        }
+        bob.send_line("/roll lots").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: Usage: /roll <count>d<sides>, like /roll 2d6"
+        );
+        Ok(())
+    }
+
+    struct Shouting;
+
+    impl MessageRenderer for Shouting {
+        fn render(&self, message: &Message) -> String {
+            format!("<{}> {}", message.sender, message.content.to_uppercase())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_renderer() -> Result<()> {
+        let state = State::new(ServerConfig::default())?.with_renderer(Arc::new(Shouting));
+        let addr = test_support::spawn_state(Arc::new(state)).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "<Server> BOB HAS JOINED THE CHAT."
+        );
+        bob.send_line("hello").await?;
+        assert_eq!(alice.expect_line().await?, "<bob> HELLO");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_timestamps_are_per_peer() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            show_timestamps: true,
+            ..Default::default()
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        let mut carol = TestClient::connect(addr, "carol").await?;
+        // notices carry no timestamp
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: bob has joined the chat."
+        );
+        alice.expect_line().await?; // carol joined
+        bob.expect_line().await?; // carol joined
+        bob.send_line("/timestamps off").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Timestamps are off.");
+
+        carol.send_line("hello").await?;
+        let line = alice.expect_line().await?;
+        let (stamp, rest) = line.split_once(' ').unwrap();
+        assert_eq!(rest, "carol: hello");
+        assert!(
+            stamp.len() == 10 && stamp.starts_with('[') && stamp.ends_with(']'),
+            "{:?}",
+            line
+        );
+        assert_eq!(bob.expect_line().await?, "carol: hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_quiet_mode_only_gets_notices() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut monitor = TestClient::connect(addr, "monitor").await?;
+        monitor.send_line("/quiet on").await?;
+        assert_eq!(
+            monitor.expect_line().await?,
+            "Server: Quiet mode is on: you will only get server notices."
+        );
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        alice.send_line("hello").await?;
+        alice.send_line("/msg monitor psst").await?;
+        drop(alice);
+
+        assert_eq!(
+            monitor.expect_line().await?,
+            "Server: alice has joined the chat."
+        );
+        assert_eq!(
+            monitor.expect_line().await?,
+            "Server: alice has left the chat."
+        );
+
+        monitor.send_line("/quiet off").await?;
+        assert_eq!(monitor.expect_line().await?, "Server: Quiet mode is off.");
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        monitor.expect_line().await?; // bob joined
+        bob.send_line("hello").await?;
+        assert_eq!(monitor.expect_line().await?, "bob: hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wrapping_is_per_peer() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            wrap_lines: true,
+            wrap_width: 20,
+            ..Default::default()
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        assert_eq!(alice.expect_line().await?, "Server: bob has");
+        assert_eq!(alice.expect_line().await?, "joined the chat.");
+        bob.send_line("/wrap off").await?;
+        assert_eq!(bob.expect_line().await?, "Server: Wrapping is off.");
+
+        bob.send_line("the quick brown fox jumps over the lazy dog")
+            .await?;
+        for line in ["bob: the quick brown", "fox jumps over the", "lazy dog"] {
+            assert_eq!(alice.expect_line().await?, line);
+        }
+
+        alice.send_line("/wrap off").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Wrapping is off.");
+        alice
+            .send_line("pack my box with five dozen liquor jugs")
+            .await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "alice: pack my box with five dozen liquor jugs"
+        );
+        Ok(())
+    }
+
+    async fn export_fixture(config: ServerConfig) -> Result<(TestClient, TestClient)> {
+        let addr = spawn_server(ServerConfig {
+            admin_password: Some("hunter2".to_string()),
+            ..config
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+        for line in ["first", "second"] {
+            bob.send_line(line).await?;
+            alice.expect_line().await?;
+        }
+        alice.send_line("/admin hunter2").await?;
+        alice.expect_line().await?;
+        Ok((alice, bob))
+    }
+
+    fn exported_contents(json: &str) -> Result<Vec<(String, String)>> {
+        let exported: Vec<serde_json::Value> = serde_json::from_str(json)?;
+        assert!(exported.iter().all(|m| m["timestamp"].as_u64().is_some()));
+        Ok(exported
+            .iter()
+            .map(|m| {
+                let field = |name: &str| m[name].as_str().unwrap_or_default().to_string();
+                (field("sender"), field("content"))
+            })
+            .collect())
+    }
+
+    #[tokio::test]
+    async fn test_export_sends_history_to_admin() -> Result<()> {
+        let (mut alice, mut bob) = export_fixture(ServerConfig::default()).await?;
+        bob.send_line("/export general").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: Permission denied: admins only."
+        );
+
+        alice.send_line("/export general").await?;
+        let reply = alice.expect_line().await?;
+        let json = reply.strip_prefix("Server: ").unwrap();
+        let expected = [
+            ("bob".to_string(), "first".to_string()),
+            ("bob".to_string(), "second".to_string()),
+        ];
+        assert_eq!(exported_contents(json)?, expected);
+
+        alice.send_line("/export nowhere").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: No such channel: #nowhere"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export_is_bounded_by_history_size() -> Result<()> {
+        let (mut alice, _bob) = export_fixture(ServerConfig {
+            history_size: 1,
+            ..Default::default()
+        })
+        .await?;
+        alice.send_line("/export general").await?;
+        let reply = alice.expect_line().await?;
+        let json = reply.strip_prefix("Server: ").unwrap();
+        let expected = [("bob".to_string(), "second".to_string())];
+        assert_eq!(exported_contents(json)?, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export_writes_to_directory() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (mut alice, _bob) = export_fixture(ServerConfig {
+            export_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        })
+        .await?;
+
+        alice.send_line("/export general").await?;
+        let reply = alice.expect_line().await?;
+        let (summary, path) = reply.split_once(" to ").unwrap();
+        assert_eq!(summary, "Server: Exported 2 messages from #general");
+        let contents = exported_contents(&std::fs::read_to_string(path)?)?;
+        assert_eq!(contents.len(), 2);
+        assert_eq!(contents[1].1, "second");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_abrupt_disconnect_announced_once() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let stream = TcpStream::connect(addr).await?;
+        socket2::SockRef::from(&stream).set_linger(Some(Duration::ZERO))?;
+        let bob = TestClient::login(stream, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        // reset the connection, then keep writing so that both the writer
+        // and the read loop see it fail
+        drop(bob);
+        for i in 0..20 {
+            alice.send_line(format!("msg {}", i)).await?;
+        }
+        assert_eq!(alice.expect_line().await?, "Server: bob has left the chat.");
+        while let Some(line) = alice.try_recv().await? {
+            assert_ne!(line, "Server: bob has left the chat.");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_close_leaves_once() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig::default())?);
+        let addr = test_support::spawn_state(state.clone()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+        let bob_addr = state.find_peer("bob").unwrap();
+        let hangup = state.peers.get(&bob_addr).unwrap().hangup.clone();
+
+        drop(bob);
+        assert_eq!(alice.expect_line().await?, "Server: bob has left the chat.");
+        assert_eq!(alice.try_recv().await?, None);
+        assert_eq!(hangup.reason(), Some(Departure::ReadClosed));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_failure_leaves_once() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig {
+            send_timeout_secs: 1,
+            ..Default::default()
+        })?);
+        let addr = test_support::spawn_state(state.clone()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let bob_addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let (client, server) = tokio::io::duplex(64);
+        tokio::spawn(handle_connection(state.clone(), bob_addr, server));
+        // bob is still connected but never reads again
+        let _bob = TestClient::login(client, "bob").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: bob has joined the chat."
+        );
+        let hangup = state.peers.get(&bob_addr).unwrap().hangup.clone();
+
+        let started = time::Instant::now();
+        while state.peers.contains_key(&bob_addr) {
+            alice.send_line("x".repeat(32)).await?;
+            time::sleep(Duration::from_millis(10)).await;
+            assert!(started.elapsed() < Duration::from_secs(5), "bob not reaped");
+        }
+        assert_eq!(alice.expect_line().await?, "Server: bob has left the chat.");
+        assert_eq!(alice.try_recv().await?, None);
+        assert_eq!(hangup.reason(), Some(Departure::WriteFailed));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stalled_reader_is_reaped_after_send_timeout() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig {
+            send_timeout_secs: 1,
+            ..Default::default()
+        })?);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let (client, server) = tokio::io::duplex(64);
+        tokio::spawn(handle_connection(state.clone(), addr, server));
+        let _client = TestClient::login(client, "alice").await?;
+        // alice stops reading, so the tiny pipe fills up and writes stall
+
+        let started = time::Instant::now();
+        let sender = SocketAddr::from(([127, 0, 0, 1], 2));
+        while state.peers.contains_key(&addr) {
+            let message = Arc::new(Message::new("bob", "x".repeat(32)));
+            state.broadcast(sender, message).await;
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "peer not reaped"
+            );
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_username_retry() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            max_username_attempts: 2,
+            ..Default::default()
+        })
+        .await?;
+        let _alice = TestClient::connect(addr, "alice").await?;
+
+        let mut bob = TestClient::connect_raw(addr).await?;
+        assert_eq!(bob.expect_line().await?, "Enter your username:");
+        bob.send_line("alice").await?;
+        assert_eq!(bob.expect_line().await?, "That username is already taken.");
+        assert_eq!(bob.expect_line().await?, "Enter your username:");
+        bob.send_line("bob").await?;
+        assert_eq!(bob.expect_line().await?, "Welcome, bob!");
+
+        let mut carol = TestClient::connect_raw(addr).await?;
+        assert_eq!(carol.expect_line().await?, "Enter your username:");
+        carol.send_line("").await?;
+        assert_eq!(
+            carol.expect_line().await?,
+            "Usernames must be 1 to 32 characters without spaces."
+        );
+        assert_eq!(carol.expect_line().await?, "Enter your username:");
+        carol.send_line("car ol").await?;
+        assert_eq!(
+            carol.expect_line().await?,
+            "Connection refused (invalid_username): Usernames must be 1 to 32 characters \
+             without spaces."
+        );
+        carol.expect_closed().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disabled_command_is_rejected() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            commands: config::CommandsConfig {
+                enabled: None,
+                disabled: vec!["nick".to_string()],
+            },
+            ..Default::default()
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+
+        alice.send_line("/nick al").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Command disabled: /nick"
+        );
+        alice.send_line("/dnd on").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Do not disturb is on.");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drain_refuses_new_connections() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            admin_password: Some("hunter2".to_string()),
+            ..Default::default()
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+        alice.send_line("/admin hunter2").await?;
+        alice.expect_line().await?;
+
+        alice.send_line("/drain").await?;
+        let notice =
+            "Server: The server is going down for maintenance. New connections are refused.";
+        assert_eq!(alice.expect_line().await?, notice);
+        assert_eq!(bob.expect_line().await?, notice);
+
+        let mut carol = TestClient::connect_raw(addr).await?;
+        assert_eq!(
+            carol.expect_line().await?,
+            "Connection refused (draining): The server is draining for maintenance."
+        );
+        carol.expect_closed().await?;
+
+        bob.send_line("still here").await?;
+        assert_eq!(alice.expect_line().await?, "bob: still here");
+        bob.send_line("/stats").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "Server: Peers: 2, channels: 1, draining: yes, dropped messages: 0"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drain_disconnects_idle_peers() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig {
+            drain_idle_secs: 1,
+            ..Default::default()
+        })?);
+        let addr = test_support::spawn_state(state.clone()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        time::sleep(Duration::from_millis(1100)).await;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        state.start_drain().await;
+        alice.expect_line().await?; // maintenance notice
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Disconnected for maintenance while idle."
+        );
+        alice.expect_closed().await?;
+        bob.expect_line().await?; // maintenance notice
+        assert_eq!(bob.expect_line().await?, "Server: alice has left the chat.");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_drains_announce_once() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig::default())?);
+        let addr = test_support::spawn_state(state.clone()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+
+        tokio::join!(state.start_drain(), state.start_drain());
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: The server is going down for maintenance. New connections are refused."
+        );
+        assert_eq!(alice.try_recv().await?, None);
+        Ok(())
+    }
+
+    /// Connects `attempts` clients to a listener that never accepts,
+    /// returning how many got through the handshake.
+    #[cfg(target_os = "linux")]
+    async fn connects_before_refusal(backlog: u32, attempts: usize) -> Result<usize> {
+        let state = State::new(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            backlog,
+            ..Default::default()
+        })?;
+        let listener = state.new_tcp_listener().await?;
+        let addr = listener.local_addr()?;
+        let mut connected = Vec::new();
+        for _ in 0..attempts {
+            // once the accept queue is full the kernel drops the handshake,
+            // and the client would only retry a second later
+            match time::timeout(Duration::from_millis(300), TcpStream::connect(addr)).await {
+                Ok(stream) => connected.push(stream?),
+                Err(_) => break,
+            }
+        }
+        Ok(connected.len())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_listener_uses_configured_backlog() -> Result<()> {
+        // Linux queues one more connection than the backlog
+        assert_eq!(connects_before_refusal(1, 8).await?, 2);
+        assert_eq!(connects_before_refusal(16, 8).await?, 8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reuse_port_listeners_share_the_port() -> Result<()> {
+        let config = ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            reuse_port: true,
+            ..Default::default()
+        };
+        let old = State::new(config.clone())?.new_tcp_listener().await?;
+        let port = old.local_addr()?.port();
+        let new = State::new(ServerConfig { port, ..config })?
+            .new_tcp_listener()
+            .await?;
+        let addr = new.local_addr()?;
+
+        // the kernel spreads connections over both...
+        let accepted = |listener: TcpListener| {
+            let count = Arc::new(AtomicUsize::new(0));
+            let counter = count.clone();
+            let task = tokio::spawn(async move {
+                while listener.accept().await.is_ok() {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+            (count, task)
+        };
+        let (old_count, old_task) = accepted(old);
+        let (new_count, _new_task) = accepted(new);
+        let mut clients = Vec::new();
+        let started = time::Instant::now();
+        while old_count.load(Ordering::Relaxed) == 0 || new_count.load(Ordering::Relaxed) == 0 {
+            clients.push(TcpStream::connect(addr).await?);
+            time::sleep(Duration::from_millis(5)).await;
+            assert!(started.elapsed() < Duration::from_secs(5), "not shared");
+        }
+
+        // ...and once the old one stops listening the new one takes them all
+        old_task.abort();
+        assert!(old_task.await.unwrap_err().is_cancelled());
+        let before = new_count.load(Ordering::Relaxed);
+        for _ in 0..10 {
+            clients.push(TcpStream::connect(addr).await?);
+        }
+        while new_count.load(Ordering::Relaxed) < before + 10 {
+            time::sleep(Duration::from_millis(10)).await;
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "not taken over"
+            );
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,160 @@
+//! An in-memory transport, so the whole server can be driven without real
+//! sockets. Each connection is a `tokio::io::duplex` pipe.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicU16, Ordering},
+    sync::Arc,
+};
+
+use tokio::{
+    io::DuplexStream,
+    sync::{mpsc, Mutex},
+};
+
+use crate::{Listener, PeerIdentity};
+
+/// Bytes each direction of a mock connection buffers.
+const BUFFER_BYTES: usize = 64 * 1024;
+
+impl PeerIdentity for DuplexStream {}
+
+/// Hands out the server ends of connections opened with a [`MockConnector`].
+#[derive(Debug)]
+pub struct MockListener {
+    rx: Mutex<mpsc::UnboundedReceiver<(DuplexStream, SocketAddr)>>,
+    connector: MockConnector,
+}
+
+/// Opens connections to a [`MockListener`].
+#[derive(Debug, Clone)]
+pub struct MockConnector {
+    tx: mpsc::UnboundedSender<(DuplexStream, SocketAddr)>,
+    next_port: Arc<AtomicU16>,
+}
+
+impl MockListener {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self {
+            rx: Mutex::new(rx),
+            connector: MockConnector {
+                tx,
+                next_port: Arc::new(AtomicU16::new(1)),
+            },
+        }
+    }
+
+    pub fn connector(&self) -> MockConnector {
+        self.connector.clone()
+    }
+}
+
+impl Listener for MockListener {
+    type Stream = DuplexStream;
+
+    async fn accept(&self) -> io::Result<(DuplexStream, SocketAddr)> {
+        match self.rx.lock().await.recv().await {
+            Some(conn) => Ok(conn),
+            // the listener holds a connector itself, so this can't happen
+            None => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+impl MockConnector {
+    /// Connects from a fresh loopback address, returning the client end.
+    pub fn connect(&self) -> io::Result<(DuplexStream, SocketAddr)> {
+        let port = self.next_port.fetch_add(1, Ordering::Relaxed);
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let (client, server) = tokio::io::duplex(BUFFER_BYTES);
+        self.tx
+            .send((server, addr))
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        Ok((client, addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time,
+    };
+
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::{serve, State};
+
+    /// Reads exactly `expected` and fails on anything else.
+    async fn expect_bytes(stream: &mut DuplexStream, expected: &[u8]) -> Result<()> {
+        let mut buf = vec![0; expected.len()];
+        time::timeout(Duration::from_secs(5), stream.read_exact(&mut buf)).await??;
+        assert_eq!(
+            String::from_utf8_lossy(&buf),
+            String::from_utf8_lossy(expected)
+        );
+        Ok(())
+    }
+
+    async fn spawn_mock_server(state: Arc<State>) -> MockConnector {
+        let listener = MockListener::new();
+        let connector = listener.connector();
+        tokio::spawn(serve(state, listener));
+        connector
+    }
+
+    async fn login(connector: &MockConnector, username: &str) -> Result<DuplexStream> {
+        let (mut client, _) = connector.connect()?;
+        expect_bytes(&mut client, b"Enter your username:\n").await?;
+        client
+            .write_all(format!("{}\n", username).as_bytes())
+            .await?;
+        expect_bytes(&mut client, format!("Welcome, {}!\n", username).as_bytes()).await?;
+        Ok(client)
+    }
+
+    #[tokio::test]
+    async fn test_username_handshake() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig::default())?);
+        let connector = spawn_mock_server(state.clone()).await;
+
+        let (mut client, addr) = connector.connect()?;
+        expect_bytes(&mut client, b"Enter your username:\n").await?;
+        // split across writes and ending in CRLF
+        client.write_all(b"ali").await?;
+        client.write_all(b"ce\r\n").await?;
+        expect_bytes(&mut client, b"Welcome, alice!\n").await?;
+        assert_eq!(state.find_peer("alice"), Some(addr));
+
+        let (mut taken, _) = connector.connect()?;
+        expect_bytes(&mut taken, b"Enter your username:\n").await?;
+        taken.write_all(b"alice\n").await?;
+        expect_bytes(
+            &mut taken,
+            b"That username is already taken.\nEnter your username:\n",
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_broadcast() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig::default())?);
+        let connector = spawn_mock_server(state).await;
+        let mut alice = login(&connector, "alice").await?;
+        let mut bob = login(&connector, "bob").await?;
+        expect_bytes(&mut alice, b"Server: bob has joined the chat.\n").await?;
+
+        bob.write_all(b"hello\nhow are you?\n").await?;
+        expect_bytes(&mut alice, b"bob: hello\nbob: how are you?\n").await?;
+
+        drop(bob);
+        expect_bytes(&mut alice, b"Server: bob has left the chat.\n").await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use tokio::time::Instant;
+
+/// Names peers go by, logins and nicks alike, compared without case. Taking
+/// one is a single map entry, so two peers can't end up with the same name
+/// however their logins interleave.
+#[derive(Debug, Default)]
+pub struct Names {
+    taken: Arc<DashMap<String, Holder>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Holder {
+    /// Held by a peer logging in or connected.
+    Claim(u64),
+    /// Held for a parked session until it is resumed or expires.
+    Parked { token: String, expires: Instant },
+}
+
+/// A name taken for as long as this is kept.
+#[derive(Debug)]
+pub struct NameClaim {
+    taken: Arc<DashMap<String, Holder>>,
+    key: String,
+    name: String,
+    id: u64,
+}
+
+impl Names {
+    /// Takes the name, unless someone has it or a live session is parked
+    /// under it.
+    pub fn claim(&self, name: &str) -> Option<NameClaim> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let key = name.to_lowercase();
+        match self.taken.entry(key.clone()) {
+            Entry::Occupied(mut held) => match held.get() {
+                Holder::Parked { expires, .. } if *expires <= Instant::now() => {
+                    held.insert(Holder::Claim(id));
+                }
+                _ => return None,
+            },
+            Entry::Vacant(free) => {
+                free.insert(Holder::Claim(id));
+            }
+        }
+        Some(self.claim_for(key, name, id))
+    }
+
+    /// Takes the name back for whoever resumes the session parked with
+    /// `token`.
+    pub fn resume(&self, name: &str, token: &str) -> Option<NameClaim> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let key = name.to_lowercase();
+        match self.taken.get_mut(&key) {
+            Some(mut held) if matches!(&*held, Holder::Parked { token: t, .. } if t == token) => {
+                *held = Holder::Claim(id);
+            }
+            _ => return None,
+        }
+        Some(self.claim_for(key, name, id))
+    }
+
+    /// Frees the name if it is still held for the session parked with
+    /// `token`.
+    pub fn release_parked(&self, name: &str, token: &str) {
+        self.taken.remove_if(
+            &name.to_lowercase(),
+            |_, held| matches!(held, Holder::Parked { token: t, .. } if t == token),
+        );
+    }
+
+    fn claim_for(&self, key: String, name: &str, id: u64) -> NameClaim {
+        NameClaim {
+            taken: self.taken.clone(),
+            key,
+            name: name.to_string(),
+            id,
+        }
+    }
+}
+
+impl NameClaim {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Keeps the name for the session parked with `token` until `expires`.
+    pub fn park(self, token: &str, expires: Instant) {
+        if let Some(mut held) = self.taken.get_mut(&self.key) {
+            if *held == Holder::Claim(self.id) {
+                *held = Holder::Parked {
+                    token: token.to_string(),
+                    expires,
+                };
+            }
+        }
+    }
+}
+
+impl Drop for NameClaim {
+    fn drop(&mut self) {
+        let mine = Holder::Claim(self.id);
+        self.taken.remove_if(&self.key, |_, held| *held == mine);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_names_are_taken_until_released() {
+        let names = Names::default();
+        let alice = names.claim("Alice").unwrap();
+        assert_eq!(alice.name(), "Alice");
+        assert!(names.claim("alice").is_none());
+        drop(alice);
+        assert!(names.claim("alice").is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_parked_names_are_kept_for_their_token() {
+        let names = Names::default();
+        let expires = Instant::now() + Duration::from_secs(60);
+        names.claim("bob").unwrap().park("token", expires);
+        assert!(names.claim("bob").is_none());
+        assert!(names.resume("bob", "other").is_none());
+
+        let bob = names.resume("BOB", "token").unwrap();
+        assert!(names.resume("bob", "token").is_none());
+        drop(bob);
+        assert!(names.claim("bob").is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_parked_names_can_be_claimed() {
+        let names = Names::default();
+        let expires = Instant::now() + Duration::from_secs(60);
+        names.claim("bob").unwrap().park("token", expires);
+        tokio::time::advance(Duration::from_secs(61)).await;
+        let bob = names.claim("bob").unwrap();
+        // the old session's cleanup leaves the new holder alone
+        names.release_parked("bob", "token");
+        assert!(names.claim("bob").is_none());
+        drop(bob);
+    }
+}
@@ -0,0 +1,101 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::time;
+
+use crate::{Message, State};
+
+/// Private messages a bot sends each new user after they log in.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OnboardingConfig {
+    /// Name the messages appear to come from.
+    #[serde(default = "default_bot")]
+    pub bot: String,
+    /// Sent in order.
+    pub messages: Vec<OnboardingMessage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OnboardingMessage {
+    pub text: String,
+    /// How long to wait before sending this message.
+    #[serde(default)]
+    pub delay_ms: u64,
+}
+
+fn default_bot() -> String {
+    "system".to_string()
+}
+
+impl State {
+    /// Starts sending the onboarding messages to a peer that just logged
+    /// in. Stops early if they leave.
+    pub(crate) fn onboard(self: &Arc<Self>, addr: SocketAddr) {
+        let Some(onboarding) = self.server.onboarding.clone() else {
+            return;
+        };
+        let Some(hangup) = self.peers.get(&addr).map(|peer| peer.hangup.clone()) else {
+            return;
+        };
+        let state = self.clone();
+        tokio::spawn(async move {
+            for message in onboarding.messages {
+                if message.delay_ms > 0 {
+                    tokio::select! {
+                        _ = hangup.wait() => return,
+                        _ = time::sleep(Duration::from_millis(message.delay_ms)) => {}
+                    }
+                }
+                let message = Message::private(&onboarding.bot, message.text);
+                state.notify(addr, message).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::test_support::{spawn_server, TestClient};
+
+    #[tokio::test]
+    async fn test_new_user_gets_onboarding_messages() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            onboarding: Some(OnboardingConfig {
+                bot: "helper".to_string(),
+                messages: vec![
+                    OnboardingMessage {
+                        text: "Be nice.".to_string(),
+                        delay_ms: 0,
+                    },
+                    OnboardingMessage {
+                        text: "Type /help for commands.".to_string(),
+                        delay_ms: 50,
+                    },
+                ],
+            }),
+            ..Default::default()
+        })
+        .await?;
+
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        assert_eq!(alice.expect_line().await?, "[PM] helper: Be nice.");
+        assert_eq!(
+            alice.expect_line().await?,
+            "[PM] helper: Type /help for commands."
+        );
+
+        // only alice hears them
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: bob has joined the chat."
+        );
+        assert_eq!(bob.expect_line().await?, "[PM] helper: Be nice.");
+        assert_eq!(alice.try_recv().await?, None);
+        Ok(())
+    }
+}
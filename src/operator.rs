@@ -0,0 +1,260 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{bail, Result};
+
+use crate::{Message, State};
+
+impl State {
+    /// Whether the peer can moderate a channel, as one of its operators or
+    /// as an admin.
+    pub(crate) fn is_op(&self, addr: SocketAddr, channel: &str) -> bool {
+        self.is_admin(addr)
+            || self
+                .channels
+                .get(channel)
+                .is_some_and(|existing| existing.ops.contains(&addr))
+    }
+
+    /// Returns the peer's current channel if they can moderate it, telling
+    /// them why not otherwise.
+    pub(crate) async fn moderated_channel(&self, addr: SocketAddr) -> Option<String> {
+        let Some(channel) = self.peers.get(&addr).and_then(|peer| peer.current.clone()) else {
+            self.notify(addr, Message::server("You are not in any channel."))
+                .await;
+            return None;
+        };
+        if self.is_op(addr, &channel) {
+            return Some(channel);
+        }
+        self.notify(
+            addr,
+            Message::server(format!(
+                "Permission denied: operators of #{} only.",
+                channel
+            )),
+        )
+        .await;
+        None
+    }
+
+    /// Makes a member of the current channel one of its operators.
+    pub(crate) async fn op(&self, addr: SocketAddr, user: &str) {
+        self.set_op(addr, user, true).await;
+    }
+
+    /// Takes operator status in the current channel away from a member.
+    pub(crate) async fn deop(&self, addr: SocketAddr, user: &str) {
+        self.set_op(addr, user, false).await;
+    }
+
+    async fn set_op(&self, addr: SocketAddr, user: &str, op: bool) {
+        let Some(channel) = self.moderated_channel(addr).await else {
+            return;
+        };
+        let target = match self.find_member(&channel, user) {
+            Ok(target) => target,
+            Err(e) => {
+                self.notify(addr, Message::server(e.to_string())).await;
+                return;
+            }
+        };
+        let changed = self.channels.get_mut(&channel).is_some_and(|mut existing| {
+            if op {
+                existing.ops.insert(target)
+            } else {
+                existing.ops.remove(&target)
+            }
+        });
+
+        let name = self.display_name(addr);
+        match (op, changed) {
+            (true, true) => {
+                let notice = format!("{} made {} an operator of #{}.", name, user, channel);
+                self.announce(&channel, notice).await;
+            }
+            (false, true) => {
+                let notice = format!(
+                    "{} removed {}'s operator status in #{}.",
+                    name, user, channel
+                );
+                self.announce(&channel, notice).await;
+            }
+            (true, false) => {
+                let reply = format!("{} is already an operator of #{}.", user, channel);
+                self.notify(addr, Message::server(reply)).await;
+            }
+            (false, false) => {
+                let reply = format!("{} is not an operator of #{}.", user, channel);
+                self.notify(addr, Message::server(reply)).await;
+            }
+        }
+    }
+
+    /// Shows the current channel's topic, or sets it when the peer is an
+    /// operator.
+    pub(crate) async fn topic(&self, addr: SocketAddr, topic: Option<String>) {
+        let Some(topic) = topic else {
+            let Some(channel) = self.peers.get(&addr).and_then(|peer| peer.current.clone()) else {
+                self.notify(addr, Message::server("You are not in any channel."))
+                    .await;
+                return;
+            };
+            let reply = match self.channels.get(&channel).and_then(|c| c.topic.clone()) {
+                Some(topic) => format!("Topic of #{}: {}", channel, topic),
+                None => format!("No topic set for #{}.", channel),
+            };
+            self.notify(addr, Message::server(reply)).await;
+            return;
+        };
+
+        let Some(channel) = self.moderated_channel(addr).await else {
+            return;
+        };
+        if let Some(mut existing) = self.channels.get_mut(&channel) {
+            existing.topic = Some(topic.clone());
+        }
+        let notice = format!(
+            "{} set the topic of #{} to: {}",
+            self.display_name(addr),
+            channel,
+            topic
+        );
+        self.announce(&channel, notice).await;
+    }
+
+    /// Pins a message to the current channel, or clears the pinned one.
+    pub(crate) async fn pin(&self, addr: SocketAddr, message: Option<String>) {
+        let Some(channel) = self.moderated_channel(addr).await else {
+            return;
+        };
+        let previous = self
+            .channels
+            .get_mut(&channel)
+            .and_then(|mut existing| std::mem::replace(&mut existing.pinned, message.clone()));
+        let name = self.display_name(addr);
+        let notice = match (message, previous) {
+            (Some(message), _) => format!("{} pinned in #{}: {}", name, channel, message),
+            (None, Some(_)) => format!("{} unpinned the message in #{}.", name, channel),
+            (None, None) => {
+                let reply = format!("Nothing is pinned in #{}.", channel);
+                self.notify(addr, Message::server(reply)).await;
+                return;
+            }
+        };
+        self.announce(&channel, notice).await;
+    }
+
+    /// Shows the channel's pinned message. `always` also says when there
+    /// is none.
+    pub(crate) async fn show_pinned(&self, addr: SocketAddr, channel: &str, always: bool) {
+        let pinned = self.channels.get(channel).and_then(|c| c.pinned.clone());
+        let reply = match pinned {
+            Some(pinned) => format!("Pinned in #{}: {}", channel, pinned),
+            None if always => format!("Nothing is pinned in #{}.", channel),
+            None => return,
+        };
+        self.notify(addr, Message::server(reply)).await;
+    }
+
+    /// Sets the current channel's welcome, or clears it. Only the operator
+    /// hears about it; members see it when they join.
+    pub(crate) async fn set_welcome(&self, addr: SocketAddr, welcome: Option<String>) {
+        let Some(channel) = self.moderated_channel(addr).await else {
+            return;
+        };
+        let set = welcome.is_some();
+        if let Some(mut existing) = self.channels.get_mut(&channel) {
+            existing.welcome = welcome;
+        }
+        let reply = if set {
+            format!("Welcome message for #{} set.", channel)
+        } else {
+            format!("Welcome message for #{} cleared.", channel)
+        };
+        self.notify(addr, Message::server(reply)).await;
+    }
+
+    /// Shows the channel's welcome, if it has one.
+    pub(crate) async fn show_welcome(&self, addr: SocketAddr, channel: &str) {
+        let welcome = self.channels.get(channel).and_then(|c| c.welcome.clone());
+        if let Some(welcome) = welcome {
+            let reply = format!("Welcome to #{}: {}", channel, welcome);
+            self.notify(addr, Message::server(reply)).await;
+        }
+    }
+
+    /// Sets the template chat in the current channel is rendered with, or
+    /// goes back to the server's format.
+    pub(crate) async fn set_format(&self, addr: SocketAddr, format: Option<String>) {
+        let Some(channel) = self.moderated_channel(addr).await else {
+            return;
+        };
+        if let Some(mut existing) = self.channels.get_mut(&channel) {
+            existing.format = format.clone();
+        }
+        let name = self.display_name(addr);
+        let notice = match format {
+            Some(format) => format!(
+                "{} set the message format of #{} to: {}",
+                name, channel, format
+            ),
+            None => format!("{} reset the message format of #{}.", name, channel),
+        };
+        self.announce(&channel, notice).await;
+    }
+
+    /// Removes a member from the current channel.
+    pub(crate) async fn kick(&self, addr: SocketAddr, user: &str) {
+        let Some(channel) = self.moderated_channel(addr).await else {
+            return;
+        };
+        if channel == self.server.default_channel {
+            self.notify(
+                addr,
+                Message::server(format!("Nobody can be kicked from #{}.", channel)),
+            )
+            .await;
+            return;
+        }
+        let target = match self.find_member(&channel, user) {
+            Ok(target) => target,
+            Err(e) => {
+                self.notify(addr, Message::server(e.to_string())).await;
+                return;
+            }
+        };
+
+        self.leave(target, &channel);
+        let kicker = self.display_name(addr);
+        self.notify(
+            target,
+            Message::server(format!("You were kicked from #{} by {}.", channel, kicker)),
+        )
+        .await;
+        self.announce(
+            &channel,
+            format!("{} was kicked from #{} by {}.", user, channel, kicker),
+        )
+        .await;
+    }
+
+    /// Looks up a member of the channel by login name.
+    fn find_member(&self, channel: &str, user: &str) -> Result<SocketAddr> {
+        let target = self.find_peer(user).filter(|target| {
+            self.channels
+                .get(channel)
+                .is_some_and(|existing| existing.members.contains(target))
+        });
+        match target {
+            Some(target) => Ok(target),
+            None => bail!("{} is not in #{}.", user, channel),
+        }
+    }
+
+    /// Tells every member of the channel, the sender included.
+    async fn announce(&self, channel: &str, notice: String) {
+        let recipients = self.channel_recipients(channel, None);
+        self.deliver(recipients, Arc::new(Message::server(notice)))
+            .await;
+    }
+}
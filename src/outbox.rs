@@ -0,0 +1,580 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::{
+    mpsc::error::{SendError, TrySendError},
+    Notify,
+};
+
+use crate::Message;
+
+/// How urgently a message should reach peers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Priority {
+    /// Chat, delivered in the order it was queued and taking up its
+    /// sender's share of each queue.
+    #[default]
+    Normal,
+    /// Server notices, which have room of their own and jump ahead of
+    /// queued chat.
+    High,
+}
+
+/// The sending half of a peer's queue.
+#[derive(Debug)]
+pub struct Outbox {
+    queue: Arc<Queue>,
+}
+
+/// The receiving half read by the peer's writer task.
+#[derive(Debug)]
+pub struct Inbox {
+    queue: Arc<Queue>,
+    /// Messages from an earlier connection, which go out before anything.
+    replayed: VecDeque<Arc<Message>>,
+}
+
+/// Room for one message in a peer's queue, given back unless a message is
+/// queued in it with [`Slot::send`].
+#[derive(Debug)]
+pub struct Slot {
+    outbox: Outbox,
+    room: Option<Room>,
+}
+
+/// Messages waiting for one peer: notices first, then chat in the order it
+/// was queued. Room for chat is handed out by sender, so a flooding sender only uses up their own share
+/// and can't crowd everyone else out.
+#[derive(Debug)]
+struct Queue {
+    /// Chat messages all senders together can have queued, and notices
+    /// likewise.
+    capacity: usize,
+    /// Chat messages each sender can have queued.
+    share: usize,
+    state: Mutex<QueueState>,
+    /// Wakes the writer when a message arrives or the last outbox is gone.
+    readable: Notify,
+    /// Wakes senders waiting for room when a message is taken.
+    writable: Notify,
+    /// Messages dropped because the peer fell behind, ever and since the
+    /// writer last reported them.
+    dropped: AtomicU64,
+    missed: AtomicU64,
+    /// Only server notices are wanted.
+    quiet: AtomicBool,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    notices_queued: VecDeque<Arc<Message>>,
+    chat_queued: VecDeque<(Arc<Message>, Origin)>,
+    /// Room taken by queued or reserved chat, by sender.
+    shares: HashMap<Origin, usize>,
+    chat: usize,
+    notices: usize,
+    outboxes: usize,
+    closed: bool,
+}
+
+/// What a message counts against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Room {
+    Notice,
+    Chat(Origin),
+}
+
+/// Who a queued chat message counts against: the local peer that sent it,
+/// or by name when it came from elsewhere.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Origin {
+    Peer(SocketAddr),
+    Remote(String),
+}
+
+impl Room {
+    fn of(message: &Message) -> Self {
+        if message.priority == Priority::High {
+            return Room::Notice;
+        }
+        Room::Chat(match message.origin {
+            Some(addr) => Origin::Peer(addr),
+            None => Origin::Remote(message.sender.clone()),
+        })
+    }
+}
+
+/// Creates a peer's queue. Up to `capacity` chat messages can be queued, no
+/// more than half of them from any one sender, and as many server notices
+/// on top.
+pub fn channel(capacity: usize) -> (Outbox, Inbox) {
+    let queue = Arc::new(Queue {
+        capacity,
+        share: (capacity / 2).max(1),
+        state: Mutex::new(QueueState {
+            outboxes: 1,
+            ..Default::default()
+        }),
+        readable: Notify::new(),
+        writable: Notify::new(),
+        dropped: AtomicU64::new(0),
+        missed: AtomicU64::new(0),
+        quiet: AtomicBool::new(false),
+    });
+    (
+        Outbox {
+            queue: queue.clone(),
+        },
+        Inbox {
+            queue,
+            replayed: VecDeque::new(),
+        },
+    )
+}
+
+impl Clone for Outbox {
+    fn clone(&self) -> Self {
+        self.queue.state.lock().unwrap().outboxes += 1;
+        Self {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        state.outboxes -= 1;
+        if state.outboxes == 0 {
+            self.queue.readable.notify_one();
+        }
+    }
+}
+
+impl Drop for Inbox {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().closed = true;
+        self.queue.writable.notify_waiters();
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(room) = self.room.take() {
+            self.outbox.queue.release(room);
+        }
+    }
+}
+
+impl Outbox {
+    /// Takes room for the message without waiting.
+    pub fn try_reserve(&self, message: &Message) -> Result<Slot, TrySendError<()>> {
+        let room = Room::of(message);
+        {
+            let mut state = self.queue.state.lock().unwrap();
+            if state.closed {
+                return Err(TrySendError::Closed(()));
+            }
+            match &room {
+                Room::Notice if state.notices >= self.queue.capacity => {
+                    return Err(TrySendError::Full(()));
+                }
+                Room::Notice => state.notices += 1,
+                Room::Chat(origin) => {
+                    let taken = state.shares.get(origin).copied().unwrap_or(0);
+                    if state.chat >= self.queue.capacity || taken >= self.queue.share {
+                        return Err(TrySendError::Full(()));
+                    }
+                    state.chat += 1;
+                    state.shares.insert(origin.clone(), taken + 1);
+                }
+            }
+        }
+        Ok(Slot {
+            outbox: self.clone(),
+            room: Some(room),
+        })
+    }
+
+    /// Takes room for the message, waiting for it if need be.
+    pub async fn reserve(&self, message: &Message) -> Result<Slot, SendError<()>> {
+        loop {
+            let mut writable = pin!(self.queue.writable.notified());
+            writable.as_mut().enable();
+            match self.try_reserve(message) {
+                Ok(slot) => return Ok(slot),
+                Err(TrySendError::Closed(())) => return Err(SendError(())),
+                Err(TrySendError::Full(())) => {}
+            }
+            writable.await;
+        }
+    }
+
+    /// Queues the message without waiting.
+    #[cfg(test)]
+    pub fn try_send(&self, message: Arc<Message>) -> Result<(), TrySendError<Arc<Message>>> {
+        match self.try_reserve(&message) {
+            Ok(slot) => slot
+                .send(message)
+                .map_err(|SendError(message)| TrySendError::Closed(message)),
+            Err(TrySendError::Full(())) => Err(TrySendError::Full(message)),
+            Err(TrySendError::Closed(())) => Err(TrySendError::Closed(message)),
+        }
+    }
+
+    /// Queues the message, waiting for room.
+    #[cfg(test)]
+    pub async fn send(&self, message: Arc<Message>) -> Result<(), SendError<Arc<Message>>> {
+        match self.reserve(&message).await {
+            Ok(slot) => slot.send(message),
+            Err(SendError(())) => Err(SendError(message)),
+        }
+    }
+
+    /// Number of messages queued and not yet taken by the writer.
+    pub fn len(&self) -> usize {
+        self.queue.state.lock().unwrap().len()
+    }
+
+    /// Counts a message that was dropped because the queue stayed full,
+    /// returning how many this peer has lost in total.
+    pub fn record_drop(&self) -> u64 {
+        self.queue.missed.fetch_add(1, Ordering::Relaxed);
+        self.queue.dropped.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Whether the peer wants the message at all; in quiet mode only server
+    /// notices are.
+    pub fn wants(&self, message: &Message) -> bool {
+        message.priority == Priority::High || !self.queue.quiet.load(Ordering::Relaxed)
+    }
+
+    pub fn set_quiet(&self, quiet: bool) {
+        self.queue.quiet.store(quiet, Ordering::Relaxed);
+    }
+
+    /// Messages dropped for this peer since the queue was created.
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Slot {
+    /// Queues the message behind everything of its priority queued so far.
+    pub fn send(mut self, message: Arc<Message>) -> Result<(), SendError<Arc<Message>>> {
+        let Some(room) = self.room.take() else {
+            return Err(SendError(message));
+        };
+        let queue = &self.outbox.queue;
+        let mut state = queue.state.lock().unwrap();
+        if state.closed {
+            drop(state);
+            queue.release(room);
+            return Err(SendError(message));
+        }
+        match room {
+            Room::Notice => state.notices_queued.push_back(message),
+            Room::Chat(origin) => state.chat_queued.push_back((message, origin)),
+        }
+        drop(state);
+        queue.readable.notify_one();
+        Ok(())
+    }
+}
+
+impl Inbox {
+    /// Waits for the next message. Returns `None` once every sender is gone
+    /// and the queue is drained.
+    pub async fn recv(&mut self) -> Option<Arc<Message>> {
+        if let Some(message) = self.replayed.pop_front() {
+            return Some(message);
+        }
+        self.queue.recv().await
+    }
+
+    pub fn try_recv(&mut self) -> Option<Arc<Message>> {
+        self.replayed.pop_front().or_else(|| self.queue.pop())
+    }
+
+    /// Queues messages ahead of everything else and regardless of capacity,
+    /// in their original order.
+    pub fn replay(&mut self, messages: impl IntoIterator<Item = Arc<Message>>) {
+        self.replayed.extend(messages);
+    }
+
+    /// Number of messages still queued.
+    pub fn len(&self) -> usize {
+        self.replayed.len() + self.queue.state.lock().unwrap().len()
+    }
+
+    /// Messages dropped since the last call.
+    pub fn take_missed(&self) -> u64 {
+        self.queue.missed.swap(0, Ordering::Relaxed)
+    }
+}
+
+impl QueueState {
+    fn len(&self) -> usize {
+        self.notices_queued.len() + self.chat_queued.len()
+    }
+}
+
+impl Queue {
+    /// Takes the next message, notices first.
+    fn pop(&self) -> Option<Arc<Message>> {
+        let mut state = self.state.lock().unwrap();
+        let (message, room) = match state.notices_queued.pop_front() {
+            Some(notice) => (notice, Room::Notice),
+            None => {
+                let (message, origin) = state.chat_queued.pop_front()?;
+                (message, Room::Chat(origin))
+            }
+        };
+        drop(state);
+        self.release(room);
+        Some(message)
+    }
+
+    /// Gives back the room a message took, waking senders waiting for it.
+    fn release(&self, room: Room) {
+        let mut state = self.state.lock().unwrap();
+        match room {
+            Room::Notice => state.notices -= 1,
+            Room::Chat(origin) => {
+                state.chat -= 1;
+                if let Some(taken) = state.shares.get_mut(&origin) {
+                    *taken -= 1;
+                    if *taken == 0 {
+                        state.shares.remove(&origin);
+                    }
+                }
+            }
+        }
+        drop(state);
+        self.writable.notify_waiters();
+    }
+
+    async fn recv(&self) -> Option<Arc<Message>> {
+        loop {
+            let mut readable = pin!(self.readable.notified());
+            readable.as_mut().enable();
+            if let Some(message) = self.pop() {
+                return Some(message);
+            }
+            if self.state.lock().unwrap().outboxes == 0 {
+                return None;
+            }
+            readable.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_high_priority_jumps_the_queue() {
+        let (outbox, mut inbox) = channel(16);
+        for i in 0..3 {
+            let message = Message::new("alice", i.to_string());
+            outbox.send(Arc::new(message)).await.unwrap();
+        }
+        let notice = Message::server("alice has left the chat.");
+        outbox.send(Arc::new(notice)).await.unwrap();
+        drop(outbox);
+
+        let mut received = Vec::new();
+        while let Some(message) = inbox.recv().await {
+            received.push(message.content.clone());
+        }
+        assert_eq!(received, ["alice has left the chat.", "0", "1", "2"]);
+    }
+
+    #[tokio::test]
+    async fn test_senders_take_turns() {
+        let (outbox, mut inbox) = channel(4);
+        // alice's third message waits for her share to free up, while bob
+        // gets in straight away
+        let alice = {
+            let outbox = outbox.clone();
+            tokio::spawn(async move {
+                for i in 0..3 {
+                    let message = Message::new("alice", i.to_string());
+                    outbox.send(Arc::new(message)).await.unwrap();
+                }
+            })
+        };
+        while inbox.len() < 2 {
+            tokio::task::yield_now().await;
+        }
+        outbox
+            .send(Arc::new(Message::new("bob", "hi")))
+            .await
+            .unwrap();
+        drop(outbox);
+
+        let mut received = Vec::new();
+        while let Some(message) = inbox.recv().await {
+            received.push(format!("{}: {}", message.sender, message.content));
+        }
+        alice.await.unwrap();
+        assert_eq!(received, ["alice: 0", "alice: 1", "bob: hi", "alice: 2"]);
+    }
+
+    #[tokio::test]
+    async fn test_flooding_sender_does_not_starve_others() {
+        let (outbox, mut inbox) = channel(4);
+        let flood = |sender: &'static str, outbox: Outbox| {
+            tokio::spawn(async move {
+                for i in 0..50 {
+                    let message = Message::new(sender, i.to_string());
+                    outbox.send(Arc::new(message)).await.unwrap();
+                }
+            })
+        };
+        let alice = flood("alice", outbox.clone());
+        let bob = flood("bob", outbox);
+
+        let mut received = Vec::new();
+        while let Some(message) = inbox.recv().await {
+            received.push(message.sender.clone());
+        }
+        alice.await.unwrap();
+        bob.await.unwrap();
+
+        assert_eq!(received.len(), 100);
+        // every stretch of the delivery order has both senders in it
+        for window in received.chunks(10).take(9) {
+            let alices = window.iter().filter(|sender| *sender == "alice").count();
+            assert!((3..=7).contains(&alices), "{:?}", received);
+        }
+        assert!(inbox.try_recv().is_none());
+        assert_eq!(inbox.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_shares_belong_to_the_sending_peer() {
+        let (outbox, mut inbox) = channel(4);
+        let from = |port: u16, content: &str| {
+            Arc::new(Message {
+                origin: Some(SocketAddr::from(([127, 0, 0, 1], port))),
+                ..Message::new("alice", content)
+            })
+        };
+        // taking someone's name doesn't take their share
+        outbox.try_send(from(1, "a")).unwrap();
+        outbox.try_send(from(1, "b")).unwrap();
+        assert!(matches!(
+            outbox.try_send(from(1, "c")),
+            Err(TrySendError::Full(_))
+        ));
+        outbox.try_send(from(2, "x")).unwrap();
+
+        // and all senders together are bounded too, though notices still
+        // have room
+        outbox.try_send(from(3, "y")).unwrap();
+        assert!(matches!(
+            outbox.try_send(from(4, "z")),
+            Err(TrySendError::Full(_))
+        ));
+        outbox
+            .try_send(Arc::new(Message::server("z was refused")))
+            .unwrap();
+
+        let mut received = Vec::new();
+        while let Some(message) = inbox.try_recv() {
+            received.push(message.content.clone());
+        }
+        assert_eq!(received, ["z was refused", "a", "b", "x", "y"]);
+    }
+
+    #[tokio::test]
+    async fn test_slots_queue_in_the_order_they_are_filled() {
+        let (outbox, mut inbox) = channel(2);
+        let first = Message::new("alice", "first");
+        let second = Message::new("bob", "second");
+        let early = outbox.try_reserve(&first).unwrap();
+        let late = outbox.try_reserve(&second).unwrap();
+        // reserved room counts until it is given back
+        assert!(outbox.try_reserve(&Message::new("carol", "x")).is_err());
+        assert_eq!(inbox.len(), 0);
+
+        late.send(Arc::new(second)).unwrap();
+        early.send(Arc::new(first)).unwrap();
+        assert_eq!(inbox.try_recv().unwrap().content, "second");
+        assert_eq!(inbox.try_recv().unwrap().content, "first");
+
+        let unused = outbox.try_reserve(&Message::new("carol", "x")).unwrap();
+        drop(unused);
+        outbox
+            .try_send(Arc::new(Message::new("dave", "y")))
+            .unwrap();
+        outbox
+            .try_send(Arc::new(Message::new("erin", "z")))
+            .unwrap();
+        assert_eq!(inbox.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_replayed_messages_go_first() {
+        let (outbox, mut inbox) = channel(1);
+        let notice = Message::server("bob has joined the chat.");
+        outbox.send(Arc::new(notice)).await.unwrap();
+        outbox
+            .send(Arc::new(Message::new("alice", "new")))
+            .await
+            .unwrap();
+        inbox.replay((0..3).map(|i| Arc::new(Message::new("alice", i.to_string()))));
+        assert_eq!(inbox.len(), 5);
+        drop(outbox);
+
+        let mut received = Vec::new();
+        while let Some(message) = inbox.recv().await {
+            received.push(message.content.clone());
+        }
+        assert_eq!(received, ["0", "1", "2", "bob has joined the chat.", "new"]);
+    }
+
+    #[test]
+    fn test_quiet_outbox_only_wants_notices() {
+        let (outbox, _inbox) = channel(1);
+        let chat = Message::new("alice", "hi");
+        let notice = Message::server("alice has left the chat.");
+        assert!(outbox.wants(&chat));
+        outbox.clone().set_quiet(true);
+        assert!(!outbox.wants(&chat));
+        assert!(outbox.wants(&notice));
+    }
+
+    #[tokio::test]
+    async fn test_drops_are_counted() {
+        let (outbox, inbox) = channel(1);
+        assert_eq!(outbox.record_drop(), 1);
+        assert_eq!(outbox.clone().record_drop(), 2);
+        assert_eq!(outbox.dropped(), 2);
+        assert_eq!(inbox.take_missed(), 2);
+        assert_eq!(inbox.take_missed(), 0);
+        // the total stays
+        assert_eq!(outbox.dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_closed_inbox_refuses_messages() {
+        let (outbox, inbox) = channel(1);
+        drop(inbox);
+        let message = Arc::new(Message::new("alice", "hi"));
+        assert!(matches!(
+            outbox.try_send(message.clone()),
+            Err(TrySendError::Closed(_))
+        ));
+        assert!(outbox.send(message).await.is_err());
+    }
+}
@@ -0,0 +1,351 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Result};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use flate2::{write::GzEncoder, Compression};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::Message;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PersistenceConfig {
+    /// File chat messages are appended to, one JSON object per line.
+    pub path: PathBuf,
+    /// Roll the log once it grows past this size.
+    #[serde(default = "default_max_file_bytes")]
+    pub max_file_bytes: u64,
+    /// Number of rolled segments kept next to the live log.
+    #[serde(default = "default_keep_files")]
+    pub keep_files: usize,
+    /// Gzip rolled segments.
+    #[serde(default)]
+    pub compress: bool,
+    /// Hex encoded 32 byte key; each line is encrypted with
+    /// ChaCha20-Poly1305 when set.
+    #[serde(default)]
+    pub encryption_key: Option<String>,
+    /// File holding the hex encoded key, to keep it out of the config.
+    #[serde(default)]
+    pub encryption_key_file: Option<PathBuf>,
+}
+
+impl PersistenceConfig {
+    /// The cipher for the configured key, if encryption is on.
+    pub fn cipher(&self) -> Result<Option<LogCipher>> {
+        match (&self.encryption_key, &self.encryption_key_file) {
+            (Some(_), Some(_)) => bail!("set only one of encryption_key and encryption_key_file"),
+            (Some(key), None) => LogCipher::from_hex(key).map(Some),
+            (None, Some(path)) => LogCipher::from_hex(fs::read_to_string(path)?.trim()).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Seals log lines, each under a fresh random nonce that is stored with it.
+#[derive(Clone)]
+pub struct LogCipher(ChaCha20Poly1305);
+
+impl fmt::Debug for LogCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LogCipher(..)")
+    }
+}
+
+/// An encrypted log line.
+#[derive(Debug, Deserialize, Serialize)]
+struct Sealed {
+    nonce: String,
+    ciphertext: String,
+}
+
+impl LogCipher {
+    pub fn from_hex(key: &str) -> Result<Self> {
+        let key = hex::decode(key)?;
+        if key.len() != 32 {
+            bail!("encryption key must be 32 bytes, got {}", key.len());
+        }
+        Ok(Self(ChaCha20Poly1305::new(Key::from_slice(&key))))
+    }
+
+    pub(crate) fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow!("failed to encrypt log line"))?;
+        Ok(serde_json::to_vec(&Sealed {
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })?)
+    }
+
+    pub(crate) fn open(&self, line: &str) -> Result<Vec<u8>> {
+        let sealed: Sealed = serde_json::from_str(line)?;
+        let nonce = hex::decode(sealed.nonce)?;
+        if nonce.len() != 12 {
+            bail!("bad nonce length {}", nonce.len());
+        }
+        self.0
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                &hex::decode(sealed.ciphertext)?[..],
+            )
+            .map_err(|_| anyhow!("failed to decrypt log line; wrong key?"))
+    }
+}
+
+fn default_max_file_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_keep_files() -> usize {
+    5
+}
+
+/// An append-only message log that rotates itself when it gets too big.
+#[derive(Debug)]
+pub struct MessageLog {
+    config: PersistenceConfig,
+    cipher: Option<LogCipher>,
+    file: File,
+    size: u64,
+}
+
+impl MessageLog {
+    pub fn open(config: PersistenceConfig) -> Result<Self> {
+        let cipher = config.cipher()?;
+        let file = open_append(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            config,
+            cipher,
+            file,
+            size,
+        })
+    }
+
+    pub fn append(&mut self, message: &Message) -> Result<()> {
+        let mut line = serde_json::to_vec(message)?;
+        if let Some(cipher) = &self.cipher {
+            line = cipher.seal(&line)?;
+        }
+        line.push(b'\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_file_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shifts `log.1` to `log.2` and so on, dropping the oldest segment, then
+    /// moves the live log to `log.1`.
+    fn rotate(&mut self) -> Result<()> {
+        self.file.flush()?;
+        let keep = self.config.keep_files;
+
+        if keep == 0 {
+            self.file = File::create(&self.config.path)?;
+            self.size = 0;
+            return Ok(());
+        }
+
+        remove_if_exists(&self.segment(keep))?;
+        for n in (1..keep).rev() {
+            let from = self.segment(n);
+            if from.exists() {
+                fs::rename(&from, self.segment(n + 1))?;
+            }
+        }
+
+        if self.config.compress {
+            let mut reader = File::open(&self.config.path)?;
+            let mut encoder = GzEncoder::new(
+                BufWriter::new(File::create(self.segment(1))?),
+                Compression::default(),
+            );
+            io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?.flush()?;
+            fs::remove_file(&self.config.path)?;
+        } else {
+            fs::rename(&self.config.path, self.segment(1))?;
+        }
+
+        self.file = open_append(&self.config.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn segment(&self, n: usize) -> PathBuf {
+        let mut name = self.config.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        if self.config.compress {
+            name.push(".gz");
+        }
+        name.into()
+    }
+}
+
+/// Reads back a plain, uncompressed log of JSON lines, decrypting it with
+/// `cipher` if it was written encrypted.
+pub fn read_log<T: DeserializeOwned>(path: &Path, cipher: Option<&LogCipher>) -> Result<Vec<T>> {
+    let mut records = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let record = match cipher {
+            Some(cipher) => serde_json::from_slice(&cipher.open(&line)?)?,
+            None => serde_json::from_str(&line)?,
+        };
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Starts a blocking writer for the log and returns the queue feeding it.
+pub fn spawn(config: PersistenceConfig) -> Result<mpsc::Sender<Arc<Message>>> {
+    let mut log = MessageLog::open(config)?;
+    let (tx, mut rx) = mpsc::channel::<Arc<Message>>(1024);
+
+    tokio::task::spawn_blocking(move || {
+        while let Some(message) = rx.blocking_recv() {
+            if let Err(e) = log.append(&message) {
+                warn!("Failed to persist message: {:?}", e);
+            }
+        }
+    });
+
+    Ok(tx)
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    fn config(dir: &Path, compress: bool) -> PersistenceConfig {
+        PersistenceConfig {
+            path: dir.join("messages.log"),
+            max_file_bytes: 100,
+            keep_files: 2,
+            compress,
+            encryption_key: None,
+            encryption_key_file: None,
+        }
+    }
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn test_encrypted_log_round_trips() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = PersistenceConfig {
+            max_file_bytes: 1024,
+            encryption_key: Some(KEY.to_string()),
+            ..config(dir.path(), false)
+        };
+        let cipher = config.cipher()?.unwrap();
+        let mut log = MessageLog::open(config)?;
+        log.append(&Message::new("alice", "the launch code is 1234"))?;
+        log.append(&Message::new("bob", "the launch code is 1234"))?;
+
+        let raw = fs::read_to_string(dir.path().join("messages.log"))?;
+        assert!(!raw.contains("launch code"));
+        assert!(!raw.contains("alice"));
+        // fresh nonces make equal messages encrypt differently
+        let lines: Vec<&str> = raw.lines().collect();
+        assert_ne!(lines[0], lines[1]);
+
+        let messages: Vec<Message> = read_log(&dir.path().join("messages.log"), Some(&cipher))?;
+        let contents: Vec<_> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["the launch code is 1234"; 2]);
+        assert_eq!(messages[0].sender, "alice");
+
+        let wrong = LogCipher::from_hex(&KEY.replace("00", "ff"))?;
+        assert!(read_log::<Message>(&dir.path().join("messages.log"), Some(&wrong)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_encryption_key_from_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let key_file = dir.path().join("log.key");
+        fs::write(&key_file, format!("{}\n", KEY))?;
+        let config = PersistenceConfig {
+            encryption_key_file: Some(key_file),
+            ..config(dir.path(), false)
+        };
+        assert!(config.cipher()?.is_some());
+
+        let both = PersistenceConfig {
+            encryption_key: Some(KEY.to_string()),
+            ..config.clone()
+        };
+        assert!(both.cipher().is_err());
+        let short = PersistenceConfig {
+            encryption_key: Some("abcd".to_string()),
+            encryption_key_file: None,
+            ..config
+        };
+        assert!(short.cipher().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_rotates_past_threshold() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut log = MessageLog::open(config(dir.path(), false))?;
+        for i in 0..3 {
+            log.append(&Message::new("alice", format!("message number {}", i)))?;
+        }
+
+        let rolled = fs::read_to_string(dir.path().join("messages.log.1"))?;
+        assert!(rolled.contains("message number 0"));
+        let live = fs::read_to_string(dir.path().join("messages.log"))?;
+        assert!(live.contains("message number 2"));
+        assert!(!live.contains("message number 0"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rolled_segments_are_compressed_and_bounded() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut log = MessageLog::open(config(dir.path(), true))?;
+        for i in 0..10 {
+            log.append(&Message::new("alice", format!("message number {}", i)))?;
+        }
+
+        let mut rolled = String::new();
+        GzDecoder::new(File::open(dir.path().join("messages.log.1.gz"))?)
+            .read_to_string(&mut rolled)?;
+        assert!(rolled.contains("message number 6"));
+        assert!(dir.path().join("messages.log.2.gz").exists());
+        assert!(!dir.path().join("messages.log.3.gz").exists());
+        Ok(())
+    }
+}
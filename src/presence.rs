@@ -0,0 +1,135 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use tokio::time::{self, Instant};
+
+use crate::{Message, State};
+
+/// Leave notices held back for `presence_cooldown_secs`, by username, so a
+/// user who drops and comes straight back doesn't flood everyone with
+/// join and leave notices.
+#[derive(Debug, Default)]
+pub struct PendingLeaves {
+    leaves: DashMap<String, PendingLeave>,
+}
+
+#[derive(Debug)]
+struct PendingLeave {
+    nick: String,
+    addr: SocketAddr,
+    left_at: Instant,
+}
+
+impl State {
+    fn presence_cooldown(&self) -> Option<Duration> {
+        let secs = self.server.presence_cooldown_secs;
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+
+    /// Holds back the notice that a user left, returning false if it should
+    /// go out right away instead.
+    pub(crate) fn hold_leave(&self, username: &str, nick: &str, addr: SocketAddr) -> bool {
+        if self.presence_cooldown().is_none() {
+            return false;
+        }
+        self.pending_leaves.leaves.insert(
+            username.to_string(),
+            PendingLeave {
+                nick: nick.to_string(),
+                addr,
+                left_at: Instant::now(),
+            },
+        );
+        true
+    }
+
+    /// Drops the held back leave notice of a user who is back, returning
+    /// whether there was one; their join notice goes with it.
+    pub(crate) fn take_rejoin(&self, username: &str) -> bool {
+        self.pending_leaves.leaves.remove(username).is_some()
+    }
+
+    /// Announces a held back leave once the cooldown is over, unless the
+    /// user came back in the meantime.
+    pub(crate) fn settle_leave(self: &Arc<Self>, username: &str) {
+        let Some(cooldown) = self.presence_cooldown() else {
+            return;
+        };
+        let Some(left_at) = self
+            .pending_leaves
+            .leaves
+            .get(username)
+            .map(|pending| pending.left_at)
+        else {
+            return;
+        };
+        let state = self.clone();
+        let username = username.to_string();
+        tokio::spawn(async move {
+            time::sleep_until(left_at + cooldown).await;
+            // a later leave has its own timer
+            let Some((_, pending)) = state
+                .pending_leaves
+                .leaves
+                .remove_if(&username, |_, pending| pending.left_at == left_at)
+            else {
+                return;
+            };
+            let notice = format!("{} has left the chat.", pending.nick);
+            state
+                .broadcast(pending.addr, Arc::new(Message::server(notice)))
+                .await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::test_support::{spawn_state, TestClient};
+
+    /// Waits until the server has noticed that the user is gone.
+    async fn wait_gone(state: &State, username: &str) {
+        while state.find_peer(username).is_some() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rapid_rejoin_is_collapsed() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig {
+            presence_cooldown_secs: 1,
+            ..Default::default()
+        })?);
+        let addr = spawn_state(state.clone()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let bob = TestClient::connect(addr, "bob").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: bob has joined the chat."
+        );
+
+        // bob's connection flaps a few times
+        drop(bob);
+        for _ in 0..3 {
+            wait_gone(&state, "bob").await;
+            let bob = TestClient::connect(addr, "bob").await?;
+            drop(bob);
+        }
+        wait_gone(&state, "bob").await;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        assert_eq!(alice.try_recv().await?, None);
+        bob.send_line("sorry, bad wifi").await?;
+        assert_eq!(alice.expect_line().await?, "bob: sorry, bad wifi");
+
+        // leaving for good is announced once the cooldown is over
+        drop(bob);
+        assert_eq!(alice.try_recv().await?, None);
+        assert_eq!(alice.expect_line().await?, "Server: bob has left the chat.");
+        assert_eq!(alice.try_recv().await?, None);
+        Ok(())
+    }
+}
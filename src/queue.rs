@@ -0,0 +1,131 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time,
+};
+use tokio_util::codec::Framed;
+
+use crate::codec::ChatCodec;
+
+/// How often a waiting connection is told its place in the queue.
+const POSITION_UPDATE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Limits concurrent connections, holding a bounded number of extra ones in
+/// line until a slot frees up.
+#[derive(Debug)]
+pub struct ConnectionQueue {
+    slots: Arc<Semaphore>,
+    waiting: Mutex<VecDeque<u64>>,
+    next_ticket: AtomicU64,
+    max_queue: usize,
+}
+
+/// How waiting for a connection slot ended.
+#[derive(Debug)]
+pub enum Admission {
+    Admitted(OwnedSemaphorePermit),
+    /// The queue was full too.
+    QueueFull,
+    /// The client hung up while waiting.
+    Left,
+}
+
+/// Removes a ticket from the line however its owner stops waiting.
+struct Ticket<'a> {
+    queue: &'a ConnectionQueue,
+    id: u64,
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        self.queue
+            .waiting
+            .lock()
+            .unwrap()
+            .retain(|id| *id != self.id);
+    }
+}
+
+impl ConnectionQueue {
+    pub fn new(max_connections: usize, max_queue: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max_connections)),
+            waiting: Mutex::new(VecDeque::new()),
+            next_ticket: AtomicU64::new(0),
+            max_queue,
+        }
+    }
+
+    /// Waits for a free slot, keeping the client posted on its position.
+    /// Clients that hang up give up their place at once; anything they send
+    /// while waiting is ignored.
+    pub async fn admit<S>(&self, framed: &mut Framed<S, ChatCodec>) -> Result<Admission>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Ok(Admission::Admitted(permit));
+        }
+        let ticket = {
+            let mut waiting = self.waiting.lock().unwrap();
+            if waiting.len() >= self.max_queue {
+                return Ok(Admission::QueueFull);
+            }
+            let id = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+            waiting.push_back(id);
+            Ticket { queue: self, id }
+        };
+
+        let acquire = self.slots.clone().acquire_owned();
+        tokio::pin!(acquire);
+        let mut updates = time::interval(POSITION_UPDATE_INTERVAL);
+        let mut last_position = None;
+        loop {
+            tokio::select! {
+                permit = &mut acquire => return Ok(Admission::Admitted(permit?)),
+                line = framed.next() => match line {
+                    Some(Ok(_)) => {
+                        let position = self.position(ticket.id);
+                        framed
+                            .send(format!(
+                                "You are in queue, position {}; wait for the username prompt.",
+                                position
+                            ))
+                            .await?;
+                    }
+                    _ => return Ok(Admission::Left),
+                },
+                _ = updates.tick() => {
+                    let position = self.position(ticket.id);
+                    if last_position != Some(position) {
+                        framed
+                            .send(format!("You are in queue, position {}.", position))
+                            .await?;
+                        last_position = Some(position);
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn waiting(&self) -> usize {
+        self.waiting.lock().unwrap().len()
+    }
+
+    fn position(&self, ticket: u64) -> usize {
+        let waiting = self.waiting.lock().unwrap();
+        waiting.iter().position(|id| *id == ticket).unwrap_or(0) + 1
+    }
+}
@@ -0,0 +1,206 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tracing::{info, warn};
+
+use crate::challenge::RecentConnects;
+use crate::{Message, State};
+
+/// How often raid mode checks whether the raid is over.
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Raid mode: while connections arrive faster than `max_connects` per
+/// `window_secs`, every new connection gets the challenge and every channel
+/// is in slow mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RaidConfig {
+    pub max_connects: usize,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// How long the rate has to stay at or under the threshold before raid
+    /// mode ends.
+    #[serde(default = "default_calm_secs")]
+    pub calm_secs: u64,
+    /// Slow mode interval for every channel during a raid; 0 leaves slow
+    /// mode alone.
+    #[serde(default = "default_slowmode_secs")]
+    pub slowmode_secs: u64,
+}
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+fn default_calm_secs() -> u64 {
+    120
+}
+
+fn default_slowmode_secs() -> u64 {
+    10
+}
+
+/// Whether the server is in raid mode, and what tells it when to be.
+#[derive(Debug, Default)]
+pub struct RaidMode {
+    arrivals: RecentConnects,
+    active: AtomicBool,
+    /// Since when the rate has been back under the threshold.
+    calm_since: Mutex<Option<Instant>>,
+}
+
+impl RaidMode {
+    pub fn new(config: Option<&RaidConfig>) -> Self {
+        match config {
+            Some(config) => Self {
+                arrivals: RecentConnects::new(Duration::from_secs(config.window_secs)),
+                ..Default::default()
+            },
+            None => Self::default(),
+        }
+    }
+}
+
+impl State {
+    pub(crate) fn raid_active(&self) -> bool {
+        self.raid.active.load(Ordering::Relaxed)
+    }
+
+    /// Slow mode every channel is held to right now, if any.
+    pub(crate) fn raid_slowmode(&self) -> Option<Duration> {
+        let config = self.server.raid?;
+        (self.raid_active() && config.slowmode_secs > 0)
+            .then(|| Duration::from_secs(config.slowmode_secs))
+    }
+
+    /// Counts a new connection, starting raid mode if that makes too many.
+    pub(crate) async fn note_connection(&self) {
+        let Some(config) = self.server.raid else {
+            return;
+        };
+        let recent = self.raid.arrivals.record(Instant::now());
+        if recent <= config.max_connects {
+            return;
+        }
+        *self.raid.calm_since.lock().unwrap() = None;
+        if self.raid.active.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        warn!(
+            "Raid mode on: {} connections in the last {}s",
+            recent, config.window_secs
+        );
+        let mut notice = "Raid mode is on: new connections have to pass a check".to_string();
+        if config.slowmode_secs > 0 {
+            notice.push_str(&format!(
+                ", and everyone can send one message every {}s",
+                config.slowmode_secs
+            ));
+        }
+        notice.push('.');
+        self.broadcast_all(Arc::new(Message::server(notice))).await;
+    }
+
+    /// Ends raid mode once connections have slowed down for long enough.
+    async fn check_raid(&self, config: RaidConfig) {
+        if !self.raid_active() {
+            return;
+        }
+        let now = Instant::now();
+        let calm = Duration::from_secs(config.calm_secs);
+        let over = {
+            let mut calm_since = self.raid.calm_since.lock().unwrap();
+            if self.raid.arrivals.count(now) > config.max_connects {
+                *calm_since = None;
+                false
+            } else {
+                now.duration_since(*calm_since.get_or_insert(now)) >= calm
+            }
+        };
+        if over && self.raid.active.swap(false, Ordering::Relaxed) {
+            *self.raid.calm_since.lock().unwrap() = None;
+            info!("Raid mode off");
+            self.broadcast_all(Arc::new(Message::server("Raid mode is off.")))
+                .await;
+        }
+    }
+}
+
+/// Watches for the end of raids. Returns `None` when raid mode is off.
+pub fn spawn_raid_monitor(state: Arc<State>) -> Option<JoinHandle<()>> {
+    let config = state.server.raid?;
+    Some(tokio::spawn(async move {
+        let mut checks = time::interval(CHECK_INTERVAL);
+        checks.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            checks.tick().await;
+            state.check_raid(config).await;
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::test_support::{spawn_state, TestClient};
+
+    #[tokio::test]
+    async fn test_connection_spike_starts_and_ends_raid_mode() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig {
+            raid: Some(RaidConfig {
+                max_connects: 3,
+                window_secs: 1,
+                calm_secs: 1,
+                slowmode_secs: 30,
+            }),
+            ..Default::default()
+        })?);
+        let addr = spawn_state(state.clone()).await?;
+        spawn_raid_monitor(state.clone());
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        alice.send_line("/stats").await?;
+        assert!(alice.expect_line().await?.ends_with(", raid mode: off"));
+
+        let mut raiders = Vec::new();
+        for _ in 0..3 {
+            raiders.push(TestClient::connect_raw(addr).await?);
+        }
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Raid mode is on: new connections have to pass a check, \
+             and everyone can send one message every 30s."
+        );
+        let question = raiders.last_mut().unwrap().expect_line().await?;
+        assert!(question.starts_with("Anti-bot check: "), "{}", question);
+        alice.send_line("/stats").await?;
+        assert!(alice.expect_line().await?.ends_with(", raid mode: on"));
+        alice.send_line("first").await?;
+        alice.send_line("second").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: Slow mode is on in #general. Wait 30s before sending again."
+        );
+
+        // the window empties, and a second later the raid is over
+        let started = Instant::now();
+        assert_eq!(alice.expect_line().await?, "Server: Raid mode is off.");
+        assert!(started.elapsed() >= Duration::from_millis(900));
+        alice.send_line("third").await?;
+        alice.send_line("/stats").await?;
+        assert!(alice.expect_line().await?.ends_with(", raid mode: off"));
+        Ok(())
+    }
+}
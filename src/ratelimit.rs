@@ -0,0 +1,214 @@
+use std::time::{Duration, Instant};
+
+use tokio::time;
+
+use crate::config::RateLimit;
+
+/// Which of a rate limit's budgets a line would have overdrawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limited {
+    Lines,
+    Bytes,
+    /// The line is bigger than the whole byte burst, so waiting won't help.
+    TooLarge,
+}
+
+impl Limited {
+    pub fn reason(self) -> &'static str {
+        match self {
+            Limited::Lines => "You are sending messages too fast.",
+            Limited::Bytes => "You are sending too much text too fast.",
+            Limited::TooLarge => "That message is too large for the rate limit.",
+        }
+    }
+}
+
+/// Per-peer token buckets, one counting lines and one counting their bytes.
+/// The limit is passed on every check so a reloaded limit applies to
+/// connected peers right away.
+#[derive(Debug)]
+pub struct TokenBucket {
+    tokens: Option<f64>,
+    bytes: Option<f64>,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new() -> Self {
+        Self {
+            tokens: None,
+            bytes: None,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes a line of `len` bytes if both budgets allow it. A refused line
+    /// takes nothing from either.
+    pub fn try_take(&mut self, limit: &RateLimit, len: usize) -> Result<(), Limited> {
+        self.try_take_at(limit, len, Instant::now())
+    }
+
+    fn try_take_at(&mut self, limit: &RateLimit, len: usize, now: Instant) -> Result<(), Limited> {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        let refill = |level: Option<f64>, rate: f64, burst: f64| match level {
+            Some(level) => (level + elapsed * rate).min(burst),
+            None => burst,
+        };
+
+        let tokens = refill(self.tokens, limit.messages_per_sec, f64::from(limit.burst));
+        self.tokens = Some(tokens);
+        let burst_bytes = (limit.bytes_per_sec > 0.0).then(|| match limit.burst_bytes {
+            0 => limit.bytes_per_sec,
+            burst => f64::from(burst),
+        });
+        let bytes = burst_bytes.map(|burst| refill(self.bytes, limit.bytes_per_sec, burst));
+        self.bytes = bytes;
+
+        let len = len as f64;
+        if burst_bytes.is_some_and(|burst| burst < len) {
+            return Err(Limited::TooLarge);
+        }
+        if tokens < 1.0 {
+            return Err(Limited::Lines);
+        }
+        if bytes.is_some_and(|bytes| bytes < len) {
+            return Err(Limited::Bytes);
+        }
+        self.tokens = Some(tokens - 1.0);
+        self.bytes = bytes.map(|bytes| bytes - len);
+        Ok(())
+    }
+}
+
+/// Paces a peer's outbound bytes, allowing up to a second's worth at once.
+#[derive(Debug)]
+pub struct ByteRate {
+    bytes_per_sec: f64,
+    allowance: f64,
+    last: time::Instant,
+}
+
+impl ByteRate {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec as f64;
+        Self {
+            bytes_per_sec,
+            allowance: bytes_per_sec,
+            last: time::Instant::now(),
+        }
+    }
+
+    /// Accounts for `bytes` just written and returns how long to pause
+    /// before writing again.
+    pub fn record(&mut self, bytes: u64) -> Duration {
+        self.record_at(bytes, time::Instant::now())
+    }
+
+    fn record_at(&mut self, bytes: u64, now: time::Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.allowance =
+            (self.allowance + elapsed * self.bytes_per_sec).min(self.bytes_per_sec) - bytes as f64;
+        if self.allowance < 0.0 {
+            Duration::from_secs_f64(-self.allowance / self.bytes_per_sec)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_allows_burst_then_refills() {
+        let limit = RateLimit {
+            messages_per_sec: 2.0,
+            burst: 2,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new();
+        assert!(bucket.try_take_at(&limit, 5, start).is_ok());
+        assert!(bucket.try_take_at(&limit, 5, start).is_ok());
+        assert_eq!(bucket.try_take_at(&limit, 5, start), Err(Limited::Lines));
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_take_at(&limit, 5, later).is_ok());
+        assert_eq!(bucket.try_take_at(&limit, 5, later), Err(Limited::Lines));
+    }
+
+    #[test]
+    fn test_large_lines_hit_the_byte_budget() {
+        let limit = RateLimit {
+            messages_per_sec: 10.0,
+            burst: 10,
+            bytes_per_sec: 1000.0,
+            burst_bytes: 0,
+        };
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new();
+        assert!(bucket.try_take_at(&limit, 600, start).is_ok());
+        assert_eq!(bucket.try_take_at(&limit, 600, start), Err(Limited::Bytes));
+        // the refused line cost nothing, so a short one still fits
+        assert!(bucket.try_take_at(&limit, 400, start).is_ok());
+        assert_eq!(bucket.try_take_at(&limit, 1, start), Err(Limited::Bytes));
+        let later = start + Duration::from_millis(600);
+        assert!(bucket.try_take_at(&limit, 600, later).is_ok());
+    }
+
+    #[test]
+    fn test_lines_bigger_than_the_byte_burst_are_too_large() {
+        let limit = RateLimit {
+            messages_per_sec: 10.0,
+            burst: 10,
+            bytes_per_sec: 100.0,
+            burst_bytes: 500,
+        };
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new();
+        assert_eq!(
+            bucket.try_take_at(&limit, 501, start),
+            Err(Limited::TooLarge)
+        );
+        // no amount of waiting lets it through
+        let much_later = start + Duration::from_secs(60);
+        assert_eq!(
+            bucket.try_take_at(&limit, 501, much_later),
+            Err(Limited::TooLarge)
+        );
+        assert!(bucket.try_take_at(&limit, 500, much_later).is_ok());
+    }
+
+    #[test]
+    fn test_bucket_follows_new_limit() {
+        let strict = RateLimit {
+            messages_per_sec: 0.0,
+            burst: 1,
+            ..Default::default()
+        };
+        let loose = RateLimit {
+            messages_per_sec: 100.0,
+            burst: 10,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new();
+        assert!(bucket.try_take_at(&strict, 5, start).is_ok());
+        assert!(bucket.try_take_at(&strict, 5, start).is_err());
+        let later = start + Duration::from_millis(100);
+        assert!(bucket.try_take_at(&loose, 5, later).is_ok());
+    }
+
+    #[test]
+    fn test_byte_rate_pauses_over_the_cap() {
+        let start = time::Instant::now();
+        let mut rate = ByteRate::new(100);
+        assert_eq!(rate.record_at(100, start), Duration::ZERO);
+        assert_eq!(rate.record_at(50, start), Duration::from_millis(500));
+        // the pause pays off the debt, so the next write starts from zero
+        let resumed = start + Duration::from_millis(500);
+        assert_eq!(rate.record_at(100, resumed), Duration::from_secs(1));
+    }
+}
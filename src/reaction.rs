@@ -0,0 +1,188 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+use crate::attachment::Attachment;
+use crate::{Message, State};
+
+const MAX_EMOJI_LEN: usize = 32;
+
+/// A structured request from a client in JSON mode.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ClientFrame {
+    React {
+        target: u64,
+        emoji: String,
+    },
+    /// A chat message, which unlike a plain line can carry attachments.
+    Message {
+        content: String,
+        #[serde(default)]
+        attachments: Vec<Attachment>,
+    },
+}
+
+#[derive(Debug, Default)]
+struct Tally {
+    channel: String,
+    /// Who reacted with each emoji; a user counts once per emoji.
+    emoji: BTreeMap<String, HashSet<String>>,
+}
+
+/// Reactions to the most recent channel messages, keyed by message id.
+#[derive(Debug)]
+pub struct Reactions {
+    inner: Mutex<(VecDeque<u64>, HashMap<u64, Tally>)>,
+    capacity: usize,
+}
+
+impl Reactions {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new((VecDeque::new(), HashMap::new())),
+            capacity,
+        }
+    }
+
+    /// Starts tracking a message, forgetting the oldest one when full.
+    pub fn track(&self, id: u64, channel: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        let (order, tallies) = &mut *self.inner.lock().unwrap();
+        if order.len() == self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                tallies.remove(&oldest);
+            }
+        }
+        order.push_back(id);
+        tallies.insert(
+            id,
+            Tally {
+                channel: channel.to_string(),
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Returns the channel of a tracked message.
+    pub fn channel(&self, id: u64) -> Option<String> {
+        let (_, tallies) = &*self.inner.lock().unwrap();
+        tallies.get(&id).map(|tally| tally.channel.clone())
+    }
+
+    /// Records a reaction and returns the updated counts, or `None` when the
+    /// message is no longer tracked.
+    pub fn react(&self, id: u64, emoji: &str, user: &str) -> Option<BTreeMap<String, usize>> {
+        let (_, tallies) = &mut *self.inner.lock().unwrap();
+        let tally = tallies.get_mut(&id)?;
+        tally
+            .emoji
+            .entry(emoji.to_string())
+            .or_default()
+            .insert(user.to_string());
+        Some(
+            tally
+                .emoji
+                .iter()
+                .map(|(emoji, users)| (emoji.clone(), users.len()))
+                .collect(),
+        )
+    }
+}
+
+impl State {
+    pub(crate) async fn handle_frame(&self, addr: SocketAddr, frame: ClientFrame) {
+        match frame {
+            ClientFrame::React { target, emoji } => {
+                if let Err(e) = self.react(addr, target, &emoji).await {
+                    self.notify(addr, Message::server(e.to_string())).await;
+                }
+            }
+            ClientFrame::Message {
+                content,
+                attachments,
+            } => self.say_with_attachments(addr, &content, attachments).await,
+        }
+    }
+
+    /// Adds a reaction to a recent message and sends the new counts to its
+    /// channel.
+    async fn react(&self, addr: SocketAddr, target: u64, emoji: &str) -> Result<()> {
+        if emoji.is_empty() || emoji.len() > MAX_EMOJI_LEN || emoji.contains(char::is_whitespace) {
+            bail!("Invalid reaction.");
+        }
+        let Some(channel) = self.reactions.channel(target) else {
+            bail!("No such message: {}", target);
+        };
+        let username = match self.peers.get(&addr) {
+            Some(peer) if peer.channels.contains(&channel) => peer.username.clone(),
+            _ => bail!("You must be in #{} to react there.", channel),
+        };
+        let Some(counts) = self.reactions.react(target, emoji, &username) else {
+            bail!("No such message: {}", target);
+        };
+
+        let summary: Vec<String> = counts
+            .iter()
+            .map(|(emoji, count)| format!("{} {}", emoji, count))
+            .collect();
+        let update = Arc::new(Message {
+            id: Some(target),
+            reactions: Some(counts),
+            ..Message::server(format!(
+                "Reactions on message {}: {}",
+                target,
+                summary.join(", ")
+            ))
+            .in_channel(&channel)
+        });
+        self.broadcast_channel(&channel, addr, update.clone()).await;
+        self.notify(addr, (*update).clone()).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reactions_count_users_once() {
+        let reactions = Reactions::new(10);
+        reactions.track(1, "general");
+        reactions.react(1, "👍", "alice");
+        reactions.react(1, "👍", "alice");
+        let counts = reactions.react(1, "👍", "bob").unwrap();
+        assert_eq!(counts, BTreeMap::from([("👍".to_string(), 2)]));
+    }
+
+    #[test]
+    fn test_reactions_are_bounded() {
+        let reactions = Reactions::new(2);
+        for id in 1..=3 {
+            reactions.track(id, "general");
+        }
+        assert!(reactions.react(1, "👍", "alice").is_none());
+        assert!(reactions.react(3, "👍", "alice").is_some());
+    }
+
+    #[test]
+    fn test_parse_react_frame() {
+        let frame: ClientFrame =
+            serde_json::from_str(r#"{"type":"react","target":7,"emoji":"👍"}"#).unwrap();
+        assert_eq!(
+            frame,
+            ClientFrame::React {
+                target: 7,
+                emoji: "👍".to_string()
+            }
+        );
+    }
+}
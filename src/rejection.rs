@@ -0,0 +1,83 @@
+use std::fmt;
+
+/// Why a connection was turned away before the peer could chat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    Banned,
+    ServerFull,
+    InvalidUsername,
+    ReservedUsername,
+    UsernameTaken,
+    InvalidResumeToken,
+    UnsupportedClient,
+    TermsNotAccepted,
+    ChallengeFailed,
+    Draining,
+}
+
+impl Rejection {
+    /// A stable identifier clients can match on.
+    pub fn code(self) -> &'static str {
+        match self {
+            Rejection::Banned => "banned",
+            Rejection::ServerFull => "server_full",
+            Rejection::InvalidUsername => "invalid_username",
+            Rejection::ReservedUsername => "reserved_username",
+            Rejection::UsernameTaken => "username_taken",
+            Rejection::InvalidResumeToken => "invalid_resume_token",
+            Rejection::UnsupportedClient => "unsupported_client",
+            Rejection::TermsNotAccepted => "terms_not_accepted",
+            Rejection::ChallengeFailed => "challenge_failed",
+            Rejection::Draining => "draining",
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            Rejection::Banned => "You are banned from this server.",
+            Rejection::ServerFull => "Server is full, try again later.",
+            Rejection::InvalidUsername => "Usernames must be 1 to 32 characters without spaces.",
+            Rejection::ReservedUsername => "That username is reserved.",
+            Rejection::UsernameTaken => "That username is already taken.",
+            Rejection::InvalidResumeToken => "Invalid or expired resume token.",
+            Rejection::UnsupportedClient => "Unsupported client: expected lines of UTF-8 text.",
+            Rejection::TermsNotAccepted => "Timed out waiting for /accept.",
+            Rejection::ChallengeFailed => "Wrong or no answer to the anti-bot check.",
+            Rejection::Draining => "The server is draining for maintenance.",
+        }
+    }
+
+    /// Fills in `{code}`, `{reason}` and `{contact}` in the template.
+    pub fn render(self, template: &str, contact: Option<&str>) -> String {
+        template
+            .replace("{code}", self.code())
+            .replace("{reason}", self.reason())
+            .replace("{contact}", contact.unwrap_or(""))
+            .trim_end()
+            .to_string()
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        let template = "Refused [{code}] {reason} {contact}";
+        assert_eq!(
+            Rejection::Banned.render(template, Some("ops@example.com")),
+            "Refused [banned] You are banned from this server. ops@example.com"
+        );
+        assert_eq!(
+            Rejection::ServerFull.render(template, None),
+            "Refused [server_full] Server is full, try again later."
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    task::JoinHandle,
+};
+use tracing::{info, warn};
+
+use crate::config::{ServerConfig, Settings};
+use crate::State;
+
+impl State {
+    /// Swaps in the reloadable part of a freshly loaded config.
+    pub(crate) fn reload(&self, config: &ServerConfig) {
+        match self.server.restart_required(config) {
+            Ok(changed) if !changed.is_empty() => {
+                warn!("Changing {} requires a restart", changed.join(", "))
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to compare configs: {:?}", e),
+        }
+        *self.settings.write().unwrap() = Arc::new(Settings::from(config));
+        info!("Reloaded config");
+    }
+}
+
+/// Reloads the config with `load` every time the process gets SIGHUP.
+pub fn spawn_sighup_handler(
+    state: Arc<State>,
+    load: impl Fn() -> Result<ServerConfig> + Send + 'static,
+) -> Result<JoinHandle<()>> {
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    Ok(tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match load() {
+                Ok(config) => state.reload(&config),
+                Err(e) => warn!("Failed to reload config: {:?}", e),
+            }
+        }
+    }))
+}
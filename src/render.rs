@@ -0,0 +1,155 @@
+use std::{
+    borrow::Cow,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::Protocol;
+use crate::signing::{MessageSigner, SignedMessage};
+use crate::wrap::wrap;
+use crate::Message;
+
+/// Formats messages for peers using the text protocol.
+pub trait MessageRenderer: Send + Sync {
+    fn render(&self, message: &Message) -> String;
+}
+
+impl fmt::Debug for dyn MessageRenderer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MessageRenderer")
+    }
+}
+
+/// Renders `sender: content`, with a `[PM]` prefix on private messages.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultRenderer;
+
+impl MessageRenderer for DefaultRenderer {
+    fn render(&self, message: &Message) -> String {
+        message.to_string()
+    }
+}
+
+/// Fills a channel's format template for a message sent at `sent`.
+/// `{timestamp}` is the UTC time of day.
+pub fn render_template(template: &str, message: &Message, sent: SystemTime) -> String {
+    let secs = sent
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let timestamp = time_of_day(secs);
+    // one pass over the template, so placeholders in what gets filled in,
+    // such as a nick or the content, stay as they are
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let placeholder = rest.find('}').map(|end| &rest[..=end]);
+        let value: Option<Cow<str>> = match placeholder {
+            Some("{sender}") => Some(message.sender.as_str().into()),
+            Some("{channel}") => Some(message.channel.as_deref().unwrap_or("").into()),
+            Some("{timestamp}") => Some(timestamp.as_str().into()),
+            Some("{content}") => Some(message.quoted_content()),
+            _ => None,
+        };
+        match (placeholder, value) {
+            (Some(placeholder), Some(value)) => {
+                rendered.push_str(&value);
+                rest = &rest[placeholder.len()..];
+            }
+            _ => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// `HH:MM:SS` in UTC for a time given in seconds since the Unix epoch.
+fn time_of_day(secs: u64) -> String {
+    let secs = secs % 86_400;
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Everything needed to turn a message into an outbound line.
+#[derive(Debug, Clone)]
+pub struct LineFormat {
+    pub protocol: Protocol,
+    pub signer: Option<MessageSigner>,
+    pub renderer: Arc<dyn MessageRenderer>,
+    /// Prefix text lines with when the message was sent, unless the
+    /// channel's own format already placed it; the peer can change this.
+    pub timestamps: Arc<AtomicBool>,
+    /// Wrap text lines to this many columns, or not at all when 0; the peer
+    /// can change this.
+    pub wrap_width: Arc<AtomicUsize>,
+    /// The codec's switch to compression, flipped by the writer once the
+    /// message that starts it is out.
+    pub deflate: Arc<AtomicBool>,
+}
+
+impl LineFormat {
+    pub fn encode(&self, message: &Message) -> serde_json::Result<String> {
+        match self.protocol {
+            Protocol::Text => match (&message.rendered, message.sent) {
+                (Some(line), _) => Ok(line.clone()),
+                (None, Some(sent)) if self.timestamps.load(Ordering::Relaxed) => Ok(format!(
+                    "[{}] {}",
+                    time_of_day(sent / 1000),
+                    self.renderer.render(message)
+                )),
+                (None, _) => Ok(self.renderer.render(message)),
+            },
+            Protocol::Json => {
+                serde_json::to_string(&SignedMessage::new(message, self.signer.as_ref()))
+            }
+        }
+    }
+
+    /// Encodes the message as the lines the peer should get, which is more
+    /// than one when a text line is wrapped.
+    pub fn encode_lines(&self, message: &Message) -> serde_json::Result<Vec<String>> {
+        let line = self.encode(message)?;
+        match self.protocol {
+            Protocol::Text => Ok(wrap(&line, self.wrap_width.load(Ordering::Relaxed))),
+            Protocol::Json => Ok(vec![line]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        let message = Message::new("alice", "hi {sender}").in_channel("support");
+        let sent = UNIX_EPOCH + Duration::from_secs(86_400 + 13 * 3600 + 5 * 60 + 9);
+        assert_eq!(
+            render_template(
+                "{timestamp} [{channel}] {sender}: {content}",
+                &message,
+                sent
+            ),
+            "13:05:09 [support] alice: hi {sender}"
+        );
+    }
+
+    #[test]
+    fn test_placeholders_in_names_are_not_expanded() {
+        let message = Message::new("{content}", "secret {channel}").in_channel("ops");
+        assert_eq!(
+            render_template("{{sender}} <{unknown}> {content}", &message, UNIX_EPOCH),
+            "{{content}} <{unknown}> secret {channel}"
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use std::net::SocketAddr;
+
+use anyhow::{bail, Result};
+
+use crate::{Message, State};
+
+/// Characters of the original message shown in a reply's quote.
+const SNIPPET_CHARS: usize = 40;
+
+/// Renders `sender: "snippet"` for a reply to `original`.
+pub fn quote(original: &Message) -> String {
+    let mut snippet: String = original.content.chars().take(SNIPPET_CHARS).collect();
+    if original.content.chars().nth(SNIPPET_CHARS).is_some() {
+        snippet.push_str("...");
+    }
+    format!("{}: \"{}\"", original.sender, snippet)
+}
+
+impl State {
+    /// Says `text` in the current channel as a reply to a message from
+    /// history.
+    pub(crate) async fn reply_to(&self, addr: SocketAddr, id: u64, text: &str) {
+        match self.find_reply_target(addr, id).await {
+            Ok(original) => {
+                let content = self.chat_content(text);
+                self.say(addr, content, Some(&original), Vec::new()).await;
+            }
+            Err(e) => self.notify(addr, Message::server(e.to_string())).await,
+        }
+    }
+
+    /// Looks up a message the peer may reply to.
+    async fn find_reply_target(&self, addr: SocketAddr, id: u64) -> Result<Message> {
+        let Some(original) = self.history.find(id).await else {
+            bail!("No such message: {}", id);
+        };
+        let channel = original.channel.clone().unwrap_or_default();
+        match self.peers.get(&addr) {
+            Some(peer) if peer.channels.contains(&channel) => Ok((*original).clone()),
+            _ => bail!("You must be in #{} to reply there.", channel),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::config::{self, ServerConfig};
+    use crate::test_support::{spawn_server, TestClient};
+
+    #[test]
+    fn test_quote_truncates_long_messages() {
+        assert_eq!(quote(&Message::new("bob", "short")), "bob: \"short\"");
+        let long = "a".repeat(SNIPPET_CHARS + 1);
+        assert_eq!(
+            quote(&Message::new("bob", long)),
+            format!("bob: \"{}...\"", "a".repeat(SNIPPET_CHARS))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reply_quotes_original() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        // the first channel message gets id 1
+        bob.send_line("ship it on friday").await?;
+        assert_eq!(alice.expect_line().await?, "bob: ship it on friday");
+        alice.send_line("/reply-to 1 not on a friday").await?;
+        assert_eq!(
+            bob.expect_line().await?,
+            "alice: [re bob: \"ship it on friday\"] not on a friday"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reply_includes_reference_in_json() -> Result<()> {
+        let addr = spawn_server(ServerConfig {
+            protocol: config::Protocol::Json,
+            ..Default::default()
+        })
+        .await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+        let mut bob = TestClient::connect(addr, "bob").await?;
+        alice.expect_line().await?; // bob joined
+
+        bob.send_line("ship it").await?;
+        let message: serde_json::Value = serde_json::from_str(&alice.expect_line().await?)?;
+        let id = message["id"].as_u64().expect("channel messages have an id");
+
+        alice.send_line(format!("/reply-to {} agreed", id)).await?;
+        let reply: serde_json::Value = serde_json::from_str(&bob.expect_line().await?)?;
+        assert_eq!(reply["content"], "agreed");
+        assert_eq!(reply["reply_to"], id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reply_to_unknown_id() -> Result<()> {
+        let addr = spawn_server(ServerConfig::default()).await?;
+        let mut alice = TestClient::connect(addr, "alice").await?;
+
+        alice.send_line("/reply-to 42 anyone?").await?;
+        assert_eq!(alice.expect_line().await?, "Server: No such message: 42");
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::names::NameClaim;
+use crate::rejection::Rejection;
+use crate::{Message, State};
+
+/// How long a departed peer's writer gets to hand back what it couldn't
+/// send before the session is parked without it.
+const WRITER_GRACE: Duration = Duration::from_secs(5);
+
+/// Lets peers that lose their connection log back in with a token and get
+/// the messages they missed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResumeConfig {
+    /// How long after a disconnect the session can still be resumed.
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+    /// Unsent messages kept per session; the oldest are dropped beyond this.
+    #[serde(default = "default_max_queued")]
+    pub max_queued: usize,
+}
+
+fn default_ttl_secs() -> u64 {
+    60
+}
+
+fn default_max_queued() -> usize {
+    100
+}
+
+/// Sessions waiting to be resumed, by token.
+#[derive(Debug, Default)]
+pub struct Sessions {
+    parked: DashMap<String, Parked>,
+}
+
+#[derive(Debug)]
+struct Parked {
+    username: String,
+    /// Channels the peer was in, whose messages are kept for them.
+    channels: HashSet<String>,
+    expires: Instant,
+    /// Whether the username is held for the session yet.
+    parked: bool,
+    /// What to replay, the oldest dropped past `max_queued`.
+    unsent: VecDeque<Arc<Message>>,
+}
+
+/// A fresh token to resume a session with.
+pub fn new_token() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+impl State {
+    /// Starts keeping channel messages for a peer whose connection was lost,
+    /// while they are still in their channels, so that nothing sent between
+    /// now and the session being parked is missed.
+    pub(crate) fn hold_session(&self, token: &str, addr: SocketAddr) {
+        let Some(config) = self.server.resume else {
+            return;
+        };
+        let Some((username, channels)) = self
+            .peers
+            .get(&addr)
+            .map(|peer| (peer.username.clone(), peer.channels.clone()))
+        else {
+            return;
+        };
+        self.sweep_sessions();
+        self.sessions.parked.insert(
+            token.to_string(),
+            Parked {
+                username,
+                channels,
+                expires: Instant::now() + Duration::from_secs(config.ttl_secs),
+                parked: false,
+                unsent: VecDeque::new(),
+            },
+        );
+    }
+
+    /// Parks a held session once the peer's writer has stopped, with what it
+    /// couldn't send ahead of what was kept since. Nobody else can log in
+    /// with their username until it is resumed or expires.
+    pub(crate) async fn park_session(
+        &self,
+        token: String,
+        name: NameClaim,
+        writer: JoinHandle<Vec<Arc<Message>>>,
+    ) {
+        let Some(config) = self.server.resume else {
+            return;
+        };
+        let username = name.name().to_string();
+        let unsent = match time::timeout(WRITER_GRACE, writer).await {
+            Ok(Ok(unsent)) => unsent,
+            Ok(Err(e)) => {
+                warn!("Writer for {} failed: {}", username, e);
+                Vec::new()
+            }
+            Err(_) => {
+                warn!(
+                    "Writer for {} did not stop; parking without its queue",
+                    username
+                );
+                Vec::new()
+            }
+        };
+
+        let Some(mut parked) = self.sessions.parked.get_mut(&token) else {
+            return;
+        };
+        // messages queued before the peer left were kept as well
+        let kept: Vec<_> = std::mem::take(&mut parked.unsent)
+            .into_iter()
+            .filter(|message| !unsent.iter().any(|sent| Arc::ptr_eq(sent, message)))
+            .collect();
+        parked.unsent = unsent.into_iter().chain(kept).collect();
+        let dropped = parked.unsent.len().saturating_sub(config.max_queued);
+        if dropped > 0 {
+            info!(
+                "Dropping {} oldest unsent messages for {}",
+                dropped, username
+            );
+            parked.unsent.drain(..dropped);
+        }
+        name.park(&token, parked.expires);
+        parked.parked = true;
+    }
+
+    /// Keeps a channel message for the parked sessions of its members.
+    pub(crate) fn keep_for_parked(&self, message: &Arc<Message>) {
+        let (Some(config), Some(channel)) = (self.server.resume, &message.channel) else {
+            return;
+        };
+        let now = Instant::now();
+        self.sessions.parked.retain(|token, parked| {
+            if !self.unexpired(token, parked, now) {
+                return false;
+            }
+            if parked.channels.contains(channel) {
+                parked.unsent.push_back(message.clone());
+                if parked.unsent.len() > config.max_queued {
+                    parked.unsent.pop_front();
+                }
+            }
+            true
+        });
+    }
+
+    /// Takes the parked session for a token, returning its username and the
+    /// messages to replay. The session stays parked unless the username can
+    /// be taken back.
+    pub(crate) fn claim_session(
+        &self,
+        token: &str,
+    ) -> Result<(NameClaim, Vec<Arc<Message>>), Rejection> {
+        self.sweep_sessions();
+        let username = match self.sessions.parked.get(token) {
+            Some(parked) if parked.expires > Instant::now() => {
+                if !parked.parked {
+                    // still connected as far as everyone else can tell
+                    return Err(Rejection::UsernameTaken);
+                }
+                parked.username.clone()
+            }
+            _ => return Err(Rejection::InvalidResumeToken),
+        };
+        let name = self
+            .names
+            .resume(&username, token)
+            .ok_or(Rejection::UsernameTaken)?;
+        let (_, parked) = self
+            .sessions
+            .parked
+            .remove(token)
+            .ok_or(Rejection::InvalidResumeToken)?;
+        Ok((name, parked.unsent.into()))
+    }
+
+    /// Forgets expired sessions, freeing their usernames.
+    fn sweep_sessions(&self) {
+        let now = Instant::now();
+        self.sessions
+            .parked
+            .retain(|token, parked| self.unexpired(token, parked, now));
+    }
+
+    fn unexpired(&self, token: &str, parked: &Parked, now: Instant) -> bool {
+        let live = parked.expires > now;
+        if !live {
+            self.names.release_parked(&parked.username, token);
+        }
+        live
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tokio::{
+        io::{AsyncRead, AsyncWrite},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::handle_connection;
+    use crate::test_support::{spawn_state, TestClient};
+
+    fn resume_config() -> ServerConfig {
+        ServerConfig {
+            resume: Some(ResumeConfig {
+                ttl_secs: 60,
+                max_queued: 3,
+            }),
+            send_timeout_secs: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Logs in and reads the resume token.
+    async fn login<S>(client: &mut TestClient<S>, login: &str, username: &str) -> Result<String>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        assert_eq!(client.expect_line().await?, "Enter your username:");
+        client.send_line(login).await?;
+        assert_eq!(
+            client.expect_line().await?,
+            format!("Welcome, {}!", username)
+        );
+        let line = client.expect_line().await?;
+        Ok(line.strip_prefix("Resume token: ").unwrap().to_string())
+    }
+
+    /// Waits until the peer has left and their session is parked.
+    async fn wait_parked(state: &State, username: &str) {
+        let started = Instant::now();
+        while state.find_peer(username).is_some()
+            || !state.sessions.parked.iter().any(|session| session.parked)
+        {
+            time::sleep(Duration::from_millis(10)).await;
+            assert!(started.elapsed() < Duration::from_secs(5), "not parked");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resume_replays_unsent_messages() -> Result<()> {
+        let state = Arc::new(State::new(resume_config())?);
+        let addr = spawn_state(state.clone()).await?;
+        let mut alice = TestClient::new(TcpStream::connect(addr).await?);
+        login(&mut alice, "alice", "alice").await?;
+
+        // bob stops reading, so his messages back up until the writer gives
+        // up and the connection is dropped
+        let bob_addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let (client, server) = tokio::io::duplex(64);
+        tokio::spawn(handle_connection(state.clone(), bob_addr, server));
+        let mut bob = TestClient::new(client);
+        let token = login(&mut bob, "bob", "bob").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: bob has joined the chat."
+        );
+        for i in 0..10 {
+            alice.send_line(format!("message {}", i)).await?;
+        }
+        wait_parked(&state, "bob").await;
+        assert_eq!(alice.expect_line().await?, "Server: bob has left the chat.");
+        drop(bob);
+
+        let mut bob = TestClient::new(TcpStream::connect(addr).await?);
+        let new_token = login(&mut bob, &format!("/resume {}", token), "bob").await?;
+        assert_ne!(new_token, token);
+        // only the most recent ones are kept, in order
+        for i in 7..10 {
+            assert_eq!(bob.expect_line().await?, format!("alice: message {}", i));
+        }
+        assert_eq!(bob.try_recv().await?, None);
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: bob has joined the chat."
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume_replays_messages_sent_while_parked() -> Result<()> {
+        let state = Arc::new(State::new(resume_config())?);
+        let addr = spawn_state(state.clone()).await?;
+        let mut alice = TestClient::new(TcpStream::connect(addr).await?);
+        login(&mut alice, "alice", "alice").await?;
+        let mut bob = TestClient::new(TcpStream::connect(addr).await?);
+        let token = login(&mut bob, "bob", "bob").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: bob has joined the chat."
+        );
+        drop(bob);
+        wait_parked(&state, "bob").await;
+        assert_eq!(alice.expect_line().await?, "Server: bob has left the chat.");
+
+        for i in 0..5 {
+            alice.send_line(format!("while away {}", i)).await?;
+        }
+        alice.send_line("/join ops").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Joined #ops.");
+        alice.send_line("not for bob").await?;
+        // answered only once the message before it was handled
+        alice.send_line("/join ops").await?;
+        assert_eq!(alice.expect_line().await?, "Server: Now talking in #ops.");
+
+        let mut bob = TestClient::new(TcpStream::connect(addr).await?);
+        login(&mut bob, &format!("/resume {}", token), "bob").await?;
+        for i in 2..5 {
+            assert_eq!(bob.expect_line().await?, format!("alice: while away {}", i));
+        }
+        assert_eq!(bob.try_recv().await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume_token_works_once() -> Result<()> {
+        let state = Arc::new(State::new(resume_config())?);
+        let addr = spawn_state(state.clone()).await?;
+        let mut bob = TestClient::new(TcpStream::connect(addr).await?);
+        let token = login(&mut bob, "bob", "bob").await?;
+        drop(bob);
+        wait_parked(&state, "bob").await;
+
+        let mut bob = TestClient::new(TcpStream::connect(addr).await?);
+        login(&mut bob, &format!("/resume {}", token), "bob").await?;
+
+        let mut carol = TestClient::connect_raw(addr).await?;
+        assert_eq!(carol.expect_line().await?, "Enter your username:");
+        carol.send_line(format!("/resume {}", token)).await?;
+        assert_eq!(
+            carol.expect_line().await?,
+            "Invalid or expired resume token."
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_parked_sessions_keep_their_username() -> Result<()> {
+        let state = Arc::new(State::new(resume_config())?);
+        let addr = spawn_state(state.clone()).await?;
+        let mut bob = TestClient::new(TcpStream::connect(addr).await?);
+        let token = login(&mut bob, "bob", "bob").await?;
+        drop(bob);
+        wait_parked(&state, "bob").await;
+
+        let mut other = TestClient::new(TcpStream::connect(addr).await?);
+        assert_eq!(other.expect_line().await?, "Enter your username:");
+        other.send_line("bob").await?;
+        assert_eq!(
+            other.expect_line().await?,
+            "That username is already taken."
+        );
+
+        let mut bob = TestClient::new(TcpStream::connect(addr).await?);
+        login(&mut bob, &format!("/resume {}", token), "bob").await?;
+        Ok(())
+    }
+}
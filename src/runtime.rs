@@ -0,0 +1,163 @@
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use tokio::runtime::{Builder, Runtime};
+
+/// Which scheduler the server runs on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimeFlavor {
+    /// A pool of worker threads sharing the connections.
+    #[default]
+    MultiThread,
+    /// Everything on the main thread.
+    CurrentThread,
+}
+
+/// How the async runtime is set up; read before it starts, so changes need
+/// a restart.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RuntimeConfig {
+    #[serde(default)]
+    pub flavor: RuntimeFlavor,
+    /// Worker threads of the multi-thread runtime; 0 starts one per core.
+    #[serde(default)]
+    pub worker_threads: usize,
+    /// Name of the runtime's threads, as shown by `top -H` and debuggers.
+    #[serde(default)]
+    pub thread_name: Option<String>,
+    /// Pin each worker thread of the multi-thread runtime to the next core
+    /// the server may use, round robin. Blocking pool threads, and the
+    /// current-thread runtime, are left alone. Only supported on Linux.
+    #[serde(default)]
+    pub pin_threads: bool,
+}
+
+/// Builds the runtime the server runs on.
+pub fn build(config: &RuntimeConfig) -> io::Result<Runtime> {
+    let mut builder = match config.flavor {
+        RuntimeFlavor::MultiThread => Builder::new_multi_thread(),
+        RuntimeFlavor::CurrentThread => Builder::new_current_thread(),
+    };
+    builder.enable_all();
+    if config.worker_threads > 0 {
+        builder.worker_threads(config.worker_threads);
+    }
+    if let Some(name) = &config.thread_name {
+        builder.thread_name(name);
+    }
+    if config.pin_threads && config.flavor == RuntimeFlavor::MultiThread {
+        pin_workers(&mut builder, config.worker_threads);
+    }
+    builder.build()
+}
+
+/// Pins the first `workers` threads the runtime starts, or one per core when
+/// `workers` is 0.
+#[cfg(target_os = "linux")]
+fn pin_workers(builder: &mut Builder, workers: usize) {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // SAFETY: the sets are zeroed and then only touched through libc's own
+    // accessors, and pid 0 means the calling thread
+    let cores: Vec<usize> = unsafe {
+        let mut allowed: libc::cpu_set_t = std::mem::zeroed();
+        libc::sched_getaffinity(0, std::mem::size_of_val(&allowed), &mut allowed);
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|&core| libc::CPU_ISSET(core, &allowed))
+            .collect()
+    };
+    if cores.is_empty() {
+        return;
+    }
+    let workers = match workers {
+        0 => cores.len(),
+        workers => workers,
+    };
+    builder.worker_threads(workers);
+    let next = AtomicUsize::new(0);
+    builder.on_thread_start(move || {
+        // the workers are started while the runtime is built, so any thread
+        // after them belongs to the blocking pool
+        let started = next.fetch_add(1, Ordering::Relaxed);
+        if started >= workers {
+            return;
+        }
+        let core = cores[started % cores.len()];
+        // SAFETY: as above
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            libc::CPU_SET(core, &mut set);
+            if libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) != 0 {
+                tracing::warn!(
+                    "Failed to pin runtime thread to core {}: {}",
+                    core,
+                    io::Error::last_os_error()
+                );
+            }
+        }
+    });
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_workers(_: &mut Builder, _: usize) {
+    tracing::warn!("pin_threads is only supported on Linux");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_threads_are_applied() {
+        let runtime = build(&RuntimeConfig {
+            worker_threads: 3,
+            thread_name: Some("chat-worker".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 3);
+        let name = runtime.block_on(async {
+            tokio::spawn(async { std::thread::current().name().map(str::to_string) })
+                .await
+                .unwrap()
+        });
+        assert_eq!(name.as_deref(), Some("chat-worker"));
+
+        let runtime = build(&RuntimeConfig {
+            flavor: RuntimeFlavor::CurrentThread,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    fn allowed_cores() -> i32 {
+        // SAFETY: the set is zeroed and filled in by libc
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            libc::sched_getaffinity(0, std::mem::size_of_val(&set), &mut set);
+            libc::CPU_COUNT(&set)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pinned_threads_run_on_one_core() {
+        let runtime = build(&RuntimeConfig {
+            worker_threads: 2,
+            pin_threads: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let (worker, blocking) = runtime.block_on(async {
+            let worker = tokio::spawn(async { allowed_cores() }).await.unwrap();
+            let blocking = tokio::task::spawn_blocking(allowed_cores).await.unwrap();
+            (worker, blocking)
+        });
+        assert_eq!(worker, 1);
+        // blocking threads keep every core the process may use
+        assert_eq!(blocking, allowed_cores());
+    }
+}
@@ -0,0 +1,179 @@
+use hmac::{Hmac, KeyInit, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::Message;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs and verifies messages with a shared secret so that relays sitting
+/// between servers can't tamper with them unnoticed.
+#[derive(Debug, Clone)]
+pub struct MessageSigner {
+    key: Vec<u8>,
+}
+
+/// A message as it goes out on the wire in JSON mode.
+#[derive(Debug, Serialize)]
+pub struct SignedMessage<'a> {
+    #[serde(flatten)]
+    pub message: &'a Message,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl MessageSigner {
+    pub fn new(secret: impl AsRef<[u8]>) -> Self {
+        Self {
+            key: secret.as_ref().to_vec(),
+        }
+    }
+
+    pub fn sign(&self, message: &Message) -> String {
+        hex::encode(self.mac(message).finalize().into_bytes())
+    }
+
+    /// Signatures from federated peers are checked here before their
+    /// messages are relayed to local peers.
+    pub fn verify(&self, message: &Message, signature: &str) -> bool {
+        match hex::decode(signature) {
+            Ok(signature) => self.mac(message).verify_slice(&signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Covers every field a message carries on the wire, so a relay can't
+    /// move it to another channel or pass it off as a new one.
+    fn mac(&self, message: &Message) -> HmacSha256 {
+        let mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        let mut fields = Fields(mac);
+        fields.str(&message.sender);
+        fields.str(&message.content);
+        fields.bytes(&[u8::from(message.private)]);
+        fields.option(message.channel.as_deref(), Fields::str);
+        fields.option(message.id, Fields::u64);
+        fields.option(message.uuid, |fields, uuid| fields.bytes(uuid.as_bytes()));
+        fields.option(message.reactions.as_ref(), |fields, reactions| {
+            fields.u64(reactions.len() as u64);
+            for (emoji, count) in reactions {
+                fields.str(emoji);
+                fields.u64(*count as u64);
+            }
+        });
+        fields.option(message.sent, Fields::u64);
+        fields.u64(message.attachments.len() as u64);
+        for attachment in &message.attachments {
+            fields.str(&attachment.name);
+            fields.str(&attachment.url);
+            fields.u64(attachment.size);
+        }
+        fields.option(message.reply_to, Fields::u64);
+        fields.0
+    }
+}
+
+/// Feeds message fields to a MAC, each behind its length, so that no two
+/// different messages feed the same bytes.
+struct Fields(HmacSha256);
+
+impl Fields {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0.update(&(bytes.len() as u64).to_be_bytes());
+        self.0.update(bytes);
+    }
+
+    fn str(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    fn option<T>(&mut self, value: Option<T>, field: impl FnOnce(&mut Self, T)) {
+        match value {
+            Some(value) => {
+                self.bytes(&[1]);
+                field(self, value);
+            }
+            None => self.bytes(&[0]),
+        }
+    }
+}
+
+impl<'a> SignedMessage<'a> {
+    pub fn new(message: &'a Message, signer: Option<&MessageSigner>) -> Self {
+        Self {
+            message,
+            signature: signer.map(|signer| signer.sign(message)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(content: &str) -> Message {
+        Message::new("alice", content)
+    }
+
+    #[test]
+    fn test_valid_signature_verifies() {
+        let signer = MessageSigner::new("secret");
+        let msg = message("hello");
+        let signature = signer.sign(&msg);
+        assert!(signer.verify(&msg, &signature));
+    }
+
+    #[test]
+    fn test_tampered_message_fails_verification() {
+        let signer = MessageSigner::new("secret");
+        let signature = signer.sign(&message("hello"));
+        assert!(!signer.verify(&message("hello!"), &signature));
+        assert!(!MessageSigner::new("other").verify(&message("hello"), &signature));
+        assert!(!signer.verify(&message("hello"), "not-hex"));
+    }
+
+    #[test]
+    fn test_signature_covers_every_field() {
+        let signer = MessageSigner::new("secret");
+        let msg = Message {
+            uuid: Some(uuid::Uuid::new_v4()),
+            ..message("hello").in_channel("rust")
+        };
+        let signature = signer.sign(&msg);
+        for tampered in [
+            msg.clone().in_channel("go"),
+            Message {
+                uuid: Some(uuid::Uuid::new_v4()),
+                ..msg.clone()
+            },
+            Message {
+                private: true,
+                ..msg.clone()
+            },
+            Message {
+                reply_to: Some(1),
+                ..msg.clone()
+            },
+        ] {
+            assert!(!signer.verify(&tampered, &signature), "{:?}", tampered);
+        }
+        // the separator can't be moved between fields
+        let split = signer.sign(&Message::new("ab", "c"));
+        assert!(!signer.verify(&Message::new("a", "bc"), &split));
+    }
+
+    #[test]
+    fn test_signed_message_json() -> anyhow::Result<()> {
+        let msg = message("hello");
+        let unsigned = serde_json::to_string(&SignedMessage::new(&msg, None))?;
+        assert_eq!(unsigned, r#"{"sender":"alice","content":"hello"}"#);
+
+        let signer = MessageSigner::new("secret");
+        let signed = serde_json::to_value(SignedMessage::new(&msg, Some(&signer)))?;
+        assert_eq!(signed["signature"], signer.sign(&msg));
+        Ok(())
+    }
+}
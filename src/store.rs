@@ -0,0 +1,467 @@
+use std::{
+    fs::OpenOptions,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(not(feature = "sqlite"))]
+use anyhow::bail;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::{fs::File, io::AsyncWriteExt, sync::Mutex};
+use tracing::warn;
+
+use crate::history::{History, HistoryStore, Visible};
+use crate::persistence::{read_log, LogCipher};
+use crate::Message;
+
+/// Which backend keeps chat history.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum HistoryStoreConfig {
+    /// The last `history_size` messages, lost on restart.
+    #[default]
+    Memory,
+    /// The last `history_size` messages, kept across restarts in a JSON
+    /// lines file.
+    File { path: PathBuf },
+    /// Every message, in an SQLite database; needs the `sqlite` feature.
+    /// Searches look through the last `history_size` of them.
+    Sqlite { path: PathBuf },
+}
+
+/// Opens the configured history backend; the file backend seals its records
+/// with `cipher`.
+pub fn open(
+    config: &HistoryStoreConfig,
+    history_size: usize,
+    cipher: Option<LogCipher>,
+) -> Result<Arc<dyn HistoryStore>> {
+    Ok(match config {
+        HistoryStoreConfig::Memory => Arc::new(History::new(history_size)),
+        HistoryStoreConfig::File { path } => {
+            Arc::new(FileHistory::open(path.clone(), history_size, cipher)?)
+        }
+        #[cfg(feature = "sqlite")]
+        HistoryStoreConfig::Sqlite { path } => {
+            Arc::new(sqlite::SqliteHistory::open(path, history_size)?)
+        }
+        #[cfg(not(feature = "sqlite"))]
+        HistoryStoreConfig::Sqlite { .. } => {
+            bail!("SQLite history needs a build with the sqlite feature")
+        }
+    })
+}
+
+/// A message as stored outside memory, with when it was sent.
+#[derive(Debug, Deserialize, Serialize)]
+struct Record<M> {
+    /// Milliseconds since the Unix epoch.
+    sent: u64,
+    message: M,
+}
+
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+fn from_unix_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// History kept across restarts in a file, one JSON record per line, sealed
+/// with the persistence key when one is set. Reads are served from the last
+/// `history_size` messages kept in memory, and the file is cut back to
+/// those once it holds twice as many.
+#[derive(Debug)]
+pub struct FileHistory {
+    path: PathBuf,
+    cipher: Option<LogCipher>,
+    capacity: usize,
+    file: Mutex<HistoryFile>,
+    recent: History,
+}
+
+#[derive(Debug)]
+struct HistoryFile {
+    file: File,
+    /// Records in the file, kept or not.
+    records: usize,
+}
+
+impl FileHistory {
+    pub fn open(path: PathBuf, capacity: usize, cipher: Option<LogCipher>) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let records: Vec<Record<Message>> = read_log(&path, cipher.as_ref())
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let stored = records.len();
+        let recent = History::new(capacity);
+        for record in records.into_iter().skip(stored.saturating_sub(capacity)) {
+            recent.push(from_unix_millis(record.sent), Arc::new(record.message));
+        }
+        Ok(Self {
+            path,
+            cipher,
+            capacity,
+            file: Mutex::new(HistoryFile {
+                file: File::from_std(file),
+                records: stored,
+            }),
+            recent,
+        })
+    }
+
+    fn encode(&self, sent: SystemTime, message: &Message) -> Result<Vec<u8>> {
+        let record = Record {
+            sent: unix_millis(sent),
+            message,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        if let Some(cipher) = &self.cipher {
+            line = cipher.seal(&line)?;
+        }
+        line.push(b'\n');
+        Ok(line)
+    }
+
+    /// Rewrites the file with only the messages kept in memory, swapping it
+    /// in whole so a crash leaves one version or the other.
+    async fn compact(&self, file: &mut HistoryFile) -> Result<()> {
+        let records = self.recent.records();
+        let mut contents = Vec::new();
+        for (sent, message) in &records {
+            contents.extend(self.encode(*sent, message)?);
+        }
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        tokio::fs::write(&temp, contents).await?;
+        tokio::fs::rename(&temp, &self.path).await?;
+        file.file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.records = records.len();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl HistoryStore for FileHistory {
+    async fn append(&self, message: &Arc<Message>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let sent = SystemTime::now();
+        let line = match self.encode(sent, message) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to encode history record: {}", e);
+                return;
+            }
+        };
+        // the file and the messages in memory change together, so
+        // compacting writes out what's in the file
+        let mut file = self.file.lock().await;
+        // flushed so the write is done before the next one or a compaction
+        let written = match file.file.write_all(&line).await {
+            Ok(()) => file.file.flush().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            warn!("Failed to append to {}: {}", self.path.display(), e);
+            return;
+        }
+        file.records += 1;
+        self.recent.push(sent, message.clone());
+        if file.records >= 2 * self.capacity {
+            if let Err(e) = self.compact(&mut file).await {
+                warn!("Failed to compact {}: {:?}", self.path.display(), e);
+            }
+        }
+    }
+
+    async fn recent(&self, channel: &str, limit: usize) -> Vec<(SystemTime, Arc<Message>)> {
+        self.recent.recent(channel, limit).await
+    }
+
+    async fn search(&self, query: &str, limit: usize, visible: Visible<'_>) -> Vec<Arc<Message>> {
+        self.recent.search(query, limit, visible).await
+    }
+
+    async fn find(&self, id: u64) -> Option<Arc<Message>> {
+        self.recent.find(id).await
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite {
+    use std::{path::Path, sync::Mutex};
+
+    use rusqlite::{params, Connection};
+
+    use super::*;
+    use crate::history::search_records;
+
+    /// History kept in an SQLite database.
+    #[derive(Debug)]
+    pub struct SqliteHistory {
+        db: Arc<Mutex<Connection>>,
+        /// Most recent messages a search looks through.
+        scan_depth: usize,
+    }
+
+    impl SqliteHistory {
+        pub fn open(path: &Path, scan_depth: usize) -> Result<Self> {
+            let db = Connection::open(path)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            db.execute_batch(
+                "CREATE TABLE IF NOT EXISTS messages (
+                     id INTEGER PRIMARY KEY,
+                     sent INTEGER NOT NULL,
+                     channel TEXT,
+                     message TEXT NOT NULL,
+                     message_id INTEGER
+                 );
+                 CREATE INDEX IF NOT EXISTS messages_by_channel ON messages (channel, id);",
+            )?;
+            // databases from before message ids had their own column
+            let has_message_id: bool = db.query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('messages')
+                 WHERE name = 'message_id'",
+                [],
+                |row| row.get(0),
+            )?;
+            if !has_message_id {
+                db.execute_batch(
+                    "ALTER TABLE messages ADD COLUMN message_id INTEGER;
+                     UPDATE messages SET message_id = json_extract(message, '$.id');",
+                )?;
+            }
+            db.execute_batch(
+                "CREATE INDEX IF NOT EXISTS messages_by_message_id
+                 ON messages (message_id, id);",
+            )?;
+            Ok(Self {
+                db: Arc::new(Mutex::new(db)),
+                scan_depth,
+            })
+        }
+
+        /// Runs a query off the async threads.
+        async fn with_db<T: Send + 'static>(
+            &self,
+            query: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+        ) -> Option<T> {
+            let db = self.db.clone();
+            match tokio::task::spawn_blocking(move || query(&db.lock().unwrap())).await {
+                Ok(Ok(result)) => Some(result),
+                Ok(Err(e)) => {
+                    warn!("History query failed: {}", e);
+                    None
+                }
+                Err(e) => {
+                    warn!("History query panicked: {}", e);
+                    None
+                }
+            }
+        }
+    }
+
+    fn decode(sent: i64, message: &str) -> Option<(SystemTime, Arc<Message>)> {
+        let message = serde_json::from_str(message).ok()?;
+        Some((from_unix_millis(sent as u64), Arc::new(message)))
+    }
+
+    #[async_trait]
+    impl HistoryStore for SqliteHistory {
+        async fn append(&self, message: &Arc<Message>) {
+            let sent = unix_millis(SystemTime::now()) as i64;
+            let channel = message.channel.clone();
+            let message_id = message.id.and_then(|id| i64::try_from(id).ok());
+            let Ok(json) = serde_json::to_string(&**message) else {
+                return;
+            };
+            self.with_db(move |db| {
+                db.execute(
+                    "INSERT INTO messages (sent, channel, message, message_id)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![sent, channel, json, message_id],
+                )
+            })
+            .await;
+        }
+
+        async fn recent(&self, channel: &str, limit: usize) -> Vec<(SystemTime, Arc<Message>)> {
+            let channel = channel.to_string();
+            let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+            let rows = self
+                .with_db(move |db| {
+                    db.prepare(
+                        "SELECT sent, message FROM messages WHERE channel = ?1
+                         ORDER BY id DESC LIMIT ?2",
+                    )?
+                    .query_map(params![channel, limit], |row| {
+                        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+                })
+                .await
+                .unwrap_or_default();
+            let mut found: Vec<_> = rows
+                .iter()
+                .filter_map(|(sent, message)| decode(*sent, message))
+                .collect();
+            found.reverse();
+            found
+        }
+
+        async fn search(
+            &self,
+            query: &str,
+            limit: usize,
+            visible: Visible<'_>,
+        ) -> Vec<Arc<Message>> {
+            let needle = query.to_lowercase();
+            let depth = i64::try_from(self.scan_depth).unwrap_or(i64::MAX);
+            // matching is done here rather than in SQL, whose lower() only
+            // knows ASCII
+            let mut matches = self
+                .with_db(move |db| {
+                    let mut statement =
+                        db.prepare("SELECT sent, message FROM messages ORDER BY id DESC LIMIT ?1")?;
+                    let rows = statement.query_map(params![depth], |row| {
+                        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                    })?;
+                    let mut matches = Vec::new();
+                    for row in rows {
+                        let (sent, message) = row?;
+                        if let Some((_, message)) = decode(sent, &message) {
+                            if message.content.to_lowercase().contains(&needle) {
+                                matches.push(message);
+                            }
+                        }
+                    }
+                    Ok(matches)
+                })
+                .await
+                .unwrap_or_default();
+            matches.reverse();
+            search_records(matches.iter(), query, limit, visible)
+        }
+
+        async fn find(&self, id: u64) -> Option<Arc<Message>> {
+            let id = i64::try_from(id).ok()?;
+            let row = self
+                .with_db(move |db| {
+                    db.prepare(
+                        "SELECT sent, message FROM messages
+                         WHERE message_id = ?1
+                         ORDER BY id DESC LIMIT 1",
+                    )?
+                    .query_map(params![id], |row| {
+                        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                    })?
+                    .next()
+                    .transpose()
+                })
+                .await
+                .flatten()?;
+            decode(row.0, &row.1).map(|(_, message)| message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn cipher() -> Option<LogCipher> {
+        Some(LogCipher::from_hex(KEY).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_history_survives_reopening() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("history.jsonl");
+        let history = FileHistory::open(path.clone(), 10, cipher())?;
+        let message = Message::new("alice", "the launch code is 1234").in_channel("ops");
+        history.append(&Arc::new(message)).await;
+        drop(history);
+
+        let raw = std::fs::read_to_string(&path)?;
+        assert!(!raw.contains("launch code"));
+        let history = FileHistory::open(path.clone(), 10, cipher())?;
+        let found = history.recent("ops", 10).await;
+        assert_eq!(found[0].1.content, "the launch code is 1234");
+        assert!(FileHistory::open(path, 10, None).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_is_cut_back_to_history_size() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("history.jsonl");
+        let history = FileHistory::open(path.clone(), 3, None)?;
+        for i in 0..10 {
+            let message = Message::new("alice", format!("message {}", i));
+            history.append(&Arc::new(message)).await;
+        }
+        let lines = std::fs::read_to_string(&path)?.lines().count();
+        assert!(lines < 6, "{} lines", lines);
+        drop(history);
+
+        let history = FileHistory::open(path, 3, None)?;
+        let found = history.search("message", 10, &|_| true).await;
+        let contents: Vec<_> = found.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["message 7", "message 8", "message 9"]);
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_search_looks_back_history_size() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let history = sqlite::SqliteHistory::open(&dir.path().join("history.db"), 3)?;
+        for i in 0..5 {
+            let message = Message::new("alice", format!("message {}", i));
+            history.append(&Arc::new(message)).await;
+        }
+        let found = history.search("message", 10, &|_| true).await;
+        let contents: Vec<_> = found.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["message 2", "message 3", "message 4"]);
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_finds_ids_in_older_databases() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("history.db");
+        let db = rusqlite::Connection::open(&path)?;
+        db.execute_batch(
+            r#"CREATE TABLE messages (
+                   id INTEGER PRIMARY KEY,
+                   sent INTEGER NOT NULL,
+                   channel TEXT,
+                   message TEXT NOT NULL
+               );
+               INSERT INTO messages (sent, channel, message)
+               VALUES (0, 'rust', '{"sender":"alice","content":"old","id":7}');"#,
+        )?;
+        drop(db);
+
+        let history = sqlite::SqliteHistory::open(&path, 10)?;
+        assert_eq!(history.find(7).await.unwrap().content, "old");
+        Ok(())
+    }
+}
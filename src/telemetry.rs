@@ -0,0 +1,61 @@
+use tracing::warn;
+
+use crate::config::ServerConfig;
+
+/// Installs the global tracing subscriber, adding the tokio-console layer
+/// when the server is built with the `console` feature and asks for it.
+pub fn init(config: &ServerConfig) {
+    #[cfg(feature = "console")]
+    if config.tokio_console {
+        use tracing_subscriber::util::SubscriberInitExt;
+
+        match console_subscriber() {
+            Some(subscriber) => {
+                subscriber.init();
+                return;
+            }
+            None => {
+                tracing_subscriber::fmt::init();
+                warn!(
+                    "tokio-console needs the server built with RUSTFLAGS=\"--cfg tokio_unstable\""
+                );
+                return;
+            }
+        }
+    }
+
+    tracing_subscriber::fmt::init();
+    if config.tokio_console {
+        warn!("tokio_console is set but the server was built without the `console` feature");
+    }
+}
+
+/// The console layer panics when tokio wasn't built with `tokio_unstable`,
+/// so there is no console subscriber in that case.
+#[cfg(feature = "console")]
+fn console_subscriber() -> Option<impl tracing::Subscriber + Send + Sync> {
+    use tracing_subscriber::{fmt, layer::SubscriberExt, EnvFilter, Layer};
+
+    if !cfg!(tokio_unstable) {
+        return None;
+    }
+    let subscriber = tracing_subscriber::registry()
+        .with(console_subscriber::spawn())
+        .with(fmt::layer().with_filter(EnvFilter::from_default_env()));
+    Some(subscriber)
+}
+
+#[cfg(all(test, feature = "console"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_console_subscriber_initializes() {
+        let subscriber = console_subscriber();
+        assert_eq!(subscriber.is_some(), cfg!(tokio_unstable));
+        if let Some(subscriber) = subscriber {
+            let _guard = tracing::subscriber::set_default(subscriber);
+            tracing::info!("console subscriber is installed");
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Helpers for driving a real server over sockets in tests.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+use tokio_util::codec::{Framed, LinesCodec};
+
+use crate::{config::ServerConfig, serve, State};
+
+/// How long to wait for a line that has to arrive.
+const EXPECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for a line that may not arrive at all.
+const TRY_RECV_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Starts a server with the given config on a free local port.
+pub async fn spawn_server(config: ServerConfig) -> Result<SocketAddr> {
+    spawn_state(Arc::new(State::new(config)?)).await
+}
+
+/// Starts a server around existing state, so tests can inspect it.
+pub async fn spawn_state(state: Arc<State>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(serve(state, listener));
+    Ok(addr)
+}
+
+/// A chat client speaking the line protocol.
+#[derive(Debug)]
+pub struct TestClient<S = TcpStream> {
+    framed: Framed<S, LinesCodec>,
+}
+
+impl TestClient {
+    /// Connects and logs in, consuming the prompt and the welcome.
+    pub async fn connect(addr: SocketAddr, username: &str) -> Result<Self> {
+        Self::login(TcpStream::connect(addr).await?, username).await
+    }
+
+    /// Connects without answering the username prompt.
+    pub async fn connect_raw(addr: SocketAddr) -> Result<Self> {
+        Ok(Self::new(TcpStream::connect(addr).await?))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TestClient<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            framed: Framed::new(stream, LinesCodec::new()),
+        }
+    }
+
+    /// Logs in over an already connected stream.
+    pub async fn login(stream: S, username: &str) -> Result<Self> {
+        let mut client = Self::new(stream);
+        assert_eq!(client.expect_line().await?, "Enter your username:");
+        client.send_line(username).await?;
+        assert_eq!(
+            client.expect_line().await?,
+            format!("Welcome, {}!", username)
+        );
+        Ok(client)
+    }
+
+    pub async fn send_line(&mut self, line: impl AsRef<str>) -> Result<()> {
+        Ok(self.framed.send(line.as_ref()).await?)
+    }
+
+    /// Writes bytes as they are, bypassing the line codec.
+    pub async fn send_raw(&mut self, bytes: &[u8]) -> Result<()> {
+        Ok(self.framed.get_mut().write_all(bytes).await?)
+    }
+
+    /// Returns the next line, failing if none arrives in time or the server
+    /// closed the connection.
+    pub async fn expect_line(&mut self) -> Result<String> {
+        match time::timeout(EXPECT_TIMEOUT, self.framed.next()).await? {
+            Some(line) => Ok(line?),
+            None => Err(anyhow!("connection closed")),
+        }
+    }
+
+    /// Returns the next line if one arrives shortly.
+    pub async fn try_recv(&mut self) -> Result<Option<String>> {
+        match time::timeout(TRY_RECV_TIMEOUT, self.framed.next()).await {
+            Ok(Some(line)) => Ok(Some(line?)),
+            Ok(None) => Err(anyhow!("connection closed")),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Hands back the stream and whatever was read past the last line.
+    pub fn into_parts(self) -> (S, BytesMut) {
+        let parts = self.framed.into_parts();
+        (parts.io, parts.read_buf)
+    }
+
+    /// Waits for the server to close the connection, failing on any line
+    /// that arrives first.
+    pub async fn expect_closed(&mut self) -> Result<()> {
+        match time::timeout(EXPECT_TIMEOUT, self.framed.next()).await? {
+            None => Ok(()),
+            Some(line) => bail!("expected the connection to close, got {:?}", line?),
+        }
+    }
+}
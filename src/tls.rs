@@ -0,0 +1,325 @@
+use std::{fmt, io, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Mutex},
+    time,
+};
+use tokio_rustls::{
+    rustls::{
+        self,
+        crypto::aws_lc_rs,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tracing::info;
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+use crate::{Listener, PeerIdentity};
+
+/// How long a client has to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain presented to clients.
+    pub cert_path: PathBuf,
+    /// PEM private key of the certificate.
+    pub key_path: PathBuf,
+    /// PEM CA certificates that client certificates are checked against.
+    /// A client presenting a valid certificate is logged in under the name
+    /// in it instead of being asked for one.
+    #[serde(default)]
+    pub client_ca_path: Option<PathBuf>,
+    /// Refuse clients that don't present a valid certificate.
+    #[serde(default)]
+    pub require_client_cert: bool,
+}
+
+impl TlsConfig {
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let provider = Arc::new(aws_lc_rs::default_provider());
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("failed to read {}", self.cert_path.display()))?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .with_context(|| format!("failed to read {}", self.key_path.display()))?;
+
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_ca_path {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(path)
+                    .with_context(|| format!("failed to read {}", path.display()))?
+                {
+                    roots.add(cert?)?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = if self.require_client_cert {
+                    verifier.build()?
+                } else {
+                    verifier.allow_unauthenticated().build()?
+                };
+                builder.with_client_cert_verifier(verifier)
+            }
+            None if self.require_client_cert => {
+                bail!("require_client_cert needs client_ca_path")
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(TlsAcceptor::from(Arc::new(
+            builder.with_single_cert(certs, key)?,
+        )))
+    }
+}
+
+/// Accepts TCP connections and hands them out once their TLS handshake is
+/// done. Handshakes run in their own tasks so a slow client can't hold up
+/// the accept loop.
+pub struct TlsListener {
+    inner: TcpListener,
+    acceptor: TlsAcceptor,
+    tx: mpsc::Sender<(TlsStream<TcpStream>, SocketAddr)>,
+    rx: Mutex<mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>>,
+}
+
+impl fmt::Debug for TlsListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsListener")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TlsListener {
+    pub fn new(inner: TcpListener, config: &TlsConfig) -> Result<Self> {
+        let (tx, rx) = mpsc::channel(64);
+        Ok(Self {
+            inner,
+            acceptor: config.acceptor()?,
+            tx,
+            rx: Mutex::new(rx),
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Stream = TlsStream<TcpStream>;
+
+    async fn accept(&self) -> io::Result<(TlsStream<TcpStream>, SocketAddr)> {
+        let mut handshaken = self.rx.lock().await;
+        loop {
+            tokio::select! {
+                Some(conn) = handshaken.recv() => return Ok(conn),
+                accepted = self.inner.accept() => {
+                    let (stream, addr) = accepted?;
+                    let acceptor = self.acceptor.clone();
+                    let tx = self.tx.clone();
+                    tokio::spawn(async move {
+                        match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => {
+                                let _ = tx.send((stream, addr)).await;
+                            }
+                            Ok(Err(e)) => info!("TLS handshake with {} failed: {}", addr, e),
+                            Err(_) => info!("TLS handshake with {} timed out", addr),
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl PeerIdentity for TlsStream<TcpStream> {
+    fn identity(&self) -> Option<String> {
+        let (_, connection) = self.get_ref();
+        certificate_name(connection.peer_certificates()?.first()?)
+    }
+}
+
+/// The name a client certificate vouches for: its common name, or failing
+/// that its first DNS name.
+fn certificate_name(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .find_map(|cn| cn.as_str().ok().map(str::to_string));
+    common_name.or_else(|| {
+        cert.subject_alternative_name()
+            .ok()
+            .flatten()?
+            .value
+            .general_names
+            .iter()
+            .find_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                _ => None,
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedIssuer, DnType, ExtendedKeyUsagePurpose,
+        IsCa, KeyPair,
+    };
+    use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::test_support::TestClient;
+    use crate::{serve, State};
+
+    struct Pki {
+        dir: tempfile::TempDir,
+        ca: CertifiedIssuer<'static, KeyPair>,
+    }
+
+    impl Pki {
+        fn new() -> Result<Self> {
+            let mut params = CertificateParams::new(Vec::<String>::new())?;
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "test ca");
+            let ca = CertifiedIssuer::self_signed(params, KeyPair::generate()?)?;
+            let pki = Self {
+                dir: tempfile::tempdir()?,
+                ca,
+            };
+            fs::write(pki.path("ca.pem"), pki.ca.pem())?;
+
+            let (cert, key) = pki.issue(
+                CertificateParams::new(vec!["localhost".to_string()])?,
+                ExtendedKeyUsagePurpose::ServerAuth,
+            )?;
+            fs::write(pki.path("server.pem"), cert.pem())?;
+            fs::write(pki.path("server.key"), key.serialize_pem())?;
+            Ok(pki)
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.dir.path().join(name)
+        }
+
+        fn issue(
+            &self,
+            mut params: CertificateParams,
+            usage: ExtendedKeyUsagePurpose,
+        ) -> Result<(rcgen::Certificate, KeyPair)> {
+            params.extended_key_usages = vec![usage];
+            let key = KeyPair::generate()?;
+            Ok((params.signed_by(&key, &self.ca)?, key))
+        }
+
+        fn tls_config(&self, require_client_cert: bool) -> TlsConfig {
+            TlsConfig {
+                cert_path: self.path("server.pem"),
+                key_path: self.path("server.key"),
+                client_ca_path: Some(self.path("ca.pem")),
+                require_client_cert,
+            }
+        }
+
+        /// A connector trusting the test CA, presenting a certificate for
+        /// `name` if given.
+        fn connector(&self, name: Option<&str>) -> Result<TlsConnector> {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.ca.der().clone())?;
+            let builder = rustls::ClientConfig::builder_with_provider(Arc::new(
+                aws_lc_rs::default_provider(),
+            ))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+            let config = match name {
+                Some(name) => {
+                    let mut params = CertificateParams::new(Vec::<String>::new())?;
+                    params.distinguished_name.push(DnType::CommonName, name);
+                    let (cert, key) = self.issue(params, ExtendedKeyUsagePurpose::ClientAuth)?;
+                    builder.with_client_auth_cert(
+                        vec![cert.der().clone()],
+                        PrivateKeyDer::try_from(key.serialize_der()).map_err(anyhow::Error::msg)?,
+                    )?
+                }
+                None => builder.with_no_client_auth(),
+            };
+            Ok(TlsConnector::from(Arc::new(config)))
+        }
+    }
+
+    async fn spawn_tls_server(
+        state: Arc<State>,
+        config: &TlsConfig,
+    ) -> Result<(SocketAddr, Arc<State>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(serve(state.clone(), TlsListener::new(listener, config)?));
+        Ok((addr, state))
+    }
+
+    async fn connect(
+        addr: SocketAddr,
+        connector: &TlsConnector,
+    ) -> Result<TestClient<tokio_rustls::client::TlsStream<TcpStream>>> {
+        let stream = TcpStream::connect(addr).await?;
+        let stream = connector
+            .connect(ServerName::try_from("localhost")?, stream)
+            .await?;
+        Ok(TestClient::new(stream))
+    }
+
+    #[tokio::test]
+    async fn test_client_cert_names_the_peer() -> Result<()> {
+        let pki = Pki::new()?;
+        let state = Arc::new(State::new(ServerConfig::default())?);
+        let (addr, state) = spawn_tls_server(state, &pki.tls_config(true)).await?;
+
+        let mut alice = connect(addr, &pki.connector(Some("alice"))?).await?;
+        assert_eq!(alice.expect_line().await?, "Welcome, alice!");
+        assert!(state.find_peer("alice").is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_missing_client_cert_is_refused() -> Result<()> {
+        let pki = Pki::new()?;
+        let state = Arc::new(State::new(ServerConfig::default())?);
+        let (addr, state) = spawn_tls_server(state, &pki.tls_config(true)).await?;
+
+        // TLS 1.3 clients finish their side of the handshake before the
+        // server checks their certificate, so the refusal may only show up
+        // on the first read
+        if let Ok(mut anonymous) = connect(addr, &pki.connector(None)?).await {
+            assert!(anonymous.expect_line().await.is_err());
+        }
+        assert!(state.peers.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_optional_client_cert_falls_back_to_prompt() -> Result<()> {
+        let pki = Pki::new()?;
+        let state = Arc::new(State::new(ServerConfig::default())?);
+        let (addr, _) = spawn_tls_server(state, &pki.tls_config(false)).await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let stream = pki
+            .connector(None)?
+            .connect(ServerName::try_from("localhost")?, stream)
+            .await?;
+        TestClient::login(stream, "bob").await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    fs, io,
+    net::{Ipv6Addr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{bail, Result};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::warn;
+
+use crate::{Listener, PeerIdentity};
+
+static NEXT_LOCAL_PEER: AtomicU64 = AtomicU64::new(1);
+
+/// Peers are keyed by address, but Unix socket clients don't have one. Hand
+/// each of them a unique placeholder in `::/96` with port 0, which no TCP
+/// peer can ever have.
+pub fn local_peer_addr() -> SocketAddr {
+    let n = NEXT_LOCAL_PEER.fetch_add(1, Ordering::Relaxed);
+    SocketAddr::from((Ipv6Addr::from(u128::from(n)), 0))
+}
+
+/// A Unix domain socket listener that removes its socket file when dropped.
+#[derive(Debug)]
+pub struct UnixSocketListener {
+    inner: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocketListener {
+    /// Binds the socket, replacing a stale socket file left behind by a
+    /// previous run. Anything else already at the path is left alone and
+    /// fails the bind.
+    pub fn bind(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(&path)?,
+            Ok(_) => bail!("{} exists and is not a socket", path.display()),
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            Err(_) => {}
+        }
+        Ok(Self {
+            inner: UnixListener::bind(&path)?,
+            path,
+        })
+    }
+}
+
+impl PeerIdentity for UnixStream {}
+
+impl Listener for UnixSocketListener {
+    type Stream = UnixStream;
+
+    async fn accept(&self) -> io::Result<(UnixStream, SocketAddr)> {
+        let (stream, _) = self.inner.accept().await?;
+        Ok((stream, local_peer_addr()))
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove socket file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_peer_addrs_are_unique() {
+        let (a, b) = (local_peer_addr(), local_peer_addr());
+        assert_ne!(a, b);
+        assert_eq!(a.port(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stale_socket_replaced_and_removed_on_drop() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("chat.sock");
+        // a socket left behind by a run that didn't clean up
+        drop(UnixListener::bind(&path)?);
+
+        let listener = UnixSocketListener::bind(&path)?;
+        assert!(path.exists());
+        drop(listener);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_other_files_are_not_replaced() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("chat.sock");
+        fs::write(&path, "precious")?;
+
+        let err = UnixSocketListener::bind(&path).unwrap_err();
+        assert!(err.to_string().contains("is not a socket"), "{}", err);
+        assert_eq!(fs::read_to_string(&path)?, "precious");
+        Ok(())
+    }
+}
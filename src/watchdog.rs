@@ -0,0 +1,107 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tracing::info;
+
+use crate::hangup::Departure;
+use crate::State;
+
+/// The shortest time between sweeps, however short the timeout.
+const MIN_SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+impl State {
+    /// Hangs up on every peer whose connection has been silent in both
+    /// directions since `cutoff`.
+    fn reap_stalled(&self, cutoff: Instant) {
+        let stalled: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|peer| peer.traffic.last_io() <= cutoff)
+            .map(|peer| *peer.key())
+            .collect();
+        for addr in stalled {
+            if let Some(peer) = self.peers.get(&addr) {
+                info!(
+                    "Closing stalled connection from {} ({:?})",
+                    peer.username, addr
+                );
+                peer.hangup.hang_up(Departure::Stalled);
+            }
+        }
+    }
+}
+
+/// Sweeps the peers every so often and closes connections that have seen
+/// no I/O for `io_timeout_secs`. Returns `None` when the watchdog is off.
+pub fn spawn_watchdog(state: Arc<State>) -> Option<JoinHandle<()>> {
+    let timeout = Duration::from_secs(state.server.io_timeout_secs);
+    if timeout.is_zero() {
+        return None;
+    }
+    let period = (timeout / 4).max(MIN_SWEEP_INTERVAL);
+
+    Some(tokio::spawn(async move {
+        let mut sweep = time::interval(period);
+        sweep.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            sweep.tick().await;
+            if let Some(cutoff) = Instant::now().checked_sub(timeout) {
+                state.reap_stalled(cutoff);
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::handle_connection;
+    use crate::test_support::{spawn_state, TestClient};
+
+    #[tokio::test]
+    async fn test_stalled_connection_is_reaped() -> Result<()> {
+        let state = Arc::new(State::new(ServerConfig {
+            io_timeout_secs: 1,
+            ..Default::default()
+        })?);
+        let addr = spawn_state(state.clone()).await?;
+        spawn_watchdog(state.clone());
+        let mut alice = TestClient::connect(addr, "alice").await?;
+
+        // bob neither reads nor writes, so once his buffer fills nothing
+        // moves on his connection at all
+        let bob_addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let (client, server) = tokio::io::duplex(64);
+        tokio::spawn(handle_connection(state.clone(), bob_addr, server));
+        let mut bob = TestClient::login(client, "bob").await?;
+        assert_eq!(
+            alice.expect_line().await?,
+            "Server: bob has joined the chat."
+        );
+
+        // alice keeps talking, which keeps her own connection busy
+        let started = Instant::now();
+        let mut sent = 0;
+        while state.find_peer("bob").is_some() {
+            alice.send_line(format!("message {}", sent)).await?;
+            sent += 1;
+            time::sleep(Duration::from_millis(50)).await;
+            assert!(started.elapsed() < Duration::from_secs(5), "not reaped");
+        }
+        assert!(started.elapsed() >= Duration::from_millis(900));
+        assert_eq!(alice.expect_line().await?, "Server: bob has left the chat.");
+        assert!(state.find_peer("alice").is_some());
+
+        // and bob's end is closed once what was buffered is read
+        while bob.try_recv().await.is_ok() {
+            assert!(started.elapsed() < Duration::from_secs(10), "not closed");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::Serialize;
+use tracing::warn;
+
+/// How long a single webhook request may take before it is abandoned.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A presence change, or a message lost to a slow peer, posted to the
+/// configured webhook.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event {
+    Join {
+        username: String,
+    },
+    Leave {
+        username: String,
+    },
+    /// A message was dropped because the peer's queue stayed full;
+    /// `dropped` counts every one lost this session.
+    Dropped {
+        username: String,
+        dropped: u64,
+    },
+}
+
+#[derive(Debug)]
+pub struct Webhook {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Webhook {
+    pub fn new(url: impl Into<String>) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()?;
+        Ok(Self {
+            client,
+            url: url.into(),
+        })
+    }
+
+    /// Posts the event in the background, retrying once on failure.
+    pub fn notify(&self, event: Event) {
+        let client = self.client.clone();
+        let url = self.url.clone();
+        tokio::spawn(async move {
+            for attempt in 1..=2 {
+                let result = client
+                    .post(&url)
+                    .json(&event)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                match result {
+                    Ok(_) => return,
+                    Err(e) => warn!("Webhook attempt {} failed: {}", attempt, e),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+        time,
+    };
+
+    use super::*;
+
+    /// A tiny HTTP server replying with `statuses` in turn and forwarding
+    /// every request body it receives.
+    pub async fn mock_server(statuses: &[u16]) -> Result<(String, mpsc::Receiver<String>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/hook", listener.local_addr()?);
+        let statuses = statuses.to_vec();
+        let (tx, rx) = mpsc::channel(8);
+
+        tokio::spawn(async move {
+            for status in statuses {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    return;
+                };
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                let body = loop {
+                    let Ok(n @ 1..) = stream.read(&mut buf).await else {
+                        return;
+                    };
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                        continue;
+                    };
+                    let length = head
+                        .lines()
+                        .find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            name.eq_ignore_ascii_case("content-length")
+                                .then(|| value.trim().parse::<usize>().ok())?
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break body.to_string();
+                    }
+                };
+                let response = format!(
+                    "HTTP/1.1 {} Mock\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = tx.send(body).await;
+            }
+        });
+
+        Ok((url, rx))
+    }
+
+    pub async fn next_payload(rx: &mut mpsc::Receiver<String>) -> Result<serde_json::Value> {
+        let body = time::timeout(Duration::from_secs(5), rx.recv())
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("mock server stopped"))?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    #[tokio::test]
+    async fn test_retries_once_on_failure() -> Result<()> {
+        let (url, mut rx) = mock_server(&[500, 200]).await?;
+        Webhook::new(url)?.notify(Event::Leave {
+            username: "alice".to_string(),
+        });
+
+        let expected = serde_json::json!({"event": "leave", "username": "alice"});
+        assert_eq!(next_payload(&mut rx).await?, expected);
+        assert_eq!(next_payload(&mut rx).await?, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use std::{net::SocketAddr, sync::atomic::Ordering};
+
+use crate::client::ClientInfo;
+use crate::{Message, State};
+
+impl State {
+    /// Columns to wrap a client's lines to: its own width, if it sent one.
+    pub(crate) fn wrap_width_for(&self, client: &ClientInfo) -> usize {
+        client.width.map_or(self.server.wrap_width, usize::from)
+    }
+
+    pub(crate) async fn set_wrap(&self, addr: SocketAddr, on: bool) {
+        let Some(peer) = self.peers.get(&addr) else {
+            return;
+        };
+        let width = if on {
+            self.wrap_width_for(&peer.client)
+        } else {
+            0
+        };
+        peer.wrap_width.store(width, Ordering::Relaxed);
+        drop(peer);
+        let reply = if on {
+            format!("Wrapping is on at {} columns.", width)
+        } else {
+            "Wrapping is off.".to_string()
+        };
+        self.notify(addr, Message::server(reply)).await;
+    }
+}
+
+/// Splits a line into lines of at most `width` characters, breaking between
+/// words. Spacing within a line is kept as sent; the spaces a line breaks
+/// at are dropped. Words longer than a whole line are split wherever they
+/// hit the edge.
+pub fn wrap(line: &str, width: usize) -> Vec<String> {
+    if width == 0 || line.chars().count() <= width {
+        return vec![line.to_string()];
+    }
+
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    let mut len = 0;
+    let mut rest = line;
+    while !rest.is_empty() {
+        let (gap, after) = rest.split_at(
+            rest.find(|c: char| !c.is_whitespace())
+                .unwrap_or(rest.len()),
+        );
+        let (word, after) = after.split_at(after.find(char::is_whitespace).unwrap_or(after.len()));
+        rest = after;
+
+        let gap_len = gap.chars().count();
+        let mut chars: Vec<char> = word.chars().collect();
+        // only the first line keeps the spacing it starts with
+        let continues = len > 0 || lines.is_empty();
+        if continues && len + gap_len + chars.len() <= width {
+            current.push_str(gap);
+            current.extend(&chars);
+            len += gap_len + chars.len();
+            continue;
+        }
+        if len > 0 {
+            lines.push(std::mem::take(&mut current));
+        }
+        while chars.len() > width {
+            lines.push(chars.drain(..width).collect());
+        }
+        len = chars.len();
+        current.extend(chars);
+    }
+    if len > 0 {
+        lines.push(current);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wraps_between_words() {
+        assert_eq!(
+            wrap("alice: the quick brown fox jumps over the lazy dog", 20),
+            ["alice: the quick", "brown fox jumps over", "the lazy dog"]
+        );
+        assert_eq!(wrap("short enough", 20), ["short enough"]);
+        assert_eq!(wrap("no limit at all", 0), ["no limit at all"]);
+    }
+
+    #[test]
+    fn test_splits_long_words() {
+        assert_eq!(
+            wrap("see https://example.com/a/very/long/path ok", 12),
+            ["see", "https://exam", "ple.com/a/ve", "ry/long/path", "ok"]
+        );
+        // widths count characters, not bytes
+        assert_eq!(wrap("ééé ééé", 3), ["ééé", "ééé"]);
+    }
+
+    #[test]
+    fn test_keeps_spacing_within_lines() {
+        assert_eq!(
+            wrap("  indented  by   two, spaced  out", 16),
+            ["  indented  by", "two, spaced  out"]
+        );
+        assert_eq!(wrap("a\tb    c", 4), ["a\tb", "c"]);
+        assert_eq!(wrap("trailing   ", 8), ["trailing"]);
+    }
+}